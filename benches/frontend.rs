@@ -0,0 +1,100 @@
+//! Lexes and parses machine-generated programs of growing size, prints the
+//! time per byte and fails if it doesn't stay flat, i.e. if the front end
+//! isn't linear.
+//!
+//! ```text
+//! $ cargo bench --bench frontend
+//! $ BW_BENCH_SIZES=1,2,4 cargo bench --bench frontend
+//! ```
+//!
+//! Sizes are in MB and default to 10, 25, 50 and 100. The slowest size may
+//! take at most `BW_BENCH_TOLERANCE` (default 1.5) times as long per byte
+//! as the fastest one.
+
+use brainwash::parser::Parser;
+
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+
+const MB: usize = 1024 * 1024;
+
+/// Each size is measured this often and the fastest run counts, which
+/// keeps one-off hiccups out of the comparison
+const RUNS: usize = 3;
+
+/// A loop-heavy building block with comments and nesting, roughly what
+/// generators for bigger programs produce
+const BLOCK: &str = "++++++++[>++++[>++>+++>+++<<<-]>+>+>->>+[<]<-]>>.>---.\n\
+                     +++++++..+++.>>.<-.<.+++.------.--------.>>+.>++. copy: [->+>+<<]\n";
+
+fn generate(size: usize) -> Vec<u8> {
+    BLOCK
+        .bytes()
+        .cycle()
+        .take(size - size % BLOCK.len())
+        .collect()
+}
+
+fn measure(source: &[u8]) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let tree = Parser::parse_bytes(source).expect("generated program parses");
+            let elapsed = start.elapsed();
+
+            drop(tree);
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().map_or(default, |value| {
+        value
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not valid", name))
+    })
+}
+
+fn main() {
+    let sizes: Vec<usize> = env::var("BW_BENCH_SIZES")
+        .ok()
+        .map(|sizes| {
+            sizes
+                .split(',')
+                .map(|size| size.trim().parse().expect("sizes are numbers of MB"))
+                .collect()
+        })
+        .unwrap_or_else(|| vec![10, 25, 50, 100]);
+    let tolerance: f64 = env_or("BW_BENCH_TOLERANCE", 1.5);
+
+    println!("{:>8} {:>12} {:>12}", "size", "time", "ns/byte");
+
+    let mut per_byte = Vec::new();
+    for size in sizes {
+        let source = generate(size * MB);
+        let elapsed = measure(&source);
+        let ns_per_byte = elapsed.as_nanos() as f64 / source.len() as f64;
+
+        println!(
+            "{:>6}MB {:>10.1}ms {:>12.2}",
+            size,
+            elapsed.as_secs_f64() * 1000.0,
+            ns_per_byte
+        );
+        per_byte.push(ns_per_byte);
+    }
+
+    let fastest = per_byte.iter().copied().fold(f64::INFINITY, f64::min);
+    let slowest = per_byte.iter().copied().fold(0.0, f64::max);
+    if slowest > fastest * tolerance {
+        eprintln!(
+            "time per byte is not flat: {:.2} ns at worst against {:.2} ns at best, more than {}x",
+            slowest, fastest, tolerance
+        );
+        process::exit(1);
+    }
+}
@@ -0,0 +1,25 @@
+//! Decodes the fuzzer's bytes into a program that always terminates and
+//! runs it in the interpreter as written and after the Brainfuck-level
+//! passes. The two have to agree on the output, the final tape and the exit
+//! code.
+//!
+//! ```text
+//! $ cargo +nightly fuzz run differential
+//! ```
+
+#![no_main]
+
+use brainwash::fuzz::{self, Backend, FuzzProgram, InterpreterBackend, OptimizedBackend};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let program = FuzzProgram::from_bytes(bytes);
+
+    let reference = InterpreterBackend::default();
+    let optimized = OptimizedBackend::default();
+    let backends: [&dyn Backend; 2] = [&reference, &optimized];
+
+    if let Some(divergence) = fuzz::find_divergence(&program, &backends).unwrap() {
+        panic!("{}\nprogram: {}", divergence, program.source());
+    }
+});
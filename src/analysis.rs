@@ -0,0 +1,805 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::slice;
+
+use crate::codegen::{CellSize, DEFAULT_TAPE_SIZE};
+use crate::lexer::Span;
+use crate::parser::Node;
+
+/// Nodes [`Analyzer::run`] may look at, counting every round of every loop,
+/// before it gives up
+pub const MAX_WORK: u64 = 1_000_000;
+
+/// Rounds after which a loop's pointer range is widened to the whole tape
+/// wherever it is still growing
+const WIDEN_AFTER: u32 = 2;
+
+/// Rounds after which nothing is assumed about a loop's head any more
+const MAX_ROUNDS: u32 = 8;
+
+/// Writes through a pointer that can be at up to this many cells forget
+/// them one by one, wider ones forget that the rest of the tape is zero
+const MAX_FORGET: i64 = 64;
+
+/// Positions the pointer may be at, both ends inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Interval {
+    lo: i64,
+    hi: i64,
+}
+
+impl Interval {
+    fn at(position: i64) -> Self {
+        Interval {
+            lo: position,
+            hi: position,
+        }
+    }
+
+    fn exact(self) -> Option<i64> {
+        if self.lo == self.hi {
+            Some(self.lo)
+        } else {
+            None
+        }
+    }
+
+    fn contains(self, position: i64) -> bool {
+        self.lo <= position && position <= self.hi
+    }
+
+    fn shift(self, amount: i64) -> Self {
+        Interval {
+            lo: self.lo.saturating_add(amount),
+            hi: self.hi.saturating_add(amount),
+        }
+    }
+
+    fn join(self, other: Interval) -> Self {
+        Interval {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    /// The part of the interval on a tape of `size` cells, `None` if there
+    /// is none and every run that gets here has already failed
+    fn clamp(self, size: i64) -> Option<Self> {
+        let clamped = Interval {
+            lo: self.lo.max(0),
+            hi: self.hi.min(size - 1),
+        };
+
+        if clamped.lo <= clamped.hi {
+            Some(clamped)
+        } else {
+            None
+        }
+    }
+}
+
+/// What is known about the pointer and the tape at one point of the program,
+/// for every run that gets there
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    pointer: Interval,
+    /// Cells that are not like the rest of the tape, `None` if their value
+    /// is unknown
+    cells: BTreeMap<i64, Option<u32>>,
+    /// Whether the cells that aren't in `cells` are known to be zero
+    rest_zero: bool,
+    /// Cells the program may have written to so far
+    written: BTreeSet<i64>,
+    /// Whether it may have written to any cell
+    all_written: bool,
+}
+
+impl State {
+    fn value(&self, cell: i64) -> Option<u32> {
+        match self.cells.get(&cell) {
+            Some(&value) => value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn current(&self) -> Option<u32> {
+        self.pointer.exact().and_then(|cell| self.value(cell))
+    }
+
+    fn set(&mut self, cell: i64, value: Option<u32>) {
+        let rest = if self.rest_zero { Some(0) } else { None };
+
+        if value == rest {
+            self.cells.remove(&cell);
+        } else {
+            self.cells.insert(cell, value);
+        }
+    }
+
+    /// Sets the current cell, or forgets every cell it may be
+    fn write(&mut self, value: Option<u32>) {
+        self.mark(self.pointer);
+        match self.pointer.exact() {
+            Some(cell) => self.set(cell, value),
+            None => self.forget(self.pointer),
+        }
+    }
+
+    /// Notes that the program may have written to the cells in `range`
+    fn mark(&mut self, range: Interval) {
+        if self.all_written {
+            return;
+        }
+        if range.hi - range.lo >= MAX_FORGET {
+            self.all_written = true;
+            self.written.clear();
+        } else {
+            self.written.extend(range.lo..=range.hi);
+        }
+    }
+
+    /// Whether nothing has written to any of the cells in `range` yet
+    fn unset(&self, range: Interval) -> bool {
+        !self.all_written && self.written.range(range.lo..=range.hi).next().is_none()
+    }
+
+    fn forget(&mut self, range: Interval) {
+        if self.rest_zero && range.hi - range.lo >= MAX_FORGET {
+            self.rest_zero = false;
+            self.cells
+                .retain(|&cell, value| value.is_some() && !range.contains(cell));
+        } else if self.rest_zero {
+            for cell in range.lo..=range.hi {
+                self.cells.insert(cell, None);
+            }
+        } else {
+            self.cells.retain(|&cell, _| !range.contains(cell));
+        }
+    }
+
+    /// What holds whichever of the two states a run is in
+    fn join(&self, other: &State) -> State {
+        let mut joined = State {
+            pointer: self.pointer.join(other.pointer),
+            cells: BTreeMap::new(),
+            rest_zero: self.rest_zero && other.rest_zero,
+            written: self.written.union(&other.written).copied().collect(),
+            all_written: self.all_written || other.all_written,
+        };
+
+        for &cell in self.cells.keys().chain(other.cells.keys()) {
+            let value = self
+                .value(cell)
+                .filter(|&value| other.value(cell) == Some(value));
+            joined.set(cell, value);
+        }
+
+        joined
+    }
+}
+
+/// What [`Analyzer::run`] found out about a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// Cells from the start of the tape the program may use, `None` if it
+    /// may run off the end of the tape
+    pub max_cells: Option<u64>,
+    /// Moves and scans that take the pointer off the start of the tape
+    /// whenever they run, in source order
+    pub underflows: Vec<Span>,
+    /// Ops that move the pointer or reach other cells and are proven to
+    /// stay on the tape
+    pub safe: HashSet<Span>,
+    /// Loops that are reached but never run because their cell is always
+    /// zero, by the span of their `[`, in source order
+    pub dead_loops: Vec<Span>,
+    /// Loops that never end once they run, because their cell is never zero
+    /// when they check it, in source order
+    pub infinite_loops: Vec<Span>,
+    /// `.`s that only ever print cells nothing has written to, in source
+    /// order
+    pub unset_outputs: Vec<Span>,
+}
+
+/// Runs the program on what is known about the pointer and the tape
+/// instead of on actual values: where the pointer may be, as a range, and
+/// which cells hold which values. Loops are run until what is known at
+/// their head stops changing, and a pointer range that keeps growing is
+/// widened to the whole tape, so every loop finishes after a few rounds.
+///
+/// Every run of the program ends up in one of the states the analysis
+/// considered, so what holds for all of them holds for the program
+#[derive(Debug, Clone)]
+pub struct Analyzer {
+    tape_size: u64,
+    cell_size: CellSize,
+    max_work: u64,
+    pointer: usize,
+    tape: Vec<u32>,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Analyzer {
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_size: CellSize::default(),
+            max_work: MAX_WORK,
+            pointer: 0,
+            tape: Vec::new(),
+        }
+    }
+
+    pub fn tape_size(mut self, tape_size: u64) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> Self {
+        self.cell_size = cell_size;
+        self
+    }
+
+    pub fn max_work(mut self, max_work: u64) -> Self {
+        self.max_work = max_work;
+        self
+    }
+
+    /// Starts from the pointer and the tape a [`crate::prefix::Prefix`]
+    /// left behind instead of from the first cell of an empty tape
+    pub fn start(mut self, pointer: usize, tape: &[u32]) -> Self {
+        self.pointer = pointer;
+        self.tape = tape.to_vec();
+        self
+    }
+
+    /// `None` if the program is too big to analyze within
+    /// [`Analyzer::max_work`]
+    pub fn run(&self, root: &Node) -> Option<Analysis> {
+        let top: &[Node] = match root {
+            Node::Expr(body) => body,
+            node => slice::from_ref(node),
+        };
+
+        let mut start = State {
+            pointer: Interval::at(self.pointer as i64),
+            cells: BTreeMap::new(),
+            rest_zero: true,
+            written: BTreeSet::new(),
+            all_written: false,
+        };
+        for (cell, &value) in self.tape.iter().enumerate() {
+            start.set(cell as i64, Some(value));
+            if value != 0 {
+                start.mark(Interval::at(cell as i64));
+            }
+        }
+
+        let mut walk = Walk {
+            size: self.tape_size as i64,
+            mask: u32::MAX >> (32 - self.cell_size.bits()),
+            max: self.pointer as i64,
+            reached: HashSet::new(),
+            unsafe_ops: HashSet::new(),
+            underflows: HashMap::new(),
+            outputs: HashMap::new(),
+            entered: HashSet::new(),
+            skipped: HashSet::new(),
+            infinite: HashMap::new(),
+        };
+
+        // `None` once every run that gets this far has failed
+        let mut state = Some(start);
+        let mut work = 0;
+        // Bodies being walked, innermost last, with the head of their loop
+        let mut stack: Vec<(slice::Iter<Node>, Option<Head>)> = vec![(top.iter(), None)];
+
+        while let Some((nodes, _)) = stack.last_mut() {
+            let node = match nodes.next() {
+                Some(node) => node,
+                None => {
+                    let (_, head) = stack.pop().unwrap();
+                    if let Some(mut head) = head {
+                        state = match head.next_round(state, &walk) {
+                            Some(next) => {
+                                stack.push((head.body.iter(), Some(head)));
+                                Some(next)
+                            }
+                            None => head.exit(&mut walk),
+                        };
+                    }
+                    continue;
+                }
+            };
+
+            work += 1;
+            if work > self.max_work {
+                return None;
+            }
+
+            let current = match state.as_mut() {
+                Some(current) => current,
+                None => continue,
+            };
+
+            match node {
+                // A loop reached on a zero cell never runs
+                Node::LoopExpr(_, span) if current.current() == Some(0) => {
+                    walk.skipped.insert(*span);
+                }
+                Node::LoopExpr(body, span) => {
+                    walk.entered.insert(*span);
+                    let head = Head {
+                        body,
+                        span: *span,
+                        state: current.clone(),
+                        rounds: 0,
+                    };
+                    stack.push((body.iter(), Some(head)));
+                }
+                node => {
+                    if !walk.step(current, node) {
+                        state = None;
+                    }
+                }
+            }
+        }
+
+        let unsafe_ops = walk.unsafe_ops;
+        let entered = walk.entered;
+        Some(Analysis {
+            max_cells: if walk.max < walk.size {
+                Some(walk.max as u64 + 1)
+            } else {
+                None
+            },
+            underflows: always(walk.underflows),
+            safe: walk
+                .reached
+                .into_iter()
+                .filter(|span| !unsafe_ops.contains(span))
+                .collect(),
+            dead_loops: sorted(
+                walk.skipped
+                    .into_iter()
+                    .filter(|span| !entered.contains(span))
+                    .collect(),
+            ),
+            infinite_loops: always(walk.infinite),
+            unset_outputs: always(walk.outputs),
+        })
+    }
+}
+
+/// The spans that got a `true` the last time they were looked at, in source
+/// order
+fn always(verdicts: HashMap<Span, bool>) -> Vec<Span> {
+    sorted(
+        verdicts
+            .into_iter()
+            .filter(|&(_, always)| always)
+            .map(|(span, _)| span)
+            .collect(),
+    )
+}
+
+fn sorted(mut spans: Vec<Span>) -> Vec<Span> {
+    spans.sort_by_key(|span| (span.start, span.end));
+    spans
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A loop being run until the state at its head settles
+struct Head<'a> {
+    body: &'a [Node],
+    span: Span,
+    /// What holds every time the loop checks its cell so far
+    state: State,
+    rounds: u32,
+}
+
+impl<'a> Head<'a> {
+    /// The state to run the body with next, `None` once it can't add
+    /// anything to the head any more
+    fn next_round(&mut self, end: Option<State>, walk: &Walk) -> Option<State> {
+        let joined = match end {
+            Some(end) => self.state.join(&end),
+            None => return None,
+        };
+        if joined == self.state {
+            return None;
+        }
+
+        self.rounds += 1;
+        let whole_tape = Interval {
+            lo: 0,
+            hi: walk.size - 1,
+        };
+
+        if self.rounds >= MAX_ROUNDS {
+            self.state = State {
+                pointer: whole_tape,
+                cells: BTreeMap::new(),
+                rest_zero: false,
+                written: BTreeSet::new(),
+                all_written: true,
+            };
+        } else if self.rounds >= WIDEN_AFTER {
+            let mut pointer = joined.pointer;
+            if pointer.lo < self.state.pointer.lo {
+                pointer.lo = whole_tape.lo;
+            }
+            if pointer.hi > self.state.pointer.hi {
+                pointer.hi = whole_tape.hi;
+            }
+            self.state = State { pointer, ..joined };
+        } else {
+            self.state = joined;
+        }
+
+        Some(self.state.clone())
+    }
+
+    /// The state after the loop, which only ends on a zero cell. `None` if
+    /// the cell it checks is never zero
+    fn exit(self, walk: &mut Walk) -> Option<State> {
+        let mut state = self.state;
+        let infinite = matches!(state.current(), Some(value) if value != 0);
+        walk.infinite.insert(self.span, infinite);
+
+        if infinite {
+            return None;
+        }
+        if let Some(cell) = state.pointer.exact() {
+            state.set(cell, Some(0));
+        }
+        Some(state)
+    }
+}
+
+/// What the walk over the program found so far
+struct Walk {
+    size: i64,
+    mask: u32,
+    /// Rightmost cell any op may reach
+    max: i64,
+    /// Ops that move the pointer or reach other cells
+    reached: HashSet<Span>,
+    /// The ones of them that may leave the tape
+    unsafe_ops: HashSet<Span>,
+    /// Moves and scans and whether they left the tape on the left every
+    /// time they were looked at. Loop bodies are looked at last with the
+    /// final state at their head, so that is the answer that counts
+    underflows: HashMap<Span, bool>,
+    /// `.`s and whether the cell they print was never written to, looked at
+    /// like `underflows`
+    outputs: HashMap<Span, bool>,
+    /// Loops that ran at least once and loops that were skipped
+    entered: HashSet<Span>,
+    skipped: HashSet<Span>,
+    /// Loops and whether their cell was never zero when they checked it
+    infinite: HashMap<Span, bool>,
+}
+
+impl Walk {
+    /// Runs anything but a loop on `state`. `false` if every run fails there
+    fn step(&mut self, state: &mut State, node: &Node) -> bool {
+        match node {
+            Node::PlusNode(_) => self.change(state, 1),
+            Node::MinusNode(_) => self.change(state, -1),
+            Node::AddNode(amount, _) => self.change(state, *amount),
+            Node::IncrementPtrNode(span) => return self.shift(state, 1, *span),
+            Node::DecrementPtrNode(span) => return self.shift(state, -1, *span),
+            Node::MoveNode(amount, span) => return self.shift(state, *amount, *span),
+            Node::ReadCurrPosNode(_) => state.write(None),
+            Node::ClearNode(_) => state.write(Some(0)),
+            Node::MulNode(factors, span) => self.multiply(state, factors, *span),
+            Node::ScanNode(step, span) => return self.scan(state, *step, *span),
+            Node::PrintCurrPosNode(span) => {
+                self.outputs.insert(*span, state.unset(state.pointer));
+            }
+            Node::BreakpointNode(_)
+            | Node::LoopCloseNode(_)
+            | Node::LoopExpr(_, _)
+            | Node::Expr(_) => {}
+        }
+        true
+    }
+
+    /// Notes that the op at `span` reaches the cells in `range`
+    fn touch(&mut self, span: Span, range: Interval) {
+        self.reached.insert(span);
+        self.max = self.max.max(range.hi);
+
+        if range.lo < 0 || range.hi >= self.size {
+            self.unsafe_ops.insert(span);
+        }
+    }
+
+    fn change(&self, state: &mut State, amount: i32) {
+        let value = state
+            .current()
+            .map(|value| value.wrapping_add(amount as u32) & self.mask);
+        state.write(value);
+    }
+
+    fn shift(&mut self, state: &mut State, amount: i64, span: Span) -> bool {
+        let moved = state.pointer.shift(amount);
+        self.touch(span, moved);
+        self.underflows.insert(span, moved.hi < 0);
+
+        match moved.clamp(self.size) {
+            Some(pointer) => {
+                state.pointer = pointer;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn multiply(&mut self, state: &mut State, factors: &[(i64, i32)], span: Span) {
+        let value = state.current();
+        if value == Some(0) {
+            return;
+        }
+
+        for &(offset, factor) in factors {
+            let target = state.pointer.shift(offset);
+            self.touch(span, target);
+
+            let target = match target.clamp(self.size) {
+                Some(target) => target,
+                None => continue,
+            };
+            state.mark(target);
+            match target.exact() {
+                Some(cell) => {
+                    let product = value.map(|value| value.wrapping_mul(factor as u32));
+                    let sum = match (state.value(cell), product) {
+                        (Some(old), Some(product)) => Some(old.wrapping_add(product) & self.mask),
+                        _ => None,
+                    };
+                    state.set(cell, sum);
+                }
+                None => state.forget(target),
+            }
+        }
+
+        state.write(Some(0));
+    }
+
+    fn scan(&mut self, state: &mut State, step: i64, span: Span) -> bool {
+        // Where the pointer may stop, past the end of the tape if it may
+        // keep going until it falls off
+        let size = self.size;
+        let unknown = |from: i64| {
+            if step > 0 {
+                Interval { lo: from, hi: size }
+            } else {
+                Interval { lo: -1, hi: from }
+            }
+        };
+
+        let end = match state.pointer.exact() {
+            Some(mut cell) => loop {
+                match state.value(cell) {
+                    Some(0) => break Interval::at(cell),
+                    Some(_) if step == 0 => return false,
+                    Some(_) => cell += step,
+                    None => break unknown(cell),
+                }
+                if cell < 0 || cell >= size {
+                    break Interval::at(cell);
+                }
+            },
+            None if step > 0 => unknown(state.pointer.lo),
+            None => unknown(state.pointer.hi),
+        };
+
+        self.touch(span, end);
+        self.underflows.insert(span, end.hi < 0);
+
+        match end.clamp(self.size) {
+            Some(pointer) => {
+                // A scan only reads cells
+                state.pointer = pointer;
+                if let Some(cell) = pointer.exact() {
+                    state.set(cell, Some(0));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzz::Rng;
+    use crate::interpreter::{Interpreter, InterpreterError, Op, Program};
+    use crate::optimizer::Pipeline;
+    use crate::parser::parse;
+    use crate::verify;
+    use std::io;
+
+    fn analyze(source: &str) -> Analysis {
+        Analyzer::new().tape_size(100).run(&parse(source)).unwrap()
+    }
+
+    fn optimized(source: &str) -> Analysis {
+        let mut root = parse(source);
+        Pipeline::all().run(&mut root);
+        Analyzer::new().tape_size(100).run(&root).unwrap()
+    }
+
+    #[test]
+    fn test_max_cells() {
+        assert_eq!(analyze("+++.").max_cells, Some(1));
+        assert_eq!(analyze(">>>+<<[-]>>>>>.").max_cells, Some(7));
+        assert_eq!(analyze("++++[>+>++<<-]>>.").max_cells, Some(3));
+        // Reads until a zero byte, as far as the input goes
+        assert_eq!(analyze(",[>,]").max_cells, None);
+        // Known cells tell where a scan stops
+        assert_eq!(optimized("+>+>+<<[>]<.").max_cells, Some(4));
+    }
+
+    #[test]
+    fn test_underflows() {
+        let analysis = analyze("+<.>");
+        assert_eq!(analysis.underflows, vec![Span::at(1, 2)]);
+
+        // Only if the pointer is off the tape whenever the move runs
+        assert!(analyze(">,[<]").underflows.is_empty());
+        assert_eq!(analyze("+[<]").underflows, vec![Span::at(1, 3)]);
+        assert_eq!(
+            optimized("+[<]").underflows,
+            vec![Span::at(1, 2).to(Span::at(1, 4))]
+        );
+        // A loop on a zero cell doesn't run at all
+        assert!(analyze("[<]").underflows.is_empty());
+    }
+
+    #[test]
+    fn test_safe_ops() {
+        let source = "++[>++>+<<-]>>[<]";
+        let analysis = analyze(source);
+        let spans = |columns: &[u32]| -> HashSet<Span> {
+            columns.iter().map(|&column| Span::at(1, column)).collect()
+        };
+
+        // The scan at the end may run off the start of the tape
+        assert_eq!(analysis.safe, spans(&[4, 7, 9, 10, 13, 14]));
+        assert_eq!(analysis.max_cells, Some(3));
+
+        // With the loop turned into a multiplication the cells it leaves
+        // behind are known, and so is where the scan stops
+        let analysis = optimized(source);
+        assert_eq!(analysis.safe.len(), 3);
+        assert_eq!(analysis.max_cells, Some(3));
+    }
+
+    #[test]
+    fn test_widening() {
+        // The pointer moves right every round, so the loop ends up anywhere
+        let analysis = analyze("+[>+]");
+        assert_eq!(analysis.max_cells, None);
+        assert!(analysis.safe.is_empty());
+
+        // A loop that keeps the pointer in place settles right away
+        let analysis = analyze(",[>+<-]>.");
+        assert_eq!(analysis.max_cells, Some(2));
+        assert_eq!(analysis.safe.len(), 3);
+
+        assert!(Analyzer::new()
+            .max_work(10)
+            .run(&parse(&"+".repeat(11)))
+            .is_none());
+    }
+
+    #[test]
+    fn test_loops_and_outputs() {
+        let analysis = analyze(">[-]+[>.<]<[]");
+        assert_eq!(analysis.dead_loops, vec![Span::at(1, 2)]);
+        assert_eq!(analysis.infinite_loops, vec![Span::at(1, 6)]);
+        assert_eq!(analysis.unset_outputs, vec![Span::at(1, 8)]);
+
+        // Changing the cell and changing it back still leaves it nonzero
+        assert_eq!(analyze("+[-+]").infinite_loops, vec![Span::at(1, 2)]);
+        assert!(analyze("+[>]").infinite_loops.is_empty());
+        assert!(analyze(",[]").infinite_loops.is_empty());
+
+        // Cleared cells were still written to, unlike cells a scan stops on
+        assert!(analyze("+[-].").unset_outputs.is_empty());
+        assert_eq!(optimized("+>>[<]>.").unset_outputs.len(), 1);
+        assert!(analyze(",[>]<.").unset_outputs.is_empty());
+    }
+
+    /// Brainfuck made of random ops, unlike [`crate::fuzz::FuzzProgram`]
+    /// free to move the pointer anywhere
+    fn random_source(rng: &mut Rng, len: usize) -> String {
+        let mut source = String::new();
+        let mut open = 0;
+
+        for _ in 0..len {
+            let op = b"+-<>>.,[]"[rng.next_u64() as usize % 9];
+            match op {
+                b'[' => open += 1,
+                b']' if open == 0 => continue,
+                b']' => open -= 1,
+                _ => {}
+            }
+            source.push(op as char);
+        }
+
+        source + &"]".repeat(open)
+    }
+
+    /// Whatever the analysis proves has to hold for actual runs, on a tape
+    /// small enough for the programs to fall off now and then
+    #[test]
+    fn test_agrees_with_interpreter() {
+        const TAPE_SIZE: u64 = 16;
+
+        let inputs = verify::sample_inputs(4, 0);
+        let mut rng = Rng::new(11);
+
+        for round in 0..400 {
+            let source = random_source(&mut rng, 30);
+            let mut root = parse(&source);
+            if round % 2 == 1 {
+                Pipeline::all().run(&mut root);
+            }
+
+            let analysis = Analyzer::new().tape_size(TAPE_SIZE).run(&root).unwrap();
+            let program = Program::new(&root);
+            let mut written = vec![false; TAPE_SIZE as usize];
+
+            for input in &inputs {
+                let mut interpreter =
+                    Interpreter::new(program.clone(), &input[..], io::sink()).tape_size(TAPE_SIZE);
+
+                written.iter_mut().for_each(|cell| *cell = false);
+
+                while let Some((op, span)) = interpreter.current_op() {
+                    if interpreter.steps() >= 10_000 {
+                        break;
+                    }
+
+                    let value = interpreter.tape()[interpreter.pointer()];
+                    match op {
+                        Op::LoopStart(_) if analysis.dead_loops.contains(&span) => {
+                            assert_eq!(value, 0, "{}", source)
+                        }
+                        Op::LoopStart(_) if analysis.infinite_loops.contains(&span) => {
+                            assert_ne!(value, 0, "{}", source)
+                        }
+                        Op::Output if analysis.unset_outputs.contains(&span) => {
+                            assert!(!written[interpreter.pointer()], "{}", source)
+                        }
+                        Op::Output | Op::LoopStart(_) | Op::LoopEnd(_) | Op::Right | Op::Left => {}
+                        Op::Move(_) | Op::Scan(_) | Op::Breakpoint => {}
+                        _ => written[interpreter.pointer()] = true,
+                    }
+
+                    match interpreter.step() {
+                        Ok(_) => assert!(!analysis.underflows.contains(&span), "{}", source),
+                        Err(InterpreterError::PointerOutOfBounds(span)) => {
+                            assert!(!analysis.safe.contains(&span), "{}", source);
+                            break;
+                        }
+                        Err(err) => panic!("{}", err),
+                    }
+
+                    if let Some(max_cells) = analysis.max_cells {
+                        assert!((interpreter.pointer() as u64) < max_cells, "{}", source);
+                    }
+                }
+            }
+        }
+    }
+}
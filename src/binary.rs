@@ -1,44 +1,76 @@
-use std::error::Error;
 use std::fmt::Display;
 use std::io;
 use std::path::Path;
 use std::process::{Command, Output};
 
+use crate::error::CompileError;
+
 pub struct BinaryGenerator<T: AsRef<Path> + Display> {
     input: T,
+    freestanding: bool,
+    opt_level: u32,
 }
 
 impl<T: AsRef<Path> + Display> BinaryGenerator<T> {
     pub fn new(filename: T) -> Self {
-        BinaryGenerator { input: filename }
+        BinaryGenerator {
+            input: filename,
+            freestanding: false,
+            opt_level: 2,
+        }
+    }
+
+    /// Optimization level passed on to `opt` and `llc`. At 0 the module
+    /// is compiled as emitted, without running `opt` at all
+    pub fn opt_level(mut self, opt_level: u32) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// Link a static executable with `ld` alone instead of going through
+    /// `clang` and libc. The module has to be generated in freestanding mode
+    pub fn freestanding(mut self, freestanding: bool) -> Self {
+        self.freestanding = freestanding;
+        self
     }
 
     /// Function that uses all the utility functions that
     /// generate an executable binary
-    pub fn compile(&self) -> Result<(), Box<dyn Error>> {
-        self.optimize_ll()?;
-        self.generate_bitcode()?;
-        self.generate_assembly()?;
-        self.generate_executable()?;
-        self.remove_intermediate()?;
+    pub fn compile(&self) -> Result<(), CompileError> {
+        if self.opt_level > 0 {
+            check_tool("opt", self.optimize_ll())?;
+            check_tool(
+                "llvm-as",
+                self.generate_bitcode(format!("{}.ll", self.input)),
+            )?;
+        } else {
+            check_tool(
+                "llvm-as",
+                self.generate_bitcode(format!("{}2.ll", self.input)),
+            )?;
+        }
+
+        check_tool("llc", self.generate_assembly())?;
+
+        let linker = if self.freestanding { "ld" } else { "clang" };
+        check_tool(linker, self.generate_executable())?;
+
+        check_tool("rm", self.remove_intermediate())?;
 
         Ok(())
     }
 
     /// Generate LLVM bitcode file
-    fn generate_bitcode(&self) -> io::Result<Output> {
+    fn generate_bitcode(&self, source: String) -> io::Result<Output> {
         Command::new("llvm-as")
-            .args(&[
-                format!("{}.ll", self.input).as_str(),
-                "-o",
-                format!("{}.bc", self.input).as_str(),
-            ])
+            .args(&[source.as_str(), "-o", format!("{}.bc", self.input).as_str()])
             .output()
     }
 
     fn optimize_ll(&self) -> io::Result<Output> {
         Command::new("opt")
             .args(&[
+                format!("-O{}", self.opt_level).as_str(),
                 "-verify",
                 "-mem2reg",
                 "-instcombine",
@@ -60,18 +92,37 @@ impl<T: AsRef<Path> + Display> BinaryGenerator<T> {
 
     /// Compile LLVM bitcode into architecture specific assembly
     fn generate_assembly(&self) -> io::Result<Output> {
-        Command::new("llc")
-            .args(&[
-                "-filetype=obj",
-                format!("{}.bc", self.input).as_str(),
-                "-o",
-                format!("{}.o", self.input).as_str(),
-            ])
-            .output()
+        let mut llc = Command::new("llc");
+
+        if self.freestanding {
+            llc.arg("-relocation-model=static");
+        }
+
+        llc.args(&[
+            format!("-O{}", self.opt_level).as_str(),
+            "-filetype=obj",
+            format!("{}.bc", self.input).as_str(),
+            "-o",
+            format!("{}.o", self.input).as_str(),
+        ])
+        .output()
     }
 
-    /// Compile assembly into an executable file with clang
+    /// Compile assembly into an executable file with clang, or link it
+    /// with `ld` directly when there is no libc to pull in
     fn generate_executable(&self) -> io::Result<Output> {
+        if self.freestanding {
+            return Command::new("ld")
+                .args(&[
+                    "-static",
+                    "--gc-sections",
+                    format!("{}.o", self.input).as_str(),
+                    "-o",
+                    format!("{}", self.input).as_str(),
+                ])
+                .output();
+        }
+
         Command::new("clang")
             .args(&[
                 format!("{}.o", self.input).as_str(),
@@ -94,3 +145,21 @@ impl<T: AsRef<Path> + Display> BinaryGenerator<T> {
             .output()
     }
 }
+
+/// Turns the outcome of running an external tool into an error if it
+/// could not be started or exited unsuccessfully
+fn check_tool(tool: &str, output: io::Result<Output>) -> Result<(), CompileError> {
+    let output = output.map_err(|e| CompileError::ToolError {
+        tool: tool.to_string(),
+        message: e.to_string(),
+    })?;
+
+    if !output.status.success() {
+        return Err(CompileError::ToolError {
+            tool: tool.to_string(),
+            message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    Ok(())
+}
@@ -1,24 +1,43 @@
 use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::execution_engine;
-use inkwell::execution_engine::{ExecutionEngine, JitFunction};
+use inkwell::execution_engine::ExecutionEngine;
 use inkwell::module::{Linkage, Module};
 use inkwell::passes::PassManager;
-use inkwell::targets::{
-    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
-};
 use inkwell::types::{FunctionType, IntType, PointerType};
-use inkwell::values::{AnyValue, FunctionValue, PointerValue};
-use inkwell::values::{BasicValueEnum, IntValue};
+use inkwell::values::{FunctionValue, IntValue, PhiValue, PointerValue};
 use inkwell::AddressSpace;
 use inkwell::IntPredicate;
-use inkwell::OptimizationLevel;
 
+use std::collections::HashSet;
 use std::fmt::Display;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use crate::error::CompileError;
+use crate::lexer::Span;
 use crate::parser::Node;
+use crate::prefix::Prefix;
+use crate::trace::TraceOptions;
+
+mod debug;
+mod profile;
+mod runtime;
+mod sink;
+mod trace;
+
+use debug::DebugInfo;
+use runtime::Runtime;
+
+/// Default size of the tape in cells
+pub const DEFAULT_TAPE_SIZE: u64 = 1024;
+
+/// Most cells a tape can have, LLVM sizes arrays with a `u32`
+pub const MAX_TAPE_SIZE: u64 = u32::MAX as u64;
+
+/// What binaries with bounds checks exit with when the pointer leaves the
+/// tape, the same as `bw run` does
+pub const OFF_TAPE_EXIT_CODE: u64 = 8;
 
 pub struct Types<'ctx> {
     i32_type: IntType<'ctx>,
@@ -27,7 +46,11 @@ pub struct Types<'ctx> {
     i8_ptr_type: PointerType<'ctx>,
     main_fn_type: FunctionType<'ctx>,
     calloc_fn_type: FunctionType<'ctx>,
-    putchar_fn_type: FunctionType<'ctx>,
+    getchar_fn_type: FunctionType<'ctx>,
+    syscall_io_fn_type: FunctionType<'ctx>,
+    void_fn_type: FunctionType<'ctx>,
+    put_byte_fn_type: FunctionType<'ctx>,
+    put_u32_fn_type: FunctionType<'ctx>,
 }
 
 impl<'ctx> Types<'ctx> {
@@ -38,7 +61,14 @@ impl<'ctx> Types<'ctx> {
         let i8_ptr_type = i8_type.ptr_type(AddressSpace::Generic);
         let main_fn_type = i32_type.fn_type(&[], false);
         let calloc_fn_type = i8_ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false);
-        let putchar_fn_type = i32_type.fn_type(&[i32_type.into()], false);
+        let getchar_fn_type = i32_type.fn_type(&[], false);
+        let syscall_io_fn_type = i64_type.fn_type(
+            &[i32_type.into(), i8_ptr_type.into(), i64_type.into()],
+            false,
+        );
+        let void_fn_type = context.void_type().fn_type(&[], false);
+        let put_byte_fn_type = context.void_type().fn_type(&[i8_type.into()], false);
+        let put_u32_fn_type = context.void_type().fn_type(&[i32_type.into()], false);
 
         Types {
             i32_type,
@@ -47,16 +77,140 @@ impl<'ctx> Types<'ctx> {
             i8_ptr_type,
             main_fn_type,
             calloc_fn_type,
-            putchar_fn_type,
+            getchar_fn_type,
+            syscall_io_fn_type,
+            void_fn_type,
+            put_byte_fn_type,
+            put_u32_fn_type,
         }
     }
 }
 
+/// Width of a single tape cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellSize {
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl CellSize {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellSize::Bits8 => 8,
+            CellSize::Bits16 => 16,
+            CellSize::Bits32 => 32,
+        }
+    }
+}
+
+impl Default for CellSize {
+    fn default() -> Self {
+        CellSize::Bits8
+    }
+}
+
+impl FromStr for CellSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellSize::Bits8),
+            "16" => Ok(CellSize::Bits16),
+            "32" => Ok(CellSize::Bits32),
+            _ => Err(format!("unsupported cell size: {}", s)),
+        }
+    }
+}
+
+/// How `.` turns the current cell into output. Cells are always treated as unsigned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// The low 8 bits of the cell as a raw byte
+    Bytes,
+    /// The cell as a decimal number followed by a newline
+    Decimal,
+    /// The cell as a Unicode codepoint encoded in UTF-8. Invalid
+    /// codepoints are written as U+FFFD
+    Utf8,
+}
+
+impl Default for OutputMode {
+    fn default() -> Self {
+        OutputMode::Bytes
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(OutputMode::Bytes),
+            "decimal" => Ok(OutputMode::Decimal),
+            "utf8" => Ok(OutputMode::Utf8),
+            _ => Err(format!("unknown output mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CodegenOptions {
+    /// Number of cells on the tape
+    pub tape_size: u64,
+    /// Do not depend on libc: emit `_start`, keep the tape in `.bss`
+    /// and do all I/O through raw Linux syscalls
+    pub freestanding: bool,
+    /// Write every output byte immediately instead of collecting them in
+    /// a buffer that is flushed when full, before input and at exit
+    pub unbuffered: bool,
+    pub cell_size: CellSize,
+    pub output_mode: OutputMode,
+    /// Emit DWARF debug info that maps the code back to this source file
+    pub debug_source: Option<PathBuf>,
+    /// Log every executed op with its position and the current cell
+    pub trace: Option<TraceOptions>,
+    /// Count how often each op runs and write the counts to this file at exit
+    pub profile: Option<PathBuf>,
+    /// Exit with an error when the pointer leaves the tape
+    pub bounds_check: bool,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            tape_size: DEFAULT_TAPE_SIZE,
+            freestanding: false,
+            unbuffered: false,
+            cell_size: CellSize::default(),
+            output_mode: OutputMode::default(),
+            debug_source: None,
+            trace: None,
+            profile: None,
+            bounds_check: false,
+        }
+    }
+}
+
+/// Fails unless a tape of `tape_size` cells can be built, which takes at
+/// least one cell and at most [`MAX_TAPE_SIZE`]
+pub fn check_tape_size(tape_size: u64) -> Result<(), CompileError> {
+    if tape_size == 0 || tape_size > MAX_TAPE_SIZE {
+        return Err(CompileError::CodegenError(format!(
+            "the tape needs between 1 and {} cells, not {}",
+            MAX_TAPE_SIZE, tape_size
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy)]
 pub struct Loop<'ctx> {
     check: BasicBlock<'ctx>,
     if_true: BasicBlock<'ctx>,
     if_false: BasicBlock<'ctx>,
+    /// Tape index on entry to `check`, fed by the preheader and the back edge
+    index_phi: PhiValue<'ctx>,
 }
 
 pub struct Codegen<'ctx> {
@@ -67,38 +221,100 @@ pub struct Codegen<'ctx> {
     pub execution_engine: ExecutionEngine<'ctx>,
     pub types: Types<'ctx>,
     pub passes: PassManager<FunctionValue<'ctx>>,
+    pub options: CodegenOptions,
+    /// What already ran at compile time, `input` is what is left of the
+    /// program afterwards, see [`crate::prefix`]
+    pub prefix: Option<Prefix>,
+    /// Ops that need no bounds check, see [`crate::analysis`]
+    pub safe_ops: HashSet<Span>,
     // pub loop_stack: Vec<Loop<'ctx>>,
 }
 
 impl<'ctx> Codegen<'ctx> {
-    pub fn generate_llvm<T: AsRef<Path> + Display>(&self, filename: T) {
-        let loop_stack: Vec<Loop> = Vec::new();
+    pub fn generate_llvm<T: AsRef<Path> + Display>(&self, filename: T) -> Result<(), CompileError> {
+        self.build_module()?;
+
+        self.module
+            .print_to_file(format!("./{}.ll", filename))
+            .map_err(|e| CompileError::LlvmError(e.to_string()))
+    }
+
+    /// Emits `main` and the runtime helpers into `self.module` and verifies the result
+    pub fn build_module(&self) -> Result<(), CompileError> {
+        check_tape_size(self.options.tape_size)?;
+        if self.options.freestanding {
+            self.check_freestanding_target()?;
+        }
 
         // Values
         let main_fn_value = self
             .module
             .add_function("main", self.types.main_fn_type, None);
 
-        let calloc_fn_value =
-            self.module
-                .add_function("calloc", self.types.calloc_fn_type, Some(Linkage::External));
+        let runtime = self.build_runtime()?;
 
-        let putchar_fn_value = self.module.add_function(
-            "putchar",
-            self.types.putchar_fn_type,
-            Some(Linkage::External),
-        );
+        let debug_info = self
+            .options
+            .debug_source
+            .as_ref()
+            .map(|source| self.build_debug_info(source, main_fn_value));
+        let debug = debug_info.as_ref();
 
         let entry_block = self.context.append_basic_block(main_fn_value, "entry");
         self.builder.position_at_end(entry_block);
+        self.set_debug_location(debug, Span::at(1, 1));
+
+        // The tape base pointer and the current index are plain SSA values,
+        // loops carry the index through phi nodes in their headers
+        let tape_ptr = if self.options.freestanding {
+            self.build_static_tape()
+        } else {
+            self.build_calloc_tape()?
+        };
 
-        // Allocate data*
-        let data_alloca = self
-            .builder
-            .build_alloca(self.types.i8_ptr_type, "data_alloca");
+        let pointer = self.prefix.as_ref().map_or(0, |prefix| prefix.pointer);
+        let index = self.types.i64_type.const_int(pointer as u64, false);
+
+        if let Some(tracer) = runtime.tracer {
+            self.builder.build_call(tracer.open, &[], "trace_open_call");
+        }
 
-        let mem_size_const = self.types.i64_type.const_int(1024, false);
-        let element_size_const = self.types.i64_type.const_int(1, false);
+        if let Some(prefix) = &self.prefix {
+            self.build_prefix(prefix, tape_ptr, runtime);
+        }
+
+        if let Some(debug) = debug {
+            self.build_debug_variables(debug, entry_block, tape_ptr, index);
+        }
+
+        if let Node::Expr(expr_val) = &self.input {
+            self.match_input(expr_val, tape_ptr, index, runtime, main_fn_value, debug);
+        }
+
+        self.build_exit(runtime, 0, None);
+
+        self.passes.run_on(&main_fn_value);
+
+        if let Some(debug) = debug {
+            debug.finalize();
+        }
+
+        self.module
+            .verify()
+            .map_err(|e| CompileError::LlvmError(e.to_string()))
+    }
+
+    /// Allocates a zeroed tape on the heap with `calloc`
+    fn build_calloc_tape(&self) -> Result<PointerValue<'ctx>, CompileError> {
+        let calloc_fn_value =
+            self.module
+                .add_function("calloc", self.types.calloc_fn_type, Some(Linkage::External));
+
+        let mem_size_const = self.types.i64_type.const_int(self.options.tape_size, false);
+        let element_size_const = self
+            .types
+            .i64_type
+            .const_int(self.options.cell_size.bits() as u64 / 8, false);
 
         let calloc_data = self.builder.build_call(
             calloc_fn_value,
@@ -107,87 +323,337 @@ impl<'ctx> Codegen<'ctx> {
         );
 
         let calloc_data_result: Result<_, _> = calloc_data.try_as_basic_value().flip().into();
-        let calloc_data_basic_val = calloc_data_result
-            .map_err(|_| "calloc returned void")
-            .unwrap();
+        let calloc_ptr = calloc_data_result
+            .map_err(|_| CompileError::CodegenError("calloc returned void".to_string()))?
+            .into_pointer_value();
 
-        self.builder.build_store(data_alloca, calloc_data_basic_val);
+        Ok(self
+            .builder
+            .build_pointer_cast(calloc_ptr, self.cell_ptr_type(), "tape_ptr"))
+    }
 
-        if let Node::Expr(expr_val) = &self.input {
-            self.match_input(
-                expr_val,
-                data_alloca,
-                putchar_fn_value,
-                main_fn_value,
-                loop_stack,
+    /// Places the tape in a zero-initialized global, which ends up in `.bss`.
+    /// [`check_tape_size`] made sure the size fits
+    fn build_static_tape(&self) -> PointerValue<'ctx> {
+        let tape_type = self.cell_type().array_type(self.options.tape_size as u32);
+
+        let tape = self.module.add_global(tape_type, None, "tape");
+        tape.set_linkage(Linkage::Internal);
+        tape.set_initializer(&tape_type.const_zero());
+
+        self.builder
+            .build_pointer_cast(tape.as_pointer_value(), self.cell_ptr_type(), "tape_ptr")
+    }
+
+    /// Writes out everything that is still buffered, then `message` to
+    /// stderr, and returns `code` from `main`
+    fn build_exit(&self, runtime: Runtime<'ctx>, code: u64, message: Option<&str>) {
+        self.builder.build_call(runtime.flush, &[], "flush_call");
+
+        if let Some(message) = message {
+            let text = self
+                .builder
+                .build_global_string_ptr(message, "exit_message");
+            let len = self.types.i64_type.const_int(message.len() as u64, false);
+
+            self.builder.build_call(
+                runtime.write_all,
+                &[
+                    self.types.i32_type.const_int(2, false).into(),
+                    text.as_pointer_value().into(),
+                    len.into(),
+                ],
+                "exit_message_call",
             );
         }
 
+        if let Some(tracer) = runtime.tracer {
+            self.builder
+                .build_call(tracer.flush, &[], "trace_flush_call");
+        }
+
+        if let Some(profiler) = runtime.profiler {
+            self.builder
+                .build_call(profiler.write, &[], "profile_write_call");
+        }
+
         self.builder
-            .build_return(Some(&self.types.i32_type.const_int(0, false)));
+            .build_return(Some(&self.types.i32_type.const_int(code, false)));
+    }
 
-        self.passes.run_on(&main_fn_value);
+    /// Leaves `main` with [`OFF_TAPE_EXIT_CODE`] if `index` is off the
+    /// tape. Ops the analysis proved safe don't get a check
+    fn emit_bounds_check(
+        &self,
+        index: IntValue<'ctx>,
+        span: Span,
+        runtime: Runtime<'ctx>,
+        main_fn_value: FunctionValue,
+    ) {
+        if !self.options.bounds_check || self.safe_ops.contains(&span) {
+            return;
+        }
 
-        self.module
-            .print_to_file(format!("./{}.ll", filename))
-            .unwrap();
+        let on_tape = self.context.append_basic_block(main_fn_value, "on_tape");
+        let off_tape = self.context.append_basic_block(main_fn_value, "off_tape");
+
+        // Indices left of the tape are negative, so as unsigned numbers
+        // they are past its end
+        let tape_size = self.types.i64_type.const_int(self.options.tape_size, false);
+        let compare =
+            self.builder
+                .build_int_compare(IntPredicate::ULT, index, tape_size, "bounds_compare");
+
+        self.builder
+            .build_conditional_branch(compare, on_tape, off_tape);
+
+        self.builder.position_at_end(off_tape);
+        let message = format!("Pointer moved off the tape at {}\n", span);
+        self.build_exit(runtime, OFF_TAPE_EXIT_CODE, Some(&message));
+
+        self.builder.position_at_end(on_tape);
     }
 
+    /// Writes the output of the evaluated prefix in one go and fills in the
+    /// cells it left behind
+    fn build_prefix(&self, prefix: &Prefix, tape_ptr: PointerValue<'ctx>, runtime: Runtime<'ctx>) {
+        let i8_type = self.types.i8_type;
+        let i64_type = self.types.i64_type;
+
+        if !prefix.output.is_empty() {
+            let bytes: Vec<IntValue> = prefix
+                .output
+                .iter()
+                .map(|&byte| i8_type.const_int(byte as u64, false))
+                .collect();
+            let data = i8_type.const_array(&bytes);
+
+            let output = self
+                .module
+                .add_global(data.get_type(), None, "prefix_output");
+            output.set_linkage(Linkage::Internal);
+            output.set_constant(true);
+            output.set_initializer(&data);
+
+            let zero = i64_type.const_zero();
+            let data_ptr = unsafe {
+                self.builder.build_in_bounds_gep(
+                    output.as_pointer_value(),
+                    &[zero, zero],
+                    "prefix_data",
+                )
+            };
+            let len = i64_type.const_int(prefix.output.len() as u64, false);
+
+            self.builder.build_call(
+                runtime.write_all,
+                &[
+                    self.types.i32_type.const_int(1, false).into(),
+                    data_ptr.into(),
+                    len.into(),
+                ],
+                "prefix_write_call",
+            );
+        }
+
+        for (cell, &value) in prefix.tape.iter().enumerate() {
+            if value != 0 {
+                let index = i64_type.const_int(cell as u64, false);
+                let value = self.cell_type().const_int(value as u64, false);
+
+                self.builder
+                    .build_store(self.current_cell_pointer(tape_ptr, index), value);
+            }
+        }
+    }
+
+    /// Emits code for a list of nodes starting at tape index `index`
+    /// and returns the index after the last one. Loop bodies are walked
+    /// with an explicit work stack, like in [`crate::parser::Parser`]
     fn match_input(
         &self,
-        input: &Vec<Node>,
-        data_alloca: PointerValue<'ctx>,
-        putchar_fn_value: FunctionValue,
+        input: &[Node],
+        tape_ptr: PointerValue<'ctx>,
+        mut index: IntValue<'ctx>,
+        runtime: Runtime<'ctx>,
         main_fn_value: FunctionValue,
-        mut loop_stack: Vec<Loop<'ctx>>,
-    ) {
-        for node_type in input.iter() {
-            match node_type {
-                Node::PlusNode => self.emit_change_data_value(data_alloca, 1),
-                Node::MinusNode => self.emit_change_data_value(data_alloca, -1),
-                Node::IncrementPtrNode => self.emit_move_pointer(data_alloca, 1),
-                Node::DecrementPtrNode => self.emit_move_pointer(data_alloca, -1),
-                Node::PrintCurrPosNode => self.emit_putchar(data_alloca, putchar_fn_value),
-                Node::LoopExpr(expr_val) => {
-                    self.build_start_loop(&mut loop_stack, data_alloca, main_fn_value);
-                    self.match_input(
-                        expr_val,
-                        data_alloca,
-                        putchar_fn_value,
+        debug: Option<&DebugInfo<'ctx>>,
+    ) -> IntValue<'ctx> {
+        let mut loop_stack: Vec<Loop<'ctx>> = Vec::new();
+        // Node lists still being emitted, innermost loop body last
+        let mut work = vec![input.iter()];
+        // Index of the next op in `Program::ops` order, for the profiler
+        let mut op_index = 0;
+
+        while let Some(nodes) = work.last_mut() {
+            let node_type = match nodes.next() {
+                Some(node_type) => node_type,
+                None => {
+                    // Every list but the outermost one is a loop body
+                    work.pop();
+                    if !work.is_empty() {
+                        self.pop_debug_scope(debug);
+                    }
+                    continue;
+                }
+            };
+
+            // Until the match below the builder is still in the block that
+            // runs the op: the preheader for `[` and the latch for `]`
+            if let Some(profiler) = runtime.profiler {
+                self.build_count(profiler, op_index);
+            }
+            op_index += 1;
+
+            // The pointer and the current cell before the op, for the trace
+            let traced = runtime
+                .tracer
+                .map(|tracer| (tracer, index, self.load_current_value(tape_ptr, index)));
+
+            let (span, op) = match node_type {
+                Node::PlusNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    self.emit_change_data_value(tape_ptr, index, 1);
+                    (*span, b'+')
+                }
+                Node::MinusNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    self.emit_change_data_value(tape_ptr, index, -1);
+                    (*span, b'-')
+                }
+                Node::IncrementPtrNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    index = self.emit_move_pointer(index, 1);
+                    self.emit_bounds_check(index, *span, runtime, main_fn_value);
+                    self.update_debug_index(debug, index);
+                    (*span, b'>')
+                }
+                Node::DecrementPtrNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    index = self.emit_move_pointer(index, -1);
+                    self.emit_bounds_check(index, *span, runtime, main_fn_value);
+                    self.update_debug_index(debug, index);
+                    (*span, b'<')
+                }
+                Node::PrintCurrPosNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    self.emit_putchar(tape_ptr, index, runtime);
+                    (*span, b'.')
+                }
+                Node::ReadCurrPosNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    self.emit_getchar(tape_ptr, index, runtime);
+                    (*span, b',')
+                }
+                Node::LoopExpr(expr_val, span) => {
+                    self.set_debug_location(debug, *span);
+                    // `[` is logged once when the loop is entered, like the
+                    // interpreter does, so the record goes into the preheader
+                    if let Some((tracer, pointer, cell)) = traced {
+                        self.build_trace_call(tracer, *span, b'[', pointer, cell, cell);
+                    }
+
+                    index = self.build_start_loop(
+                        &mut loop_stack,
+                        tape_ptr,
+                        index,
                         main_fn_value,
-                        loop_stack.clone(),
+                        debug,
                     );
+
+                    self.push_debug_scope(debug, *span);
+                    work.push(expr_val.iter());
+                    continue;
+                }
+                Node::LoopCloseNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    // `]` is logged on every back edge, before the branch
+                    if let Some((tracer, pointer, cell)) = traced {
+                        self.build_trace_call(tracer, *span, b']', pointer, cell, cell);
+                    }
+
+                    index = self.build_loop_close(&mut loop_stack, index);
+                    continue;
+                }
+                Node::AddNode(amount, span) => {
+                    self.set_debug_location(debug, *span);
+                    self.emit_change_data_value(tape_ptr, index, *amount);
+                    (*span, if *amount < 0 { b'-' } else { b'+' })
+                }
+                Node::MoveNode(amount, span) => {
+                    self.set_debug_location(debug, *span);
+                    index = self.emit_move_pointer(index, *amount);
+                    self.emit_bounds_check(index, *span, runtime, main_fn_value);
+                    self.update_debug_index(debug, index);
+                    (*span, if *amount < 0 { b'<' } else { b'>' })
                 }
-                Node::LoopCloseNode => self.build_loop_close(&mut loop_stack),
-                _ => {}
+                Node::ClearNode(span) => {
+                    self.set_debug_location(debug, *span);
+                    let pointer = self.current_cell_pointer(tape_ptr, index);
+                    self.builder
+                        .build_store(pointer, self.cell_type().const_zero());
+                    (*span, b'[')
+                }
+                Node::MulNode(factors, span) => {
+                    self.set_debug_location(debug, *span);
+                    self.emit_multiply(tape_ptr, index, factors, *span, runtime, main_fn_value);
+                    (*span, b'[')
+                }
+                Node::ScanNode(step, span) => {
+                    self.set_debug_location(debug, *span);
+                    index = self.emit_scan(tape_ptr, index, *step, *span, runtime, main_fn_value);
+                    self.update_debug_index(debug, index);
+                    (*span, b'[')
+                }
+                Node::BreakpointNode(_) | Node::Expr(_) => continue,
+            };
+
+            if let Some((tracer, pointer, before)) = traced {
+                let after = self.load_current_value(tape_ptr, index);
+                self.build_trace_call(tracer, span, op, pointer, before, after);
             }
         }
+
+        index
     }
 
+    /// Opens a loop and returns the tape index inside its body
     fn build_start_loop(
         &self,
         loop_stack: &mut Vec<Loop<'ctx>>,
-        data_ptr: PointerValue<'ctx>,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
         main_fn_value: FunctionValue,
-    ) {
+        debug: Option<&DebugInfo<'ctx>>,
+    ) -> IntValue<'ctx> {
+        let preheader = self.builder.get_insert_block().unwrap();
+        let check = self.context.append_basic_block(main_fn_value, "check_loop");
+
+        self.builder.build_unconditional_branch(check);
+        self.builder.position_at_end(check);
+
+        let index_phi = self.builder.build_phi(self.types.i64_type, "loop_index");
+        index_phi.add_incoming(&[(&index, preheader)]);
+
         let new_loop = Loop {
-            check: self.context.append_basic_block(main_fn_value, "check_loop"),
+            check,
             if_true: self
                 .context
                 .append_basic_block(main_fn_value, "if_true_loop"),
             if_false: self
                 .context
                 .append_basic_block(main_fn_value, "if_false_loop"),
+            index_phi,
         };
 
         loop_stack.push(new_loop);
         let last_one = loop_stack.last().unwrap();
 
-        self.builder.build_unconditional_branch(last_one.check);
-        self.builder.position_at_end(last_one.check);
+        let loop_index = index_phi.as_basic_value().into_int_value();
+        self.update_debug_index(debug, loop_index);
 
-        let zero = self.types.i8_type.const_int(0, false);
-        let value = self.load_current_value(data_ptr);
+        let zero = self.cell_type().const_int(0, false);
+        let value = self.load_current_value(tape_ptr, loop_index);
 
         let compare = self
             .builder
@@ -197,20 +663,41 @@ impl<'ctx> Codegen<'ctx> {
             .build_conditional_branch(compare, last_one.if_true, last_one.if_false);
 
         self.builder.position_at_end(last_one.if_true);
+
+        loop_index
     }
 
-    fn build_loop_close(&self, loop_stack: &mut Vec<Loop<'ctx>>) {
-        if let Some(block) = loop_stack.pop() {
-            self.builder.build_unconditional_branch(block.check);
-            self.builder.position_at_end(block.if_false);
+    /// Closes the innermost loop and returns the tape index after it,
+    /// which is the one its header saw last
+    fn build_loop_close(
+        &self,
+        loop_stack: &mut Vec<Loop<'ctx>>,
+        index: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        match loop_stack.pop() {
+            Some(block) => {
+                let latch = self.builder.get_insert_block().unwrap();
+                block.index_phi.add_incoming(&[(&index, latch)]);
+
+                self.builder.build_unconditional_branch(block.check);
+                self.builder.position_at_end(block.if_false);
+
+                block.index_phi.as_basic_value().into_int_value()
+            }
+            None => index,
         }
     }
 
-    fn emit_change_data_value(&self, data_ptr: PointerValue<'ctx>, value: i32) {
-        let amount_const = self.types.i8_type.const_int(value as u64, false);
+    fn emit_change_data_value(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+        value: i32,
+    ) {
+        let amount_const = self.cell_type().const_int(value as u64, false);
 
-        let pointer = self.load_current_pointer(data_ptr);
-        let value = self.load_current_value(data_ptr);
+        let pointer = self.current_cell_pointer(tape_ptr, index);
+        let value = self.builder.build_load(pointer, "ptr_val").into_int_value();
 
         let result = self
             .builder
@@ -219,51 +706,173 @@ impl<'ctx> Codegen<'ctx> {
         self.builder.build_store(pointer, result);
     }
 
-    fn emit_move_pointer(&self, data_ptr: PointerValue<'ctx>, value: i32) {
-        let amount_const = self.types.i32_type.const_int(value as u64, false);
+    fn emit_move_pointer(&self, index: IntValue<'ctx>, value: i64) -> IntValue<'ctx> {
+        let amount_const = self.types.i64_type.const_int(value as u64, true);
 
-        let pointer = self.load_current_pointer(data_ptr);
+        self.builder.build_int_add(index, amount_const, "move_ptr")
+    }
 
-        let result = unsafe {
-            self.builder
-                .build_in_bounds_gep(pointer, &[amount_const], "move_ptr")
-        };
+    /// Adds the current cell times each factor to the cell at each offset
+    /// and zeroes it. Like the loop it replaces, it only touches the other
+    /// cells when the current one is not zero
+    fn emit_multiply(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+        factors: &[(i64, i32)],
+        span: Span,
+        runtime: Runtime<'ctx>,
+        main_fn_value: FunctionValue,
+    ) {
+        let body = self.context.append_basic_block(main_fn_value, "mul_body");
+        let done = self.context.append_basic_block(main_fn_value, "mul_done");
 
-        self.builder.build_store(data_ptr, result);
-    }
+        let zero = self.cell_type().const_zero();
+        let value = self.load_current_value(tape_ptr, index);
+        let compare = self
+            .builder
+            .build_int_compare(IntPredicate::NE, value, zero, "mul_compare");
 
-    fn emit_putchar(&self, data_ptr: PointerValue, putchar_callee: FunctionValue) {
-        let pc_char = self.builder.build_load(
-            self.builder
-                .build_load(data_ptr, "load_ptr")
-                .into_pointer_value(),
-            "load_ptr_val",
-        );
+        self.builder.build_conditional_branch(compare, body, done);
+        self.builder.position_at_end(body);
 
-        let sext = self.builder.build_int_s_extend(
-            pc_char.into_int_value(),
-            self.context.i32_type(),
-            "putchar_sign",
-        );
-        self.builder
-            .build_call(putchar_callee, &[sext.into()], "putchar_call");
+        for &(offset, factor) in factors {
+            let factor_const = self.cell_type().const_int(factor as u64, false);
+            let product = self
+                .builder
+                .build_int_mul(value, factor_const, "mul_product");
+
+            let target = self.emit_move_pointer(index, offset);
+            self.emit_bounds_check(target, span, runtime, main_fn_value);
+            let pointer = self.current_cell_pointer(tape_ptr, target);
+            let old = self.builder.build_load(pointer, "ptr_val").into_int_value();
+            let sum = self.builder.build_int_add(old, product, "mul_sum");
+
+            self.builder.build_store(pointer, sum);
+        }
+
+        let pointer = self.current_cell_pointer(tape_ptr, index);
+        self.builder.build_store(pointer, zero);
+
+        self.builder.build_unconditional_branch(done);
+        self.builder.position_at_end(done);
     }
 
-    /// Helper function that loads the value stored at current pointer
-    fn load_current_value(&self, data_ptr: PointerValue<'ctx>) -> IntValue<'ctx> {
-        let ptr_load = self
+    /// Moves the pointer by `step` until it is on a zero cell and returns
+    /// the index it stopped at
+    fn emit_scan(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+        step: i64,
+        span: Span,
+        runtime: Runtime<'ctx>,
+        main_fn_value: FunctionValue,
+    ) -> IntValue<'ctx> {
+        let preheader = self.builder.get_insert_block().unwrap();
+        let check = self.context.append_basic_block(main_fn_value, "scan_check");
+        let body = self.context.append_basic_block(main_fn_value, "scan_body");
+        let done = self.context.append_basic_block(main_fn_value, "scan_done");
+
+        self.builder.build_unconditional_branch(check);
+        self.builder.position_at_end(check);
+
+        let index_phi = self.builder.build_phi(self.types.i64_type, "scan_index");
+        index_phi.add_incoming(&[(&index, preheader)]);
+        let current = index_phi.as_basic_value().into_int_value();
+
+        let zero = self.cell_type().const_zero();
+        let value = self.load_current_value(tape_ptr, current);
+        let compare = self
             .builder
-            .build_load(data_ptr, "load_ptr")
-            .into_pointer_value();
+            .build_int_compare(IntPredicate::NE, value, zero, "scan_compare");
+
+        self.builder.build_conditional_branch(compare, body, done);
+        self.builder.position_at_end(body);
+
+        let next = self.emit_move_pointer(current, step);
+        self.emit_bounds_check(next, span, runtime, main_fn_value);
+        let latch = self.builder.get_insert_block().unwrap();
+        index_phi.add_incoming(&[(&next, latch)]);
+
+        self.builder.build_unconditional_branch(check);
+        self.builder.position_at_end(done);
+
+        current
+    }
+
+    fn emit_putchar(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+        runtime: Runtime<'ctx>,
+    ) {
+        let pc_char = self.load_current_value(tape_ptr, index);
 
         self.builder
-            .build_load(ptr_load, "ptr_val")
-            .into_int_value()
+            .build_call(runtime.output, &[pc_char.into()], "output_call");
     }
 
-    fn load_current_pointer(&self, data_ptr: PointerValue<'ctx>) -> PointerValue<'ctx> {
+    /// Reads one byte from stdin into the current cell. On EOF the cell is left unchanged
+    fn emit_getchar(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+        runtime: Runtime<'ctx>,
+    ) {
+        let pointer = self.current_cell_pointer(tape_ptr, index);
+
         self.builder
-            .build_load(data_ptr, "load_ptr")
-            .into_pointer_value()
+            .build_call(runtime.get_byte, &[pointer.into()], "get_byte_call");
+    }
+
+    fn cell_type(&self) -> IntType<'ctx> {
+        self.context
+            .custom_width_int_type(self.options.cell_size.bits())
+    }
+
+    fn cell_ptr_type(&self) -> PointerType<'ctx> {
+        self.cell_type().ptr_type(AddressSpace::Generic)
+    }
+
+    /// Zero-extends or truncates an integer to the given type
+    fn build_unsigned_cast(
+        &self,
+        value: IntValue<'ctx>,
+        int_type: IntType<'ctx>,
+        name: &str,
+    ) -> IntValue<'ctx> {
+        let from_bits = value.get_type().get_bit_width();
+        let to_bits = int_type.get_bit_width();
+
+        if from_bits < to_bits {
+            self.builder.build_int_z_extend(value, int_type, name)
+        } else if from_bits > to_bits {
+            self.builder.build_int_truncate(value, int_type, name)
+        } else {
+            value
+        }
+    }
+
+    /// Helper function that loads the value stored at current pointer
+    fn load_current_value(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        let pointer = self.current_cell_pointer(tape_ptr, index);
+
+        self.builder.build_load(pointer, "ptr_val").into_int_value()
+    }
+
+    fn current_cell_pointer(
+        &self,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+    ) -> PointerValue<'ctx> {
+        unsafe {
+            self.builder
+                .build_in_bounds_gep(tape_ptr, &[index], "cell_ptr")
+        }
     }
 }
@@ -0,0 +1,231 @@
+use inkwell::basic_block::BasicBlock;
+use inkwell::debug_info::{
+    AsDIScope, DIFile, DIFlags, DIFlagsConstants, DILocalVariable, DIScope, DIType,
+    DWARFEmissionKind, DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::FlagBehavior;
+use inkwell::values::{FunctionValue, IntValue, PointerValue};
+use inkwell::AddressSpace;
+
+use std::cell::RefCell;
+use std::path::Path;
+
+use super::Codegen;
+use crate::lexer::Span;
+
+/// DWARF encoding for unsigned integers
+const DW_ATE_UNSIGNED: u32 = 0x08;
+
+/// DWARF debug info for the generated `main`. Every op gets a location
+/// pointing at its token, loop bodies are lexical blocks and the tape
+/// and the current index are visible as the `tape` and `ptr` variables
+pub struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    file: DIFile<'ctx>,
+    /// Innermost scope last, starting with the `main` subprogram
+    scopes: RefCell<Vec<DIScope<'ctx>>>,
+    /// Shadow slot of the tape index, described by the `ptr` variable
+    index_slot: RefCell<Option<PointerValue<'ctx>>>,
+}
+
+impl<'ctx> Codegen<'ctx> {
+    /// Creates the compile unit and the subprogram of `main`
+    pub(super) fn build_debug_info(
+        &self,
+        source: &Path,
+        main_fn_value: FunctionValue<'ctx>,
+    ) -> DebugInfo<'ctx> {
+        let filename = source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let directory = source
+            .parent()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        self.module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            self.types.i32_type.const_int(3, false),
+        );
+        self.module.add_basic_value_flag(
+            "Dwarf Version",
+            FlagBehavior::Warning,
+            self.types.i32_type.const_int(4, false),
+        );
+
+        let (builder, compile_unit) = self.module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "brainwash",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+
+        let file = compile_unit.get_file();
+
+        let int_type = builder
+            .create_basic_type("int", 32, DW_ATE_UNSIGNED, DIFlags::PUBLIC)
+            .unwrap();
+        let main_type =
+            builder.create_subroutine_type(file, Some(int_type.as_type()), &[], DIFlags::PUBLIC);
+
+        let subprogram = builder.create_function(
+            compile_unit.as_debug_info_scope(),
+            "main",
+            None,
+            file,
+            1,
+            main_type,
+            false,
+            true,
+            1,
+            DIFlags::PUBLIC,
+            false,
+        );
+        main_fn_value.set_subprogram(subprogram);
+
+        DebugInfo {
+            builder,
+            file,
+            scopes: RefCell::new(vec![subprogram.as_debug_info_scope()]),
+            index_slot: RefCell::new(None),
+        }
+    }
+
+    /// Declares `tape` and `ptr` in the entry block. The index lives in
+    /// SSA registers, so it gets a shadow stack slot that every pointer
+    /// move updates; `mem2reg` turns that back into `dbg.value` calls
+    pub(super) fn build_debug_variables(
+        &self,
+        debug: &DebugInfo<'ctx>,
+        entry_block: BasicBlock<'ctx>,
+        tape_ptr: PointerValue<'ctx>,
+        index: IntValue<'ctx>,
+    ) {
+        let cell_bits = self.options.cell_size.bits() as u64;
+
+        let cell_type = debug
+            .builder
+            .create_basic_type("cell", cell_bits, DW_ATE_UNSIGNED, DIFlags::PUBLIC)
+            .unwrap();
+        let tape_type = debug.builder.create_pointer_type(
+            "tape",
+            cell_type.as_type(),
+            64,
+            64,
+            AddressSpace::Generic,
+        );
+        let index_type = debug
+            .builder
+            .create_basic_type("size_t", 64, DW_ATE_UNSIGNED, DIFlags::PUBLIC)
+            .unwrap();
+
+        let tape_slot = self.builder.build_alloca(self.cell_ptr_type(), "tape");
+        self.builder.build_store(tape_slot, tape_ptr);
+        self.build_debug_declare(debug, entry_block, tape_slot, "tape", tape_type.as_type());
+
+        let index_slot = self.builder.build_alloca(self.types.i64_type, "ptr");
+        self.builder.build_store(index_slot, index);
+        self.build_debug_declare(debug, entry_block, index_slot, "ptr", index_type.as_type());
+
+        debug.index_slot.replace(Some(index_slot));
+    }
+
+    fn build_debug_declare(
+        &self,
+        debug: &DebugInfo<'ctx>,
+        block: BasicBlock<'ctx>,
+        slot: PointerValue<'ctx>,
+        name: &str,
+        ty: DIType<'ctx>,
+    ) {
+        let scope = debug.current_scope();
+
+        let variable: DILocalVariable = debug.builder.create_auto_variable(
+            scope,
+            name,
+            debug.file,
+            1,
+            ty,
+            true,
+            DIFlags::PUBLIC,
+            64,
+        );
+        let location = debug
+            .builder
+            .create_debug_location(self.context, 1, 1, scope, None);
+
+        debug
+            .builder
+            .insert_declare_at_end(slot, Some(variable), None, location, block);
+    }
+
+    /// Attaches the location of `span` to everything emitted from now on
+    pub(super) fn set_debug_location(&self, debug: Option<&DebugInfo<'ctx>>, span: Span) {
+        if let Some(debug) = debug {
+            let location = debug.builder.create_debug_location(
+                self.context,
+                span.start.line,
+                span.start.column,
+                debug.current_scope(),
+                None,
+            );
+
+            self.builder
+                .set_current_debug_location(self.context, location);
+        }
+    }
+
+    /// Opens a lexical block for the body of the loop starting at `span`
+    pub(super) fn push_debug_scope(&self, debug: Option<&DebugInfo<'ctx>>, span: Span) {
+        if let Some(debug) = debug {
+            let block = debug.builder.create_lexical_block(
+                debug.current_scope(),
+                debug.file,
+                span.start.line,
+                span.start.column,
+            );
+
+            debug.scopes.borrow_mut().push(block.as_debug_info_scope());
+        }
+    }
+
+    pub(super) fn pop_debug_scope(&self, debug: Option<&DebugInfo<'ctx>>) {
+        if let Some(debug) = debug {
+            debug.scopes.borrow_mut().pop();
+        }
+    }
+
+    /// Keeps the `ptr` variable in sync with the SSA tape index
+    pub(super) fn update_debug_index(
+        &self,
+        debug: Option<&DebugInfo<'ctx>>,
+        index: IntValue<'ctx>,
+    ) {
+        if let Some(slot) = debug.and_then(|debug| *debug.index_slot.borrow()) {
+            self.builder.build_store(slot, index);
+        }
+    }
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    fn current_scope(&self) -> DIScope<'ctx> {
+        *self.scopes.borrow().last().unwrap()
+    }
+
+    pub fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
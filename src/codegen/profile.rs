@@ -0,0 +1,133 @@
+use inkwell::module::Linkage;
+use inkwell::values::{FunctionValue, GlobalValue};
+use inkwell::IntPredicate;
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use super::sink::Sink;
+use super::Codegen;
+use crate::error::CompileError;
+use crate::interpreter::Program;
+use crate::profile::PROFILE_MAGIC;
+
+/// Execution counters for every op and the code that saves them at exit
+#[derive(Clone, Copy)]
+pub struct Profiler<'ctx> {
+    /// `[ops x i64]`, indexed like [`Program::ops`]
+    counters: GlobalValue<'ctx>,
+    /// `void bw_profile_write()`, writes the counters to the profile
+    pub write: FunctionValue<'ctx>,
+}
+
+impl<'ctx> Codegen<'ctx> {
+    pub(super) fn build_profiler(
+        &self,
+        path: &Path,
+        write_all_fn: FunctionValue<'ctx>,
+        open_fn: Option<FunctionValue<'ctx>>,
+    ) -> Result<Profiler<'ctx>, CompileError> {
+        // Ops are numbered like the interpreter numbers them, which is
+        // how `bw report` maps the counters back to the source
+        let op_count = Program::new(&self.input).len() as u64;
+        let length = u32::try_from(op_count).map_err(|_| {
+            CompileError::CodegenError(format!("{} ops are too many to profile", op_count))
+        })?;
+
+        let counters_type = self.types.i64_type.array_type(length);
+        let counters = self
+            .module
+            .add_global(counters_type, None, "profile_counters");
+        counters.set_linkage(Linkage::Internal);
+        counters.set_initializer(&counters_type.const_zero());
+
+        let sink = self.build_sink("profile", Some(path), write_all_fn, open_fn);
+
+        Ok(Profiler {
+            counters,
+            write: self.build_profile_write(sink, counters, op_count),
+        })
+    }
+
+    /// Counts one execution of op number `op`
+    pub(super) fn build_count(&self, profiler: Profiler<'ctx>, op: usize) {
+        let i64_type = self.types.i64_type;
+
+        let counter = unsafe {
+            self.builder.build_in_bounds_gep(
+                profiler.counters.as_pointer_value(),
+                &[i64_type.const_zero(), i64_type.const_int(op as u64, false)],
+                "counter",
+            )
+        };
+        let count = self.builder.build_load(counter, "count").into_int_value();
+        let new_count =
+            self.builder
+                .build_int_add(count, i64_type.const_int(1, false), "new_count");
+
+        self.builder.build_store(counter, new_count);
+    }
+
+    /// Emits `bw_profile_write`, which writes the magic and the number of
+    /// counters followed by one counter per line
+    fn build_profile_write(
+        &self,
+        sink: Sink<'ctx>,
+        counters: GlobalValue<'ctx>,
+        op_count: u64,
+    ) -> FunctionValue<'ctx> {
+        let write_fn = self.module.add_function(
+            "bw_profile_write",
+            self.types.void_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(write_fn, "entry");
+        let check = self.context.append_basic_block(write_fn, "check_counter");
+        let body = self.context.append_basic_block(write_fn, "write_counter");
+        let done = self.context.append_basic_block(write_fn, "done");
+
+        let i64_type = self.types.i64_type;
+
+        self.builder.position_at_end(entry);
+        self.builder.build_call(sink.open, &[], "open_call");
+        self.build_sink_str(sink, &format!("{} {}\n", PROFILE_MAGIC, op_count));
+        self.builder.build_unconditional_branch(check);
+
+        self.builder.position_at_end(check);
+        let index = self.builder.build_phi(i64_type, "index");
+        let index_val = index.as_basic_value().into_int_value();
+        let in_range = self.builder.build_int_compare(
+            IntPredicate::ULT,
+            index_val,
+            i64_type.const_int(op_count, false),
+            "in_range",
+        );
+        self.builder.build_conditional_branch(in_range, body, done);
+
+        self.builder.position_at_end(body);
+        let counter = unsafe {
+            self.builder.build_in_bounds_gep(
+                counters.as_pointer_value(),
+                &[i64_type.const_zero(), index_val],
+                "counter",
+            )
+        };
+        let count = self.builder.build_load(counter, "count");
+        self.builder
+            .build_call(sink.put_decimal, &[count.into()], "put_decimal_call");
+        self.build_sink_str(sink, "\n");
+        let next_index =
+            self.builder
+                .build_int_add(index_val, i64_type.const_int(1, false), "next_index");
+        self.builder.build_unconditional_branch(check);
+
+        index.add_incoming(&[(&i64_type.const_zero(), entry), (&next_index, body)]);
+
+        self.builder.position_at_end(done);
+        self.builder.build_call(sink.flush, &[], "flush_call");
+        self.builder.build_return(None);
+
+        write_fn
+    }
+}
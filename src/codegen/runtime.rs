@@ -0,0 +1,835 @@
+use inkwell::module::Linkage;
+use inkwell::targets::TargetMachine;
+use inkwell::types::IntType;
+use inkwell::values::{FunctionValue, GlobalValue, IntValue, PointerValue};
+use inkwell::IntPredicate;
+
+use super::profile::Profiler;
+use super::trace::Tracer;
+use super::{Codegen, OutputMode};
+use crate::error::CompileError;
+
+/// Size of the output buffer in bytes
+const OUTPUT_BUFFER_SIZE: u64 = 4096;
+
+/// Entry point and syscall stubs used by freestanding binaries (x86_64 Linux).
+/// `_start` calls `main` and passes its return value to `exit`.
+const FREESTANDING_ASM: &str = "\
+\t.text
+\t.globl\t_start
+\t.type\t_start,@function
+_start:
+\txorl\t%ebp, %ebp
+\tandq\t$-16, %rsp
+\tcallq\tmain
+\tmovl\t%eax, %edi
+\tmovl\t$60, %eax
+\tsyscall
+\t.globl\tbw_sys_read
+\t.type\tbw_sys_read,@function
+bw_sys_read:
+\txorl\t%eax, %eax
+\tsyscall
+\tretq
+\t.globl\tbw_sys_write
+\t.type\tbw_sys_write,@function
+bw_sys_write:
+\tmovl\t$1, %eax
+\tsyscall
+\tretq
+\t.globl\tbw_sys_open
+\t.type\tbw_sys_open,@function
+bw_sys_open:
+\tmovl\t$2, %eax
+\tsyscall
+\tretq
+";
+
+/// Internal helper functions the generated code calls to do I/O
+#[derive(Clone, Copy)]
+pub struct Runtime<'ctx> {
+    /// `void bw_put_byte(i8)`, writes one byte to stdout
+    pub put_byte: FunctionValue<'ctx>,
+    /// `void bw_output(cell)`, writes a cell in the configured output mode
+    pub output: FunctionValue<'ctx>,
+    /// `void bw_get_byte(cell*)`, reads one byte from stdin into the given
+    /// cell. On EOF the cell is left unchanged
+    pub get_byte: FunctionValue<'ctx>,
+    /// `void bw_flush()`, writes out everything that is still buffered
+    pub flush: FunctionValue<'ctx>,
+    /// `void bw_write_all(i32 fd, i8* data, i64 len)`, writes unbuffered
+    pub write_all: FunctionValue<'ctx>,
+    /// Logs executed ops, only there if tracing is enabled
+    pub tracer: Option<Tracer<'ctx>>,
+    /// Counts executed ops, only there if profiling is enabled
+    pub profiler: Option<Profiler<'ctx>>,
+}
+
+/// Functions provided by libc or by the freestanding syscall stubs
+#[derive(Clone, Copy)]
+struct IoFunctions<'ctx> {
+    write: FunctionValue<'ctx>,
+    read: FunctionValue<'ctx>,
+    /// `open` from libc or the `bw_sys_open` stub, only declared when a
+    /// trace or a profile is written
+    open: Option<FunctionValue<'ctx>>,
+    getchar: Option<FunctionValue<'ctx>>,
+}
+
+impl<'ctx> Codegen<'ctx> {
+    /// Emits the I/O helpers into the module. Has to be called before
+    /// the builder is positioned inside `main`
+    pub(super) fn build_runtime(&self) -> Result<Runtime<'ctx>, CompileError> {
+        let io_fns = self.declare_io_functions();
+
+        let buffer = if self.options.unbuffered {
+            None
+        } else {
+            Some(self.build_output_buffer())
+        };
+
+        let write_all = self.build_write_all(io_fns);
+        let flush = self.build_flush(write_all, buffer);
+        let put_byte = self.build_put_byte(io_fns, buffer, flush);
+        let output = self.build_output(put_byte);
+        let get_byte = self.build_get_byte(io_fns, flush);
+        let tracer = self
+            .options
+            .trace
+            .as_ref()
+            .map(|trace| self.build_tracer(trace, write_all, io_fns.open));
+        let profiler = self
+            .options
+            .profile
+            .as_ref()
+            .map(|path| self.build_profiler(path, write_all, io_fns.open))
+            .transpose()?;
+
+        Ok(Runtime {
+            put_byte,
+            output,
+            get_byte,
+            flush,
+            write_all,
+            tracer,
+            profiler,
+        })
+    }
+
+    /// `FREESTANDING_ASM` only works on x86_64 Linux, anything else would
+    /// silently get a broken binary
+    pub(super) fn check_freestanding_target(&self) -> Result<(), CompileError> {
+        let triple = TargetMachine::get_default_triple();
+        let triple = triple.as_str().to_string_lossy();
+
+        if supports_freestanding(&triple) {
+            Ok(())
+        } else {
+            Err(CompileError::CodegenError(format!(
+                "freestanding binaries can only be built for x86_64 Linux, not {}",
+                triple
+            )))
+        }
+    }
+
+    /// Declares the external functions used for reading and writing
+    fn declare_io_functions(&self) -> IoFunctions<'ctx> {
+        let i32_type = self.types.i32_type;
+        let writes_files = self.options.trace.is_some() || self.options.profile.is_some();
+
+        if self.options.freestanding {
+            self.module.set_inline_assembly(FREESTANDING_ASM);
+
+            IoFunctions {
+                write: self.module.add_function(
+                    "bw_sys_write",
+                    self.types.syscall_io_fn_type,
+                    Some(Linkage::External),
+                ),
+                read: self.module.add_function(
+                    "bw_sys_read",
+                    self.types.syscall_io_fn_type,
+                    Some(Linkage::External),
+                ),
+                open: if writes_files {
+                    let open_fn_type = i32_type.fn_type(
+                        &[
+                            self.types.i8_ptr_type.into(),
+                            i32_type.into(),
+                            i32_type.into(),
+                        ],
+                        false,
+                    );
+                    Some(self.module.add_function(
+                        "bw_sys_open",
+                        open_fn_type,
+                        Some(Linkage::External),
+                    ))
+                } else {
+                    None
+                },
+                getchar: None,
+            }
+        } else {
+            IoFunctions {
+                write: self.module.add_function(
+                    "write",
+                    self.types.syscall_io_fn_type,
+                    Some(Linkage::External),
+                ),
+                read: self.module.add_function(
+                    "read",
+                    self.types.syscall_io_fn_type,
+                    Some(Linkage::External),
+                ),
+                open: if writes_files {
+                    // open(2) is variadic, the mode is passed as the third argument
+                    let open_fn_type =
+                        i32_type.fn_type(&[self.types.i8_ptr_type.into(), i32_type.into()], true);
+                    Some(
+                        self.module
+                            .add_function("open", open_fn_type, Some(Linkage::External)),
+                    )
+                } else {
+                    None
+                },
+                getchar: Some(self.module.add_function(
+                    "getchar",
+                    self.types.getchar_fn_type,
+                    Some(Linkage::External),
+                )),
+            }
+        }
+    }
+
+    /// Output buffer and its fill level, both zero-initialized globals
+    fn build_output_buffer(&self) -> (GlobalValue<'ctx>, GlobalValue<'ctx>) {
+        let buffer_type = self.types.i8_type.array_type(OUTPUT_BUFFER_SIZE as u32);
+
+        let buffer = self.module.add_global(buffer_type, None, "out_buf");
+        buffer.set_linkage(Linkage::Internal);
+        buffer.set_initializer(&buffer_type.const_zero());
+
+        let length = self.module.add_global(self.types.i64_type, None, "out_len");
+        length.set_linkage(Linkage::Internal);
+        length.set_initializer(&self.types.i64_type.const_zero());
+
+        (buffer, length)
+    }
+
+    /// Emits `void bw_write_all(i32 fd, i8* data, i64 len)`, which writes
+    /// the whole range to `fd`
+    fn build_write_all(&self, io_fns: IoFunctions<'ctx>) -> FunctionValue<'ctx> {
+        let i64_type = self.types.i64_type;
+        let write_all_fn_type = self.context.void_type().fn_type(
+            &[
+                self.types.i32_type.into(),
+                self.types.i8_ptr_type.into(),
+                i64_type.into(),
+            ],
+            false,
+        );
+
+        let write_all_fn =
+            self.module
+                .add_function("bw_write_all", write_all_fn_type, Some(Linkage::Internal));
+
+        let entry = self.context.append_basic_block(write_all_fn, "entry");
+        let check = self
+            .context
+            .append_basic_block(write_all_fn, "check_written");
+        let body = self.context.append_basic_block(write_all_fn, "write_chunk");
+        let done = self.context.append_basic_block(write_all_fn, "done");
+
+        self.builder.position_at_end(entry);
+        let fd = write_all_fn.get_nth_param(0).unwrap().into_int_value();
+        let data = write_all_fn.get_nth_param(1).unwrap().into_pointer_value();
+        let len = write_all_fn.get_nth_param(2).unwrap().into_int_value();
+        let zero = i64_type.const_zero();
+        self.builder.build_unconditional_branch(check);
+
+        // write(2) may write less than asked, so keep going until
+        // everything is out or it fails
+        self.builder.position_at_end(check);
+        let offset = self.builder.build_phi(i64_type, "offset");
+        let offset_val = offset.as_basic_value().into_int_value();
+        let remaining =
+            self.builder
+                .build_int_compare(IntPredicate::ULT, offset_val, len, "remaining");
+        self.builder.build_conditional_branch(remaining, body, done);
+
+        self.builder.position_at_end(body);
+        let chunk = unsafe {
+            self.builder
+                .build_in_bounds_gep(data, &[offset_val], "chunk")
+        };
+        let chunk_len = self.builder.build_int_sub(len, offset_val, "chunk_len");
+        let written = self
+            .builder
+            .build_call(
+                io_fns.write,
+                &[fd.into(), chunk.into(), chunk_len.into()],
+                "write_call",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let failed =
+            self.builder
+                .build_int_compare(IntPredicate::SLE, written, zero, "write_failed");
+        let next_offset = self
+            .builder
+            .build_int_add(offset_val, written, "next_offset");
+        self.builder.build_conditional_branch(failed, done, check);
+
+        offset.add_incoming(&[(&zero, entry), (&next_offset, body)]);
+
+        self.builder.position_at_end(done);
+        self.builder.build_return(None);
+
+        write_all_fn
+    }
+
+    fn build_flush(
+        &self,
+        write_all_fn: FunctionValue<'ctx>,
+        buffer: Option<(GlobalValue<'ctx>, GlobalValue<'ctx>)>,
+    ) -> FunctionValue<'ctx> {
+        let flush_fn =
+            self.module
+                .add_function("bw_flush", self.types.void_fn_type, Some(Linkage::Internal));
+
+        let entry = self.context.append_basic_block(flush_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        if let Some(buffer) = buffer {
+            let stdout = self.types.i32_type.const_int(1, false);
+            self.build_drain_buffer(write_all_fn, stdout, buffer);
+        }
+
+        self.builder.build_return(None);
+
+        flush_fn
+    }
+
+    /// Writes the filled part of `buffer` to `fd` and empties it
+    pub(super) fn build_drain_buffer(
+        &self,
+        write_all_fn: FunctionValue<'ctx>,
+        fd: IntValue<'ctx>,
+        (buffer, length): (GlobalValue<'ctx>, GlobalValue<'ctx>),
+    ) {
+        let zero = self.types.i64_type.const_zero();
+
+        let len = self
+            .builder
+            .build_load(length.as_pointer_value(), "buf_len")
+            .into_int_value();
+        let data = unsafe {
+            self.builder
+                .build_in_bounds_gep(buffer.as_pointer_value(), &[zero, zero], "buf_data")
+        };
+
+        self.builder.build_call(
+            write_all_fn,
+            &[fd.into(), data.into(), len.into()],
+            "write_all_call",
+        );
+        self.builder.build_store(length.as_pointer_value(), zero);
+    }
+
+    fn build_put_byte(
+        &self,
+        io_fns: IoFunctions<'ctx>,
+        buffer: Option<(GlobalValue<'ctx>, GlobalValue<'ctx>)>,
+        flush_fn: FunctionValue<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        let put_byte_fn = self.module.add_function(
+            "bw_put_byte",
+            self.types.put_byte_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(put_byte_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        let byte = put_byte_fn.get_nth_param(0).unwrap().into_int_value();
+
+        // Unbuffered bytes go straight to `write`, libc's `putchar` would
+        // buffer them again whenever stdout isn't a terminal
+        let (buffer, length) = match buffer {
+            Some(buffer) => buffer,
+            None => {
+                let byte_alloca = self.builder.build_alloca(self.types.i8_type, "byte");
+                self.builder.build_store(byte_alloca, byte);
+                self.build_io_call(io_fns.write, 1, byte_alloca, "write_call");
+                self.builder.build_return(None);
+                return put_byte_fn;
+            }
+        };
+
+        let flush_block = self.context.append_basic_block(put_byte_fn, "flush");
+        let done = self.context.append_basic_block(put_byte_fn, "done");
+
+        let len = self
+            .builder
+            .build_load(length.as_pointer_value(), "out_len")
+            .into_int_value();
+        let slot = unsafe {
+            self.builder.build_in_bounds_gep(
+                buffer.as_pointer_value(),
+                &[self.types.i64_type.const_zero(), len],
+                "slot",
+            )
+        };
+        self.builder.build_store(slot, byte);
+
+        let new_len =
+            self.builder
+                .build_int_add(len, self.types.i64_type.const_int(1, false), "new_len");
+        self.builder.build_store(length.as_pointer_value(), new_len);
+
+        let is_full = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            new_len,
+            self.types.i64_type.const_int(OUTPUT_BUFFER_SIZE, false),
+            "is_full",
+        );
+        self.builder
+            .build_conditional_branch(is_full, flush_block, done);
+
+        self.builder.position_at_end(flush_block);
+        self.builder.build_call(flush_fn, &[], "flush_call");
+        self.builder.build_unconditional_branch(done);
+
+        self.builder.position_at_end(done);
+        self.builder.build_return(None);
+
+        put_byte_fn
+    }
+
+    fn build_get_byte(
+        &self,
+        io_fns: IoFunctions<'ctx>,
+        flush_fn: FunctionValue<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        let get_byte_fn_type = self
+            .context
+            .void_type()
+            .fn_type(&[self.cell_ptr_type().into()], false);
+
+        let get_byte_fn =
+            self.module
+                .add_function("bw_get_byte", get_byte_fn_type, Some(Linkage::Internal));
+
+        let entry = self.context.append_basic_block(get_byte_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        let cell = get_byte_fn.get_nth_param(0).unwrap().into_pointer_value();
+
+        // Prompts have to be visible before we block on input
+        self.builder.build_call(flush_fn, &[], "flush_call");
+
+        let value = self.builder.build_load(cell, "cell_val").into_int_value();
+
+        let (is_eof, byte) = match io_fns.getchar {
+            Some(getchar_fn) => {
+                let gc_char = self
+                    .builder
+                    .build_call(getchar_fn, &[], "getchar_call")
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_int_value();
+
+                let eof = self.types.i32_type.const_int(-1i64 as u64, true);
+                let is_eof =
+                    self.builder
+                        .build_int_compare(IntPredicate::EQ, gc_char, eof, "is_eof");
+
+                (is_eof, gc_char)
+            }
+            None => {
+                let byte_alloca = self.builder.build_alloca(self.types.i8_type, "byte");
+                let read = self
+                    .builder
+                    .build_call(
+                        io_fns.read,
+                        &[
+                            self.types.i32_type.const_zero().into(),
+                            byte_alloca.into(),
+                            self.types.i64_type.const_int(1, false).into(),
+                        ],
+                        "read_call",
+                    )
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_int_value();
+
+                let is_eof = self.builder.build_int_compare(
+                    IntPredicate::SLT,
+                    read,
+                    self.types.i64_type.const_int(1, false),
+                    "is_eof",
+                );
+                let byte = self
+                    .builder
+                    .build_load(byte_alloca, "byte_val")
+                    .into_int_value();
+
+                (is_eof, byte)
+            }
+        };
+
+        let byte = self.build_unsigned_cast(byte, self.cell_type(), "input_cell");
+        let result = self
+            .builder
+            .build_select(is_eof, value, byte, "get_byte_result");
+
+        self.builder.build_store(cell, result);
+        self.builder.build_return(None);
+
+        get_byte_fn
+    }
+
+    /// Emits `bw_output`, which writes a cell according to the output mode
+    fn build_output(&self, put_byte_fn: FunctionValue<'ctx>) -> FunctionValue<'ctx> {
+        let encoder = match self.options.output_mode {
+            OutputMode::Bytes => None,
+            OutputMode::Decimal => Some(self.build_put_decimal(put_byte_fn)),
+            OutputMode::Utf8 => Some(self.build_put_utf8(put_byte_fn)),
+        };
+
+        let output_fn_type = self
+            .context
+            .void_type()
+            .fn_type(&[self.cell_type().into()], false);
+
+        let output_fn =
+            self.module
+                .add_function("bw_output", output_fn_type, Some(Linkage::Internal));
+
+        let entry = self.context.append_basic_block(output_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        let cell = output_fn.get_nth_param(0).unwrap().into_int_value();
+
+        match encoder {
+            Some(encoder_fn) => {
+                let value = self.build_unsigned_cast(cell, self.types.i32_type, "cell_u32");
+                self.builder
+                    .build_call(encoder_fn, &[value.into()], "encode_call");
+            }
+            None => {
+                let byte = self.build_unsigned_cast(cell, self.types.i8_type, "cell_byte");
+                self.builder
+                    .build_call(put_byte_fn, &[byte.into()], "put_byte_call");
+            }
+        }
+
+        self.builder.build_return(None);
+
+        output_fn
+    }
+
+    /// Emits `void bw_put_decimal(i32)`, which writes an unsigned number
+    /// in decimal followed by a newline
+    fn build_put_decimal(&self, put_byte_fn: FunctionValue<'ctx>) -> FunctionValue<'ctx> {
+        let i32_type = self.types.i32_type;
+
+        self.build_put_unsigned("bw_put_decimal", put_byte_fn, i32_type, Some(b'\n'))
+    }
+
+    /// Emits a function that passes the decimal digits of an unsigned
+    /// `value_type` to `put_byte_fn`, followed by `terminator` if given
+    pub(super) fn build_put_unsigned(
+        &self,
+        name: &str,
+        put_byte_fn: FunctionValue<'ctx>,
+        value_type: IntType<'ctx>,
+        terminator: Option<u8>,
+    ) -> FunctionValue<'ctx> {
+        // u32::MAX has 10 digits, u64::MAX 20
+        let max_digits: u64 = if value_type.get_bit_width() > 32 {
+            20
+        } else {
+            10
+        };
+
+        let put_decimal_fn = self.module.add_function(
+            name,
+            self.context
+                .void_type()
+                .fn_type(&[value_type.into()], false),
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(put_decimal_fn, "entry");
+        let convert = self.context.append_basic_block(put_decimal_fn, "convert");
+        let print_check = self
+            .context
+            .append_basic_block(put_decimal_fn, "print_check");
+        let print_body = self
+            .context
+            .append_basic_block(put_decimal_fn, "print_body");
+        let done = self.context.append_basic_block(put_decimal_fn, "done");
+
+        let i64_type = self.types.i64_type;
+        let ten = value_type.const_int(10, false);
+
+        self.builder.position_at_end(entry);
+        let value = put_decimal_fn.get_nth_param(0).unwrap().into_int_value();
+        let digits = self
+            .builder
+            .build_alloca(self.types.i8_type.array_type(max_digits as u32), "digits");
+        self.builder.build_unconditional_branch(convert);
+
+        // Fill the digit buffer from the back
+        self.builder.position_at_end(convert);
+        let rest = self.builder.build_phi(value_type, "rest");
+        let index = self.builder.build_phi(i64_type, "index");
+        let rest_val = rest.as_basic_value().into_int_value();
+        let index_val = index.as_basic_value().into_int_value();
+
+        let quotient = self
+            .builder
+            .build_int_unsigned_div(rest_val, ten, "quotient");
+        let remainder = self
+            .builder
+            .build_int_unsigned_rem(rest_val, ten, "remainder");
+        let next_index =
+            self.builder
+                .build_int_sub(index_val, i64_type.const_int(1, false), "next_index");
+        let digit = self.builder.build_int_add(
+            self.builder
+                .build_int_truncate(remainder, self.types.i8_type, "remainder_byte"),
+            self.types.i8_type.const_int(b'0' as u64, false),
+            "digit",
+        );
+        let slot = unsafe {
+            self.builder
+                .build_in_bounds_gep(digits, &[i64_type.const_zero(), next_index], "slot")
+        };
+        self.builder.build_store(slot, digit);
+
+        let has_more = self.builder.build_int_compare(
+            IntPredicate::NE,
+            quotient,
+            value_type.const_zero(),
+            "has_more",
+        );
+        self.builder
+            .build_conditional_branch(has_more, convert, print_check);
+
+        rest.add_incoming(&[(&value, entry), (&quotient, convert)]);
+        index.add_incoming(&[
+            (&i64_type.const_int(max_digits, false), entry),
+            (&next_index, convert),
+        ]);
+
+        // Write the filled part of the buffer
+        self.builder.position_at_end(print_check);
+        let position = self.builder.build_phi(i64_type, "position");
+        let position_val = position.as_basic_value().into_int_value();
+        let in_range = self.builder.build_int_compare(
+            IntPredicate::ULT,
+            position_val,
+            i64_type.const_int(max_digits, false),
+            "in_range",
+        );
+        self.builder
+            .build_conditional_branch(in_range, print_body, done);
+
+        self.builder.position_at_end(print_body);
+        let slot = unsafe {
+            self.builder
+                .build_in_bounds_gep(digits, &[i64_type.const_zero(), position_val], "slot")
+        };
+        let digit = self.builder.build_load(slot, "digit");
+        self.builder
+            .build_call(put_byte_fn, &[digit.into()], "put_byte_call");
+        let next_position =
+            self.builder
+                .build_int_add(position_val, i64_type.const_int(1, false), "next_position");
+        self.builder.build_unconditional_branch(print_check);
+
+        position.add_incoming(&[(&next_index, convert), (&next_position, print_body)]);
+
+        self.builder.position_at_end(done);
+        if let Some(terminator) = terminator {
+            self.build_put_const(put_byte_fn, terminator);
+        }
+        self.builder.build_return(None);
+
+        put_decimal_fn
+    }
+
+    /// Emits `void bw_put_utf8(i32)`, which writes a codepoint as UTF-8
+    fn build_put_utf8(&self, put_byte_fn: FunctionValue<'ctx>) -> FunctionValue<'ctx> {
+        let put_utf8_fn = self.module.add_function(
+            "bw_put_utf8",
+            self.types.put_u32_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(put_utf8_fn, "entry");
+        let check_two = self.context.append_basic_block(put_utf8_fn, "check_two");
+        let check_three = self.context.append_basic_block(put_utf8_fn, "check_three");
+        let one = self.context.append_basic_block(put_utf8_fn, "one_byte");
+        let two = self.context.append_basic_block(put_utf8_fn, "two_bytes");
+        let three = self.context.append_basic_block(put_utf8_fn, "three_bytes");
+        let four = self.context.append_basic_block(put_utf8_fn, "four_bytes");
+        let done = self.context.append_basic_block(put_utf8_fn, "done");
+
+        let i32_type = self.types.i32_type;
+        let int = |value: u64| i32_type.const_int(value, false);
+
+        self.builder.position_at_end(entry);
+        let raw = put_utf8_fn.get_nth_param(0).unwrap().into_int_value();
+
+        // Surrogates and anything above U+10FFFF can't be encoded
+        let too_big =
+            self.builder
+                .build_int_compare(IntPredicate::UGT, raw, int(0x10FFFF), "too_big");
+        let surrogate_bits = self
+            .builder
+            .build_and(raw, int(0xFFFF_F800), "surrogate_bits");
+        let is_surrogate = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            surrogate_bits,
+            int(0xD800),
+            "is_surrogate",
+        );
+        let invalid = self.builder.build_or(too_big, is_surrogate, "invalid");
+        let codepoint = self
+            .builder
+            .build_select(invalid, int(0xFFFD), raw, "codepoint")
+            .into_int_value();
+
+        let fits_one =
+            self.builder
+                .build_int_compare(IntPredicate::ULT, codepoint, int(0x80), "fits_one");
+        self.builder
+            .build_conditional_branch(fits_one, one, check_two);
+
+        self.builder.position_at_end(check_two);
+        let fits_two =
+            self.builder
+                .build_int_compare(IntPredicate::ULT, codepoint, int(0x800), "fits_two");
+        self.builder
+            .build_conditional_branch(fits_two, two, check_three);
+
+        self.builder.position_at_end(check_three);
+        let fits_three = self.builder.build_int_compare(
+            IntPredicate::ULT,
+            codepoint,
+            int(0x10000),
+            "fits_three",
+        );
+        self.builder
+            .build_conditional_branch(fits_three, three, four);
+
+        // Leading byte prefix and number of continuation bytes per length
+        let sequences = [
+            (one, 0x00, 0),
+            (two, 0xC0, 1),
+            (three, 0xE0, 2),
+            (four, 0xF0, 3),
+        ];
+
+        for &(block, prefix, continuations) in sequences.iter() {
+            self.builder.position_at_end(block);
+
+            self.build_put_utf8_byte(put_byte_fn, codepoint, continuations * 6, 0xFF, prefix);
+            for i in (0..continuations).rev() {
+                self.build_put_utf8_byte(put_byte_fn, codepoint, i * 6, 0x3F, 0x80);
+            }
+
+            self.builder.build_unconditional_branch(done);
+        }
+
+        self.builder.position_at_end(done);
+        self.builder.build_return(None);
+
+        put_utf8_fn
+    }
+
+    /// Writes `((codepoint >> shift) & mask) | prefix` as a single byte
+    fn build_put_utf8_byte(
+        &self,
+        put_byte_fn: FunctionValue<'ctx>,
+        codepoint: IntValue<'ctx>,
+        shift: u64,
+        mask: u64,
+        prefix: u64,
+    ) {
+        let i32_type = self.types.i32_type;
+
+        let shifted = self.builder.build_right_shift(
+            codepoint,
+            i32_type.const_int(shift, false),
+            false,
+            "shifted",
+        );
+        let masked = self
+            .builder
+            .build_and(shifted, i32_type.const_int(mask, false), "masked");
+        let prefixed = self
+            .builder
+            .build_or(masked, i32_type.const_int(prefix, false), "prefixed");
+        let byte = self
+            .builder
+            .build_int_truncate(prefixed, self.types.i8_type, "utf8_byte");
+
+        self.builder
+            .build_call(put_byte_fn, &[byte.into()], "put_byte_call");
+    }
+
+    pub(super) fn build_put_const(&self, put_byte_fn: FunctionValue<'ctx>, byte: u8) {
+        let byte_const = self.types.i8_type.const_int(byte as u64, false);
+
+        self.builder
+            .build_call(put_byte_fn, &[byte_const.into()], "put_byte_call");
+    }
+
+    /// Calls `read`/`write` on a single byte
+    fn build_io_call(
+        &self,
+        callee: FunctionValue<'ctx>,
+        fd: u64,
+        byte_ptr: PointerValue<'ctx>,
+        name: &str,
+    ) {
+        let fd_const = self.types.i32_type.const_int(fd, false);
+        let len_const = self.types.i64_type.const_int(1, false);
+
+        self.builder.build_call(
+            callee,
+            &[fd_const.into(), byte_ptr.into(), len_const.into()],
+            name,
+        );
+    }
+}
+
+/// Whether `triple`, e.g. `x86_64-unknown-linux-gnu`, is one the syscall
+/// stubs are written for
+fn supports_freestanding(triple: &str) -> bool {
+    let mut parts = triple.split('-');
+    parts.next() == Some("x86_64") && parts.any(|part| part == "linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supports_freestanding() {
+        assert!(supports_freestanding("x86_64-unknown-linux-gnu"));
+        assert!(supports_freestanding("x86_64-pc-linux-musl"));
+        assert!(!supports_freestanding("aarch64-unknown-linux-gnu"));
+        assert!(!supports_freestanding("x86_64-apple-darwin"));
+        assert!(!supports_freestanding("x86_64-pc-windows-msvc"));
+    }
+}
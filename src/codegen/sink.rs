@@ -0,0 +1,226 @@
+use inkwell::module::Linkage;
+use inkwell::values::{FunctionValue, GlobalValue};
+use inkwell::IntPredicate;
+
+use std::path::Path;
+
+use super::Codegen;
+
+/// Size of the buffer in front of each sink
+const SINK_BUFFER_SIZE: u64 = 1 << 16;
+
+/// `O_WRONLY | O_CREAT | O_TRUNC` on Linux
+const OPEN_FLAGS: u64 = 0o1101;
+const OPEN_MODE: u64 = 0o644;
+
+const STDERR: u64 = 2;
+
+/// A buffered file the generated code writes diagnostics to, like traces
+/// and profiles, separate from the program's own output
+#[derive(Clone, Copy)]
+pub struct Sink<'ctx> {
+    /// `void bw_<name>_open()`, opens the file. Without a file, or if it
+    /// can't be opened, everything goes to stderr
+    pub open: FunctionValue<'ctx>,
+    /// `void bw_<name>_byte(i8)`, flushes when the buffer fills up
+    pub put_byte: FunctionValue<'ctx>,
+    /// `void bw_<name>_decimal(i64)`, writes an unsigned number
+    pub put_decimal: FunctionValue<'ctx>,
+    /// `void bw_<name>_flush()`
+    pub flush: FunctionValue<'ctx>,
+}
+
+impl<'ctx> Codegen<'ctx> {
+    /// Emits the globals and functions of a sink. `open_fn` is `open` or
+    /// its freestanding stub and has to be there if `path` is
+    pub(super) fn build_sink(
+        &self,
+        name: &str,
+        path: Option<&Path>,
+        write_all_fn: FunctionValue<'ctx>,
+        open_fn: Option<FunctionValue<'ctx>>,
+    ) -> Sink<'ctx> {
+        let buffer_type = self.types.i8_type.array_type(SINK_BUFFER_SIZE as u32);
+
+        let buffer = self
+            .module
+            .add_global(buffer_type, None, &format!("{}_buf", name));
+        buffer.set_linkage(Linkage::Internal);
+        buffer.set_initializer(&buffer_type.const_zero());
+
+        let length = self
+            .module
+            .add_global(self.types.i64_type, None, &format!("{}_len", name));
+        length.set_linkage(Linkage::Internal);
+        length.set_initializer(&self.types.i64_type.const_zero());
+
+        let fd = self
+            .module
+            .add_global(self.types.i32_type, None, &format!("{}_fd", name));
+        fd.set_linkage(Linkage::Internal);
+        fd.set_initializer(&self.types.i32_type.const_int(STDERR, false));
+
+        let flush = self.build_sink_flush(name, write_all_fn, (buffer, length), fd);
+        let put_byte = self.build_sink_byte(name, (buffer, length), flush);
+        let put_decimal = self.build_put_unsigned(
+            &format!("bw_{}_decimal", name),
+            put_byte,
+            self.types.i64_type,
+            None,
+        );
+        let open = self.build_sink_open(name, path.zip(open_fn), fd);
+
+        Sink {
+            open,
+            put_byte,
+            put_decimal,
+            flush,
+        }
+    }
+
+    /// Writes a string that is known at compile time
+    pub(super) fn build_sink_str(&self, sink: Sink<'ctx>, text: &str) {
+        for byte in text.bytes() {
+            self.build_put_const(sink.put_byte, byte);
+        }
+    }
+
+    fn build_sink_flush(
+        &self,
+        name: &str,
+        write_all_fn: FunctionValue<'ctx>,
+        buffer: (GlobalValue<'ctx>, GlobalValue<'ctx>),
+        fd: GlobalValue<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        let flush_fn = self.module.add_function(
+            &format!("bw_{}_flush", name),
+            self.types.void_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(flush_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        let fd = self
+            .builder
+            .build_load(fd.as_pointer_value(), "fd")
+            .into_int_value();
+        self.build_drain_buffer(write_all_fn, fd, buffer);
+        self.builder.build_return(None);
+
+        flush_fn
+    }
+
+    fn build_sink_byte(
+        &self,
+        name: &str,
+        (buffer, length): (GlobalValue<'ctx>, GlobalValue<'ctx>),
+        flush_fn: FunctionValue<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        let put_byte_fn = self.module.add_function(
+            &format!("bw_{}_byte", name),
+            self.types.put_byte_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(put_byte_fn, "entry");
+        let flush_block = self.context.append_basic_block(put_byte_fn, "flush");
+        let done = self.context.append_basic_block(put_byte_fn, "done");
+
+        self.builder.position_at_end(entry);
+        let byte = put_byte_fn.get_nth_param(0).unwrap().into_int_value();
+        let len = self
+            .builder
+            .build_load(length.as_pointer_value(), "len")
+            .into_int_value();
+        let slot = unsafe {
+            self.builder.build_in_bounds_gep(
+                buffer.as_pointer_value(),
+                &[self.types.i64_type.const_zero(), len],
+                "slot",
+            )
+        };
+        self.builder.build_store(slot, byte);
+
+        let new_len =
+            self.builder
+                .build_int_add(len, self.types.i64_type.const_int(1, false), "new_len");
+        self.builder.build_store(length.as_pointer_value(), new_len);
+
+        let is_full = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            new_len,
+            self.types.i64_type.const_int(SINK_BUFFER_SIZE, false),
+            "is_full",
+        );
+        self.builder
+            .build_conditional_branch(is_full, flush_block, done);
+
+        self.builder.position_at_end(flush_block);
+        self.builder.build_call(flush_fn, &[], "flush_call");
+        self.builder.build_unconditional_branch(done);
+
+        self.builder.position_at_end(done);
+        self.builder.build_return(None);
+
+        put_byte_fn
+    }
+
+    fn build_sink_open(
+        &self,
+        name: &str,
+        file: Option<(&Path, FunctionValue<'ctx>)>,
+        fd: GlobalValue<'ctx>,
+    ) -> FunctionValue<'ctx> {
+        let sink_open_fn = self.module.add_function(
+            &format!("bw_{}_open", name),
+            self.types.void_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(sink_open_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        let i32_type = self.types.i32_type;
+
+        if let Some((path, open_fn)) = file {
+            let path = self
+                .builder
+                .build_global_string_ptr(&path.to_string_lossy(), &format!("{}_path", name));
+
+            let opened = self
+                .builder
+                .build_call(
+                    open_fn,
+                    &[
+                        path.as_pointer_value().into(),
+                        i32_type.const_int(OPEN_FLAGS, false).into(),
+                        i32_type.const_int(OPEN_MODE, false).into(),
+                    ],
+                    "open_call",
+                )
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_int_value();
+
+            let failed = self.builder.build_int_compare(
+                IntPredicate::SLT,
+                opened,
+                i32_type.const_zero(),
+                "open_failed",
+            );
+            let new_fd = self.builder.build_select(
+                failed,
+                i32_type.const_int(STDERR, false),
+                opened,
+                "new_fd",
+            );
+            self.builder.build_store(fd.as_pointer_value(), new_fd);
+        }
+
+        self.builder.build_return(None);
+
+        sink_open_fn
+    }
+}
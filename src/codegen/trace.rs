@@ -0,0 +1,169 @@
+use inkwell::module::Linkage;
+use inkwell::values::{FunctionValue, IntValue};
+
+use super::sink::Sink;
+use super::Codegen;
+use crate::lexer::Span;
+use crate::trace::{TraceFormat, TraceOptions, BINARY_MAGIC};
+
+/// Helpers that log every executed op, see [`crate::trace`] for the format
+#[derive(Clone, Copy)]
+pub struct Tracer<'ctx> {
+    /// `void bw_trace_start()`, opens the trace file and writes the header
+    pub open: FunctionValue<'ctx>,
+    /// `void bw_trace(i32 line, i32 column, i8 op, i64 pointer, i32 before, i32 after)`
+    pub record: FunctionValue<'ctx>,
+    /// `void bw_trace_flush()`, writes out the buffered records
+    pub flush: FunctionValue<'ctx>,
+}
+
+impl<'ctx> Codegen<'ctx> {
+    /// Emits the trace helpers into the module. Like the rest of the
+    /// runtime this has to happen before the builder moves into `main`
+    pub(super) fn build_tracer(
+        &self,
+        options: &TraceOptions,
+        write_all_fn: FunctionValue<'ctx>,
+        open_fn: Option<FunctionValue<'ctx>>,
+    ) -> Tracer<'ctx> {
+        let sink = self.build_sink("trace", options.file.as_deref(), write_all_fn, open_fn);
+
+        Tracer {
+            open: self.build_trace_start(options.format, sink),
+            record: self.build_trace_record(options.format, sink),
+            flush: sink.flush,
+        }
+    }
+
+    /// Logs one op. `before` and `after` are cells, `pointer` the tape index
+    pub(super) fn build_trace_call(
+        &self,
+        tracer: Tracer<'ctx>,
+        span: Span,
+        op: u8,
+        pointer: IntValue<'ctx>,
+        before: IntValue<'ctx>,
+        after: IntValue<'ctx>,
+    ) {
+        let i32_type = self.types.i32_type;
+
+        let before = self.build_unsigned_cast(before, i32_type, "trace_before");
+        let after = self.build_unsigned_cast(after, i32_type, "trace_after");
+
+        self.builder.build_call(
+            tracer.record,
+            &[
+                i32_type.const_int(span.start.line as u64, false).into(),
+                i32_type.const_int(span.start.column as u64, false).into(),
+                self.types.i8_type.const_int(op as u64, false).into(),
+                pointer.into(),
+                before.into(),
+                after.into(),
+            ],
+            "trace_call",
+        );
+    }
+
+    /// Emits `bw_trace_start`, which opens the sink and starts binary
+    /// traces with the magic
+    fn build_trace_start(&self, format: TraceFormat, sink: Sink<'ctx>) -> FunctionValue<'ctx> {
+        let start_fn = self.module.add_function(
+            "bw_trace_start",
+            self.types.void_fn_type,
+            Some(Linkage::Internal),
+        );
+
+        let entry = self.context.append_basic_block(start_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        self.builder.build_call(sink.open, &[], "open_call");
+
+        if format == TraceFormat::Binary {
+            for &byte in BINARY_MAGIC.iter() {
+                self.build_put_const(sink.put_byte, byte);
+            }
+        }
+
+        self.builder.build_return(None);
+
+        start_fn
+    }
+
+    /// Emits `bw_trace`, which appends one record in the given format
+    fn build_trace_record(&self, format: TraceFormat, sink: Sink<'ctx>) -> FunctionValue<'ctx> {
+        let i32_type = self.types.i32_type;
+        let i64_type = self.types.i64_type;
+
+        let record_fn_type = self.context.void_type().fn_type(
+            &[
+                i32_type.into(),
+                i32_type.into(),
+                self.types.i8_type.into(),
+                i64_type.into(),
+                i32_type.into(),
+                i32_type.into(),
+            ],
+            false,
+        );
+        let record_fn =
+            self.module
+                .add_function("bw_trace", record_fn_type, Some(Linkage::Internal));
+
+        let entry = self.context.append_basic_block(record_fn, "entry");
+        self.builder.position_at_end(entry);
+
+        let param = |n: u32| record_fn.get_nth_param(n).unwrap().into_int_value();
+        let (line, column, op, pointer, before, after) =
+            (param(0), param(1), param(2), param(3), param(4), param(5));
+
+        match format {
+            TraceFormat::Text => {
+                let put_decimal = |value: IntValue<'ctx>| {
+                    let value = self.build_unsigned_cast(value, i64_type, "field");
+                    self.builder
+                        .build_call(sink.put_decimal, &[value.into()], "put_decimal_call");
+                };
+
+                put_decimal(line);
+                self.build_sink_str(sink, ":");
+                put_decimal(column);
+                self.build_sink_str(sink, " ");
+                self.builder
+                    .build_call(sink.put_byte, &[op.into()], "put_byte_call");
+                self.build_sink_str(sink, " ptr ");
+                put_decimal(pointer);
+                self.build_sink_str(sink, " ");
+                put_decimal(before);
+                self.build_sink_str(sink, " -> ");
+                put_decimal(after);
+                self.build_sink_str(sink, "\n");
+            }
+            TraceFormat::Binary => {
+                // Same layout as `TraceRecord::to_bytes`
+                for &value in [line, column, op, pointer, before, after].iter() {
+                    let value64 = self.build_unsigned_cast(value, i64_type, "field");
+
+                    for i in 0..value.get_type().get_bit_width() / 8 {
+                        let shifted = self.builder.build_right_shift(
+                            value64,
+                            i64_type.const_int(i as u64 * 8, false),
+                            false,
+                            "shifted",
+                        );
+                        let byte = self.builder.build_int_truncate(
+                            shifted,
+                            self.types.i8_type,
+                            "field_byte",
+                        );
+                        self.builder
+                            .build_call(sink.put_byte, &[byte.into()], "put_byte_call");
+                    }
+                }
+            }
+        }
+
+        self.builder.build_return(None);
+
+        record_fn
+    }
+}
@@ -0,0 +1,502 @@
+use inkwell::context::Context;
+use inkwell::memory_buffer::MemoryBuffer;
+use inkwell::targets::{InitializationConfig, Target};
+use inkwell::values::FunctionValue;
+use inkwell::OptimizationLevel;
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::analysis::{Analysis, Analyzer};
+use crate::binary::BinaryGenerator;
+use crate::codegen::{self, CellSize, Codegen, CodegenOptions, OutputMode, Types};
+use crate::error::CompileError;
+use crate::optimizer::{OptWrapper, PassStats, Pipeline};
+use crate::parser::{Node, Parser};
+use crate::prefix::{self, Evaluator};
+use crate::trace::TraceOptions;
+use crate::verify::{self, Stage, Validator};
+
+/// Builder-style entry point that runs the whole pipeline: lexing,
+/// parsing, code generation and, through [`Artifact::link`], turning the
+/// module into an executable
+///
+/// ```no_run
+/// use brainwash::Compiler;
+///
+/// let artifact = Compiler::new()
+///     .tape_size(30000)
+///     .opt_level(3)
+///     .compile_str("++++++++[>++++++++<-]>+.")?;
+///
+/// artifact.link("capital_a")?;
+/// # Ok::<(), brainwash::CompileError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    options: CodegenOptions,
+    opt_level: u32,
+    passes: Pipeline,
+    /// Step budget per input when optimizations are verified
+    verify_steps: Option<u64>,
+    /// Steps of the program that may run at compile time, 0 for none
+    prefix_steps: u64,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {
+            options: CodegenOptions::default(),
+            opt_level: 2,
+            passes: Pipeline::all(),
+            verify_steps: None,
+            prefix_steps: prefix::DEFAULT_MAX_STEPS,
+        }
+    }
+
+    /// Number of cells on the tape, compiling fails unless it is between 1
+    /// and [`codegen::MAX_TAPE_SIZE`]
+    pub fn tape_size(mut self, tape_size: u64) -> Self {
+        self.options.tape_size = tape_size;
+        self
+    }
+
+    /// Optimization level used by [`Artifact::link`], 0 skips `opt`
+    pub fn opt_level(mut self, opt_level: u32) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> Self {
+        self.options.cell_size = cell_size;
+        self
+    }
+
+    pub fn output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.options.output_mode = output_mode;
+        self
+    }
+
+    /// Emit a libc-free binary, see [`CodegenOptions::freestanding`]
+    pub fn freestanding(mut self, freestanding: bool) -> Self {
+        self.options.freestanding = freestanding;
+        self
+    }
+
+    pub fn unbuffered(mut self, unbuffered: bool) -> Self {
+        self.options.unbuffered = unbuffered;
+        self
+    }
+
+    /// Emit DWARF debug info pointing at `source`
+    pub fn debug_source<P: Into<PathBuf>>(mut self, source: Option<P>) -> Self {
+        self.options.debug_source = source.map(Into::into);
+        self
+    }
+
+    /// Make the binary log every op it executes, see [`crate::trace`]
+    pub fn trace(mut self, trace: Option<TraceOptions>) -> Self {
+        self.options.trace = trace;
+        self
+    }
+
+    /// Make the binary count how often each op runs and write the counts to
+    /// `profile` at exit, see [`crate::profile`]
+    pub fn profile_loops<P: Into<PathBuf>>(mut self, profile: Option<P>) -> Self {
+        self.options.profile = profile.map(Into::into);
+        self
+    }
+
+    /// Make the binary exit with an error when the pointer leaves the
+    /// tape. Ops [`crate::analysis`] proves to stay on it go unchecked, and
+    /// the passes keep every step that can leave it, see
+    /// [`Pipeline::bounds_checked`]
+    pub fn bounds_check(mut self, bounds_check: bool) -> Self {
+        self.options.bounds_check = bounds_check;
+        self
+    }
+
+    /// Brainfuck-level passes to run before code generation, all of them
+    /// unless told otherwise. Traces and profiles are matched up with the
+    /// source op by op, so builds with either skip the passes
+    pub fn passes(mut self, passes: Pipeline) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// Check every Brainfuck-level optimization by running the program
+    /// before and after it in the interpreter, on sampled inputs and for at
+    /// most `max_steps` steps per input, see [`crate::verify`]
+    pub fn verify_opt(mut self, max_steps: Option<u64>) -> Self {
+        self.verify_steps = max_steps;
+        self
+    }
+
+    /// Run the start of the program, up to the first `,`, in the
+    /// interpreter for at most `max_steps` steps and compile only the rest,
+    /// see [`crate::prefix`]. Instrumented builds don't, like with
+    /// [`Compiler::passes`], and neither do builds with debug info
+    pub fn prefix_steps(mut self, max_steps: u64) -> Self {
+        self.prefix_steps = max_steps;
+        self
+    }
+
+    pub fn compile_file<P: AsRef<Path>>(&self, filename: P) -> Result<Artifact, CompileError> {
+        self.compile_reader(fs::File::open(filename)?)
+    }
+
+    pub fn compile_str(&self, source: &str) -> Result<Artifact, CompileError> {
+        self.compile_bytes(source.as_bytes())
+    }
+
+    pub fn compile_bytes(&self, source: &[u8]) -> Result<Artifact, CompileError> {
+        codegen::check_tape_size(self.options.tape_size)?;
+        self.compile_tree(Parser::parse_bytes(source)?)
+    }
+
+    /// Compiles everything `reader` produces until EOF, e.g. stdin. The
+    /// source is lexed and parsed in one pass and never held as a whole
+    pub fn compile_reader<R: Read>(&self, reader: R) -> Result<Artifact, CompileError> {
+        codegen::check_tape_size(self.options.tape_size)?;
+        self.compile_tree(Parser::parse_reader(reader)?)
+    }
+
+    fn compile_tree(&self, mut nodes: Node) -> Result<Artifact, CompileError> {
+        // The versions of the program the optimizer went through, oldest first
+        let mut stages = Vec::new();
+        if self.verify_steps.is_some() {
+            stages.push(Stage::new("unoptimized", &nodes));
+        }
+
+        let instrumented = self.options.trace.is_some() || self.options.profile.is_some();
+        let passes = if self.options.bounds_check {
+            self.passes.clone().bounds_checked()
+        } else {
+            self.passes.clone()
+        };
+        let pass_stats = if instrumented {
+            Vec::new()
+        } else {
+            passes.run_with(&mut nodes, |name, nodes| {
+                if self.verify_steps.is_some() {
+                    stages.push(Stage::new(name, nodes));
+                }
+            })
+        };
+
+        if let Some(max_steps) = self.verify_steps {
+            Validator::new(verify::sample_inputs(verify::SAMPLES, 0))
+                .max_steps(max_steps)
+                .tape_size(self.options.tape_size)
+                .cell_size(self.options.cell_size)
+                .bounds_check(self.options.bounds_check)
+                .check(&stages)?;
+        }
+
+        let analysis = self.analyzer().run(&nodes);
+
+        // Ops that ran at compile time can't stop at a breakpoint
+        let debug = self.options.debug_source.is_some();
+        let prefix = if instrumented || debug || self.prefix_steps == 0 {
+            None
+        } else {
+            Evaluator::new()
+                .max_steps(self.prefix_steps)
+                .tape_size(self.options.tape_size)
+                .cell_size(self.options.cell_size)
+                .output_mode(self.options.output_mode)
+                .run(&mut nodes)
+        };
+        let prefix_steps = prefix.as_ref().map_or(0, |prefix| prefix.steps);
+
+        // What is left after the prefix starts where the prefix stopped
+        let safe_ops = if self.options.bounds_check {
+            let analyzer = match &prefix {
+                Some(prefix) => self.analyzer().start(prefix.pointer, &prefix.tape),
+                None => self.analyzer(),
+            };
+            analyzer
+                .run(&nodes)
+                .map_or_else(HashSet::new, |analysis| analysis.safe)
+        } else {
+            HashSet::new()
+        };
+
+        let context = Context::create();
+        let module = context.create_module("bfc");
+        let builder = context.create_builder();
+        let execution_engine = module
+            .create_execution_engine()
+            .map_err(|e| CompileError::LlvmError(e.to_string()))?;
+
+        let types = Types::new(&context);
+
+        let opt: OptWrapper<FunctionValue> = OptWrapper::new(&module);
+        opt.optimize();
+
+        let cdg = Codegen {
+            input: nodes,
+            context: &context,
+            module,
+            builder,
+            execution_engine,
+            types,
+            passes: opt.pass_manager,
+            options: self.options.clone(),
+            prefix,
+            safe_ops,
+        };
+
+        cdg.build_module()?;
+
+        Ok(Artifact {
+            ir: cdg.module.print_to_string().to_string(),
+            freestanding: self.options.freestanding,
+            opt_level: self.opt_level,
+            pass_stats,
+            prefix_steps,
+            analysis,
+        })
+    }
+
+    fn analyzer(&self) -> Analyzer {
+        Analyzer::new()
+            .tape_size(self.options.tape_size)
+            .cell_size(self.options.cell_size)
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A compiled program as textual LLVM IR
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    ir: String,
+    freestanding: bool,
+    opt_level: u32,
+    pass_stats: Vec<(&'static str, PassStats)>,
+    prefix_steps: u64,
+    analysis: Option<Analysis>,
+}
+
+impl Artifact {
+    pub fn ir(&self) -> &str {
+        &self.ir
+    }
+
+    /// What each Brainfuck-level pass did, in the order they ran
+    pub fn pass_stats(&self) -> &[(&'static str, PassStats)] {
+        &self.pass_stats
+    }
+
+    /// Steps of the program that ran at compile time
+    pub fn prefix_steps(&self) -> u64 {
+        self.prefix_steps
+    }
+
+    /// What static analysis found out about the program after the
+    /// Brainfuck-level passes, `None` if it was too big to analyze
+    pub fn analysis(&self) -> Option<&Analysis> {
+        self.analysis.as_ref()
+    }
+
+    pub fn write_ir<P: AsRef<Path>>(&self, filename: P) -> Result<(), CompileError> {
+        fs::write(filename, &self.ir)?;
+        Ok(())
+    }
+
+    /// Builds an executable named `output` with the external LLVM tools
+    pub fn link<T: AsRef<Path> + Display>(&self, output: T) -> Result<(), CompileError> {
+        self.write_ir(format!("{}2.ll", output))?;
+
+        BinaryGenerator::new(output)
+            .freestanding(self.freestanding)
+            .opt_level(self.opt_level)
+            .compile()
+    }
+
+    /// Runs the program in this process through LLVM's JIT instead of
+    /// building an executable and returns what `main` returned. The program
+    /// reads and writes the real stdin and stdout
+    pub fn run_jit(&self) -> Result<i32, CompileError> {
+        if self.freestanding {
+            return Err(CompileError::CodegenError(String::from(
+                "freestanding programs make raw syscalls and can't run in the JIT",
+            )));
+        }
+
+        Target::initialize_native(&InitializationConfig::default())
+            .map_err(CompileError::LlvmError)?;
+
+        let context = Context::create();
+        let buffer = MemoryBuffer::create_from_memory_range_copy(self.ir.as_bytes(), "bfc");
+        let module = context
+            .create_module_from_ir(buffer)
+            .map_err(|e| CompileError::LlvmError(e.to_string()))?;
+
+        let opt_level = match self.opt_level {
+            0 => OptimizationLevel::None,
+            1 => OptimizationLevel::Less,
+            2 => OptimizationLevel::Default,
+            _ => OptimizationLevel::Aggressive,
+        };
+        let execution_engine = module
+            .create_jit_execution_engine(opt_level)
+            .map_err(|e| CompileError::LlvmError(e.to_string()))?;
+
+        let main_fn = module
+            .get_function("main")
+            .ok_or_else(|| CompileError::LlvmError(String::from("the module has no `main`")))?;
+
+        Ok(unsafe { execution_engine.run_function_as_main(main_fn, &[]) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Program;
+    use crate::lexer::Lexer;
+    use crate::trace::TraceFormat;
+
+    #[test]
+    fn test_compile_deeply_nested_loops() {
+        const DEPTH: usize = 100_000;
+
+        // Starting on input keeps the loops from being removed as dead
+        let source = format!(",{}-{}", "[".repeat(DEPTH), "]".repeat(DEPTH));
+        let artifact = Compiler::new().compile_str(&source).unwrap();
+
+        assert!(artifact.ir().contains("check_loop"));
+    }
+
+    #[test]
+    fn test_compile_rejects_bad_tape_sizes() {
+        for &tape_size in &[0, codegen::MAX_TAPE_SIZE + 1] {
+            let result = Compiler::new().tape_size(tape_size).compile_str("+.");
+            assert!(matches!(result, Err(CompileError::CodegenError(_))));
+        }
+
+        assert!(Compiler::new().tape_size(1).compile_str(",.").is_ok());
+    }
+
+    #[test]
+    fn test_compile_unbuffered() {
+        let artifact = Compiler::new()
+            .unbuffered(true)
+            .compile_str(",[.,]")
+            .unwrap();
+
+        assert!(!artifact.ir().contains("out_buf"));
+        assert!(!artifact.ir().contains("putchar"));
+        assert!(artifact.ir().contains("@write("));
+    }
+
+    #[test]
+    fn test_compile_input_free_program() {
+        let source = "++++++++[>++++++++<-]>+.";
+
+        let artifact = Compiler::new().compile_str(source).unwrap();
+        assert!(artifact.prefix_steps() > 0);
+        assert!(artifact.ir().contains("prefix_output"));
+        assert!(!artifact.ir().contains("check_loop"));
+
+        let artifact = Compiler::new().prefix_steps(0).compile_str(source).unwrap();
+        assert_eq!(artifact.prefix_steps(), 0);
+        assert!(!artifact.ir().contains("prefix_output"));
+
+        let artifact = Compiler::new()
+            .debug_source(Some("input_free.bf"))
+            .compile_str(source)
+            .unwrap();
+        assert_eq!(artifact.prefix_steps(), 0);
+
+        // The evaluator works on a small tape however big the real one is
+        let artifact = Compiler::new()
+            .tape_size(codegen::MAX_TAPE_SIZE)
+            .compile_str(source)
+            .unwrap();
+        assert!(artifact.prefix_steps() > 0);
+    }
+
+    /// Program length in ops before any pass, how the instrumented code
+    /// numbers the ops
+    fn op_count(source: &str) -> usize {
+        Program::from_lexer(Lexer::from(source)).unwrap().len()
+    }
+
+    #[test]
+    fn test_compile_profile() {
+        let source = "++[->+<]>.";
+        let artifact = Compiler::new()
+            .profile_loops(Some("loops.profile"))
+            .compile_str(source)
+            .unwrap();
+        let ir = artifact.ir();
+
+        // One counter per op of the unoptimized program, each bumped once
+        let ops = op_count(source);
+        assert!(ir.contains(&format!(
+            "@profile_counters = internal global [{} x i64] zeroinitializer",
+            ops
+        )));
+        for op in 0..ops {
+            assert!(ir.contains(&format!("@profile_counters, i64 0, i64 {})", op)));
+        }
+        assert!(!ir.contains(&format!("@profile_counters, i64 0, i64 {})", ops)));
+        assert!(ir.contains("call void @bw_profile_write()"));
+
+        // The program is compiled as written
+        assert!(artifact.pass_stats().is_empty());
+        assert_eq!(artifact.prefix_steps(), 0);
+    }
+
+    #[test]
+    fn test_compile_trace() {
+        let source = "+[->+<]\n.";
+        let trace = TraceOptions {
+            format: TraceFormat::Text,
+            file: None,
+        };
+        let artifact = Compiler::new()
+            .trace(Some(trace))
+            .compile_str(source)
+            .unwrap();
+        let ir = artifact.ir();
+
+        assert_eq!(ir.matches("call void @bw_trace(").count(), op_count(source));
+        // The `.` on the second line is logged with its position
+        assert!(ir.contains("call void @bw_trace(i32 2, i32 1, i8 46,"));
+        assert!(ir.contains("call void @bw_trace_start()"));
+        assert!(ir.contains("call void @bw_trace_flush()"));
+
+        assert!(artifact.pass_stats().is_empty());
+        assert_eq!(artifact.prefix_steps(), 0);
+    }
+
+    #[test]
+    fn test_compile_bounds_checks() {
+        let compiler = Compiler::new().bounds_check(true);
+
+        // The pointer only ever reaches the first two cells
+        let artifact = compiler.compile_str(",[>+<-]>.").unwrap();
+        assert_eq!(artifact.analysis().unwrap().max_cells, Some(2));
+        assert!(!artifact.ir().contains("off_tape"));
+
+        let artifact = compiler.compile_str(",[>,]").unwrap();
+        assert_eq!(artifact.analysis().unwrap().max_cells, None);
+        assert!(artifact.ir().contains("off_tape"));
+
+        // Folding `<>` away would hide the step off the tape
+        let artifact = compiler.verify_opt(Some(1000)).compile_str("<>").unwrap();
+        assert!(artifact.ir().contains("off_tape"));
+        assert_eq!(artifact.pass_stats()[1], ("fold", PassStats::default()));
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::interpreter::Op;
+use crate::profile::Report;
+
+/// The coverage of a profiled run as an lcov tracefile for `source`, the
+/// path the program was read from.
+///
+/// A line counts as often as its most executed op. Every loop is a branch
+/// on the line of its `[` with two outcomes: running the body and leaving
+/// the loop, so loops that were reached but never ran show up as partially
+/// covered
+pub fn lcov(report: &Report, source: &str) -> String {
+    let program = report.program();
+    let counts = report.counts();
+
+    let mut lines = BTreeMap::new();
+    for (span, &count) in program.spans.iter().zip(counts) {
+        let hits = lines.entry(span.start.line).or_insert(0);
+        *hits = count.max(*hits);
+    }
+
+    let mut out = String::new();
+    writeln!(out, "TN:").unwrap();
+    writeln!(out, "SF:{}", source).unwrap();
+
+    let (mut branches, mut branches_hit) = (0, 0);
+    let loops = program
+        .ops
+        .iter()
+        .enumerate()
+        .filter_map(|(start, op)| match *op {
+            Op::LoopStart(end) => Some((start, end)),
+            _ => None,
+        });
+
+    for (block, (start, end)) in loops.enumerate() {
+        let line = program.spans[start].start.line;
+        let entries = counts[start];
+        // The op after `[` runs whenever the condition holds, at `[` or `]`
+        let body = counts[start + 1];
+        let exits = (entries + counts[end]).saturating_sub(body);
+
+        for (branch, taken) in [body, exits].iter().enumerate() {
+            branches += 1;
+
+            if entries == 0 {
+                writeln!(out, "BRDA:{},{},{},-", line, block, branch).unwrap();
+            } else {
+                writeln!(out, "BRDA:{},{},{},{}", line, block, branch, taken).unwrap();
+                branches_hit += (*taken > 0) as u32;
+            }
+        }
+    }
+
+    writeln!(out, "BRF:{}", branches).unwrap();
+    writeln!(out, "BRH:{}", branches_hit).unwrap();
+
+    for (line, hits) in &lines {
+        writeln!(out, "DA:{},{}", line, hits).unwrap();
+    }
+
+    let lines_hit = lines.values().filter(|&&hits| hits > 0).count();
+    writeln!(out, "LF:{}", lines.len()).unwrap();
+    writeln!(out, "LH:{}", lines_hit).unwrap();
+    writeln!(out, "end_of_record").unwrap();
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{Interpreter, Program};
+    use crate::lexer::Lexer;
+    use std::io;
+
+    fn coverage(source: &str) -> String {
+        let program = Program::from_lexer(Lexer::from(source)).unwrap();
+        let mut interpreter =
+            Interpreter::new(program.clone(), io::empty(), io::sink()).count_ops();
+        interpreter.run().unwrap();
+
+        let profile = interpreter.profile().unwrap();
+        lcov(&Report::new(&program, &profile).unwrap(), "test.bf")
+    }
+
+    #[test]
+    fn test_lcov() {
+        let expected = [
+            "TN:",
+            "SF:test.bf",
+            "BRDA:1,0,0,2",
+            "BRDA:1,0,1,1",
+            "BRDA:2,1,0,0",
+            "BRDA:2,1,1,1",
+            "BRDA:3,2,0,0",
+            "BRDA:3,2,1,1",
+            "BRDA:4,3,0,-",
+            "BRDA:4,3,1,-",
+            "BRF:8",
+            "BRH:4",
+            "DA:1,2",
+            "DA:2,1",
+            "DA:3,1",
+            "DA:4,0",
+            "LF:4",
+            "LH:3",
+            "end_of_record",
+            "",
+        ];
+
+        assert_eq!(coverage("++[-]\n[-]\n[\n[-]]"), expected.join("\n"));
+    }
+}
@@ -0,0 +1,808 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use crate::interpreter::{Interpreter, InterpreterError, Op, Status};
+use crate::lexer::Span;
+
+const HELP: &str = "\
+break LOCATION   Stops at LINE, LINE:COLUMN or *INDEX (instruction index)
+delete ID        Removes a breakpoint
+watch CELL       Stops whenever the cell changes
+unwatch CELL     Removes a watchpoint
+step [N]         Executes N ops, 1 by default
+next             Like step, but runs a loop to its end in one go
+continue         Runs until a breakpoint, a watchpoint or the end
+reverse-step [N] Goes back N ops, needs --record
+reverse-continue Goes back to the last breakpoint or watched change, needs --record
+last-change CELL Shows when the cell last changed, needs --record
+tape [RADIUS]    Shows the cells around the pointer, 8 on each side by default
+info             Lists breakpoints and watchpoints
+restart          Starts over with an empty tape
+quit             Leaves the debugger
+An empty line repeats the last command";
+
+/// Where execution should stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// The op at this line and column, or the first op executed on the line
+    /// when there is no column
+    Position { line: u32, column: Option<u32> },
+    /// The op at this index of the flattened program
+    Instruction(usize),
+}
+
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid breakpoint location: {}", s);
+
+        if let Some(index) = s.strip_prefix('*') {
+            return index
+                .parse()
+                .map(Breakpoint::Instruction)
+                .map_err(|_| invalid());
+        }
+
+        let (line, column) = match s.split_once(':') {
+            Some((line, column)) => (line, Some(column.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+
+        Ok(Breakpoint::Position {
+            line: line.parse().map_err(|_| invalid())?,
+            column,
+        })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Position { line, column: None } => write!(f, "{}", line),
+            Breakpoint::Position {
+                line,
+                column: Some(column),
+            } => write!(f, "{}:{}", line, column),
+            Breakpoint::Instruction(index) => write!(f, "*{}", index),
+        }
+    }
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A `step` or `next` is done
+    Step,
+    /// The breakpoint with this id was hit
+    Breakpoint(usize),
+    /// A `#` in the source was executed
+    BreakpointOp,
+    Watchpoint {
+        cell: usize,
+        old: u32,
+        new: u32,
+    },
+    Finished,
+    /// Going backwards reached the first step
+    Start,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Break(Breakpoint),
+    Delete(usize),
+    Watch(usize),
+    Unwatch(usize),
+    Step(usize),
+    Next,
+    Continue,
+    ReverseStep(usize),
+    ReverseContinue,
+    LastChange(usize),
+    Tape(usize),
+    Info,
+    Restart,
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let argument = words.next();
+
+        let number = |default: Option<usize>| match (argument, default) {
+            (Some(arg), _) => arg
+                .parse()
+                .map_err(|_| format!("`{}` expects a number, got {}", name, arg)),
+            (None, Some(default)) => Ok(default),
+            (None, None) => Err(format!("`{}` expects a number", name)),
+        };
+
+        match name {
+            "break" | "b" => match argument {
+                Some(location) => Ok(Command::Break(location.parse()?)),
+                None => Err(String::from("`break` expects a location")),
+            },
+            "delete" | "d" => Ok(Command::Delete(number(None)?)),
+            "watch" | "w" => Ok(Command::Watch(number(None)?)),
+            "unwatch" => Ok(Command::Unwatch(number(None)?)),
+            "step" | "s" => Ok(Command::Step(number(Some(1))?)),
+            "next" | "n" => Ok(Command::Next),
+            "continue" | "c" => Ok(Command::Continue),
+            "reverse-step" | "rs" => Ok(Command::ReverseStep(number(Some(1))?)),
+            "reverse-continue" | "rc" => Ok(Command::ReverseContinue),
+            "last-change" | "lc" => Ok(Command::LastChange(number(None)?)),
+            "tape" | "t" => Ok(Command::Tape(number(Some(8))?)),
+            "info" | "i" => Ok(Command::Info),
+            "restart" | "r" => Ok(Command::Restart),
+            "help" | "h" => Ok(Command::Help),
+            "quit" | "q" => Ok(Command::Quit),
+            _ => Err(format!("unknown command `{}`, try `help`", name)),
+        }
+    }
+}
+
+/// Source-level debugger on top of the [`Interpreter`]
+pub struct Debugger<R, W> {
+    interpreter: Interpreter<R, W>,
+    /// Breakpoint `id` is at index `id - 1`, deleted ones become `None`
+    /// so the ids of the others stay the same
+    breakpoints: Vec<Option<Breakpoint>>,
+    /// Watched cells with the value they had when last checked
+    watchpoints: Vec<(usize, u32)>,
+    /// Step the debugger last stopped at. A breakpoint there was already
+    /// hit or stepped onto, so `continue` moves past it. Anywhere else,
+    /// e.g. at the start, a breakpoint at the current op stops right away
+    stopped_at: Option<u64>,
+}
+
+impl<R: Read, W: Write> Debugger<R, W> {
+    pub fn new(interpreter: Interpreter<R, W>) -> Self {
+        Debugger {
+            interpreter,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            stopped_at: None,
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<R, W> {
+        &self.interpreter
+    }
+
+    /// Returns the id of the new breakpoint
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(Some(breakpoint));
+        self.breakpoints.len()
+    }
+
+    pub fn delete_breakpoint(&mut self, id: usize) -> bool {
+        match self.breakpoints.get_mut(id.wrapping_sub(1)) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns false if `cell` is not on the tape
+    pub fn watch(&mut self, cell: usize) -> bool {
+        match self.interpreter.tape().get(cell) {
+            Some(&value) => {
+                self.watchpoints.retain(|&(watched, _)| watched != cell);
+                self.watchpoints.push((cell, value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unwatch(&mut self, cell: usize) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|&(watched, _)| watched != cell);
+        self.watchpoints.len() != before
+    }
+
+    /// Executes a single op
+    pub fn step(&mut self) -> Result<StopReason, InterpreterError> {
+        let result = self.advance(true, None);
+        self.stop(result)
+    }
+
+    /// Executes a single op, or a whole loop when standing on its `[`
+    pub fn step_over(&mut self) -> Result<StopReason, InterpreterError> {
+        let result = match self.interpreter.current_op() {
+            Some((Op::LoopStart(end), _)) => self.advance(false, Some(end + 1)),
+            _ => self.advance(true, None),
+        };
+        self.stop(result)
+    }
+
+    /// Runs until something stops the program
+    pub fn resume(&mut self) -> Result<StopReason, InterpreterError> {
+        let here = match self.interpreter.current_op() {
+            Some((_, span)) if self.stopped_at != Some(self.interpreter.steps()) => {
+                self.breakpoint_at(self.interpreter.pc(), span, None)
+            }
+            _ => None,
+        };
+
+        let result = match here {
+            Some(id) => Ok(StopReason::Breakpoint(id)),
+            None => self.advance(false, None),
+        };
+        self.stop(result)
+    }
+
+    pub fn restart(&mut self) {
+        self.interpreter.reset();
+        self.sync_watchpoints();
+        self.stopped_at = None;
+    }
+
+    /// Remembers where the program stopped on its way out of a command
+    fn stop(
+        &mut self,
+        result: Result<StopReason, InterpreterError>,
+    ) -> Result<StopReason, InterpreterError> {
+        self.stopped_at = Some(self.interpreter.steps());
+        result
+    }
+
+    /// Undoes the last op, needs a recording
+    pub fn reverse_step(&mut self) -> Result<StopReason, InterpreterError> {
+        if !self.interpreter.is_recording() {
+            return Err(InterpreterError::NotRecording);
+        }
+
+        let steps = self.interpreter.steps();
+
+        if steps == 0 {
+            return Ok(StopReason::Start);
+        }
+
+        self.interpreter.seek(steps - 1)?;
+        self.sync_watchpoints();
+
+        self.stop(Ok(StopReason::Step))
+    }
+
+    /// Goes back to the last point where a breakpoint was hit or a watched
+    /// cell changed, or to the start. Needs a recording
+    pub fn reverse_continue(&mut self) -> Result<StopReason, InterpreterError> {
+        if !self.interpreter.is_recording() {
+            return Err(InterpreterError::NotRecording);
+        }
+
+        let current = self.interpreter.steps();
+
+        // Like going forwards, a watched change stops right before the op
+        // that made it
+        let watched = self
+            .watchpoints
+            .iter()
+            .filter_map(|&(cell, _)| self.interpreter.last_change(cell, current))
+            .max_by_key(|write| write.step);
+
+        let breakpoint = self.last_breakpoint_hit(current, watched.map(|write| write.step))?;
+
+        let reason = match (breakpoint, watched) {
+            (Some((position, id)), _) => {
+                self.interpreter.seek(position)?;
+                StopReason::Breakpoint(id)
+            }
+            (None, Some(write)) => {
+                self.interpreter.seek(write.step)?;
+                StopReason::Watchpoint {
+                    cell: write.cell,
+                    old: write.old,
+                    new: write.new,
+                }
+            }
+            (None, None) => {
+                self.interpreter.seek(0)?;
+                StopReason::Start
+            }
+        };
+
+        self.sync_watchpoints();
+        self.stop(Ok(reason))
+    }
+
+    /// The last step before `current`, and after `after` if given, where
+    /// a breakpoint stops execution. Only the op positions are recorded,
+    /// so the stretch between two snapshots is replayed at a time, latest
+    /// first
+    fn last_breakpoint_hit(
+        &mut self,
+        current: u64,
+        after: Option<u64>,
+    ) -> Result<Option<(u64, usize)>, InterpreterError> {
+        let interval = self.interpreter.snapshot_interval();
+        let mut segment_end = current;
+
+        while segment_end > after.map_or(0, |after| after + 1) {
+            let segment_start = (segment_end - 1) / interval * interval;
+            let mut previous_line = None;
+            let mut hit = None;
+
+            self.interpreter.seek(segment_start)?;
+
+            while self.interpreter.steps() < segment_end {
+                let position = self.interpreter.steps();
+                let (_, span) = match self.interpreter.current_op() {
+                    Some(current_op) => current_op,
+                    None => break,
+                };
+
+                if after.map_or(true, |after| position > after) {
+                    if let Some(id) = self.breakpoint_at(self.interpreter.pc(), span, previous_line)
+                    {
+                        hit = Some((position, id));
+                    }
+                }
+
+                previous_line = Some(span.start.line);
+                self.interpreter.step()?;
+            }
+
+            if hit.is_some() {
+                return Ok(hit);
+            }
+
+            segment_end = segment_start;
+        }
+
+        Ok(None)
+    }
+
+    /// Watched values have to follow the tape when it jumps
+    fn sync_watchpoints(&mut self) {
+        let tape = self.interpreter.tape();
+
+        for (cell, value) in self.watchpoints.iter_mut() {
+            *value = tape[*cell];
+        }
+    }
+
+    /// Executes ops until a breakpoint, a watchpoint or the end of the
+    /// program stops it, after one op if `single` is set, or when `pc`
+    /// reaches `until`
+    fn advance(
+        &mut self,
+        single: bool,
+        until: Option<usize>,
+    ) -> Result<StopReason, InterpreterError> {
+        loop {
+            let previous_line = self
+                .interpreter
+                .current_op()
+                .map(|(_, span)| span.start.line);
+
+            match self.interpreter.step()? {
+                Status::Finished => return Ok(StopReason::Finished),
+                Status::Breakpoint => return Ok(StopReason::BreakpointOp),
+                Status::Running => {}
+            }
+
+            if let Some(reason) = self.check_watchpoints() {
+                return Ok(reason);
+            }
+
+            let (_, span) = match self.interpreter.current_op() {
+                Some(current) => current,
+                None => return Ok(StopReason::Finished),
+            };
+
+            if single || until == Some(self.interpreter.pc()) {
+                return Ok(StopReason::Step);
+            }
+
+            if let Some(id) = self.breakpoint_at(self.interpreter.pc(), span, previous_line) {
+                return Ok(StopReason::Breakpoint(id));
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Option<StopReason> {
+        let tape = self.interpreter.tape();
+
+        for (cell, value) in self.watchpoints.iter_mut() {
+            let new = tape[*cell];
+
+            if new != *value {
+                let old = std::mem::replace(value, new);
+
+                return Some(StopReason::Watchpoint {
+                    cell: *cell,
+                    old,
+                    new,
+                });
+            }
+        }
+
+        None
+    }
+
+    fn breakpoint_at(&self, pc: usize, span: Span, previous_line: Option<u32>) -> Option<usize> {
+        let position = span.start;
+
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Some(Breakpoint::Instruction(index)) => *index == pc,
+                Some(Breakpoint::Position {
+                    line,
+                    column: Some(column),
+                }) => *line == position.line && *column == position.column,
+                Some(Breakpoint::Position { line, column: None }) => {
+                    *line == position.line && previous_line != Some(position.line)
+                }
+                None => false,
+            })
+            .map(|index| index + 1)
+    }
+
+    /// Current position in the source, the next op and the current cell
+    pub fn location(&self) -> String {
+        match self.interpreter.current_op() {
+            Some((op, span)) => format!(
+                "{} op {} `{}`, ptr {} = {}",
+                span,
+                self.interpreter.pc(),
+                op,
+                self.interpreter.pointer(),
+                self.interpreter.tape()[self.interpreter.pointer()]
+            ),
+            None => format!("finished after {} steps", self.interpreter.steps()),
+        }
+    }
+
+    /// Reads commands from `commands` until `quit` or EOF and writes the
+    /// replies to `console`
+    pub fn run<C, O>(&mut self, commands: C, mut console: O) -> io::Result<()>
+    where
+        C: IntoIterator<Item = io::Result<String>>,
+        O: Write,
+    {
+        writeln!(
+            console,
+            "{} ops, type `help` for a list of commands",
+            self.interpreter.program().len()
+        )?;
+        writeln!(console, "{}", self.location())?;
+
+        let mut last_command = None;
+        let mut lines = commands.into_iter();
+
+        loop {
+            write!(console, "(bw) ")?;
+            console.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+
+            let command = match (line.trim(), last_command) {
+                ("", Some(command)) => command,
+                ("", None) => continue,
+                (line, _) => match line.parse::<Command>() {
+                    Ok(command) => command,
+                    Err(err) => {
+                        writeln!(console, "{}", err)?;
+                        continue;
+                    }
+                },
+            };
+
+            if command == Command::Quit {
+                break;
+            }
+
+            last_command = Some(command);
+            self.execute(command, &mut console)?;
+        }
+
+        Ok(())
+    }
+
+    fn execute<O: Write>(&mut self, command: Command, console: &mut O) -> io::Result<()> {
+        match command {
+            Command::Break(breakpoint) => {
+                let id = self.add_breakpoint(breakpoint);
+                writeln!(console, "Breakpoint {} at {}", id, breakpoint)?;
+            }
+            Command::Delete(id) => {
+                if !self.delete_breakpoint(id) {
+                    writeln!(console, "No breakpoint {}", id)?;
+                }
+            }
+            Command::Watch(cell) => {
+                if self.watch(cell) {
+                    writeln!(console, "Watching cell {}", cell)?;
+                } else {
+                    writeln!(console, "Cell {} is not on the tape", cell)?;
+                }
+            }
+            Command::Unwatch(cell) => {
+                if !self.unwatch(cell) {
+                    writeln!(console, "Cell {} is not watched", cell)?;
+                }
+            }
+            Command::Step(count) => {
+                let mut result = Ok(StopReason::Step);
+
+                for _ in 0..count {
+                    result = self.step();
+
+                    if !matches!(result, Ok(StopReason::Step)) {
+                        break;
+                    }
+                }
+
+                self.report(result, console)?;
+            }
+            Command::Next => {
+                let result = self.step_over();
+                self.report(result, console)?;
+            }
+            Command::Continue => {
+                let result = self.resume();
+                self.report(result, console)?;
+            }
+            Command::ReverseStep(count) => {
+                let mut result = Ok(StopReason::Step);
+
+                for _ in 0..count {
+                    result = self.reverse_step();
+
+                    if !matches!(result, Ok(StopReason::Step)) {
+                        break;
+                    }
+                }
+
+                self.report(result, console)?;
+            }
+            Command::ReverseContinue => {
+                let result = self.reverse_continue();
+                self.report(result, console)?;
+            }
+            Command::LastChange(cell) => {
+                if !self.interpreter.is_recording() {
+                    writeln!(console, "{}", InterpreterError::NotRecording)?;
+                } else {
+                    match self.interpreter.last_change(cell, self.interpreter.steps()) {
+                        Some(write) => writeln!(
+                            console,
+                            "Cell {} last changed from {} to {} at step {} by op {} `{}` at {}",
+                            cell,
+                            write.old,
+                            write.new,
+                            write.step,
+                            write.pc,
+                            self.interpreter.program().ops[write.pc],
+                            self.interpreter.program().spans[write.pc]
+                        )?,
+                        None => writeln!(console, "Cell {} has not changed yet", cell)?,
+                    }
+                }
+            }
+            Command::Tape(radius) => writeln!(console, "{}", self.interpreter.tape_window(radius))?,
+            Command::Info => {
+                for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+                    if let Some(breakpoint) = breakpoint {
+                        writeln!(console, "Breakpoint {} at {}", index + 1, breakpoint)?;
+                    }
+                }
+
+                for (cell, value) in &self.watchpoints {
+                    writeln!(console, "Watching cell {} = {}", cell, value)?;
+                }
+            }
+            Command::Restart => {
+                self.restart();
+                writeln!(console, "{}", self.location())?;
+            }
+            Command::Help => writeln!(console, "{}", HELP)?,
+            Command::Quit => {}
+        }
+
+        Ok(())
+    }
+
+    fn report<O: Write>(
+        &mut self,
+        result: Result<StopReason, InterpreterError>,
+        console: &mut O,
+    ) -> io::Result<()> {
+        // Program output goes first, it was produced before the stop
+        if let Err(InterpreterError::IoError(err)) = self.interpreter.flush() {
+            return Err(err);
+        }
+
+        if self.interpreter.end_output_line() {
+            writeln!(console)?;
+        }
+
+        match result {
+            Ok(StopReason::Breakpoint(id)) => write!(console, "Breakpoint {}, ", id)?,
+            Ok(StopReason::BreakpointOp) => write!(console, "Breakpoint `#`, ")?,
+            Ok(StopReason::Start) => write!(console, "Start of the recording, ")?,
+            Ok(StopReason::Watchpoint { cell, old, new }) => {
+                writeln!(console, "Cell {} changed from {} to {}", cell, old, new)?
+            }
+            Ok(StopReason::Step) | Ok(StopReason::Finished) => {}
+            Err(err) => writeln!(console, "error: {}", err)?,
+        }
+
+        writeln!(console, "{}", self.location())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Program;
+    use crate::lexer::Lexer;
+
+    fn debugger(source: &str) -> Debugger<io::Empty, io::Sink> {
+        let program = Program::from_lexer(Lexer::from_bytes_debug(source.as_bytes())).unwrap();
+
+        Debugger::new(Interpreter::new(program, io::empty(), io::sink()).recording(3))
+    }
+
+    #[test]
+    fn test_parse_breakpoints() {
+        assert_eq!(
+            "12".parse(),
+            Ok(Breakpoint::Position {
+                line: 12,
+                column: None
+            })
+        );
+        assert_eq!(
+            "3:4".parse(),
+            Ok(Breakpoint::Position {
+                line: 3,
+                column: Some(4)
+            })
+        );
+        assert_eq!("*7".parse(), Ok(Breakpoint::Instruction(7)));
+        assert!("x:1".parse::<Breakpoint>().is_err());
+    }
+
+    #[test]
+    fn test_break_on_line() {
+        let mut dbg = debugger("++\n[->+<]\n>.");
+        let id = dbg.add_breakpoint("3".parse().unwrap());
+
+        assert_eq!(dbg.resume().unwrap(), StopReason::Breakpoint(id));
+        assert_eq!(dbg.interpreter().pc(), 8);
+        assert_eq!(dbg.interpreter().tape()[1], 2);
+
+        // The loop on line 2 ran twice, but line 3 is only entered once
+        assert_eq!(dbg.resume().unwrap(), StopReason::Finished);
+    }
+
+    #[test]
+    fn test_break_on_first_op() {
+        let mut dbg = debugger("+\n+");
+        let id = dbg.add_breakpoint(Breakpoint::Instruction(0));
+
+        assert_eq!(dbg.resume().unwrap(), StopReason::Breakpoint(id));
+        assert_eq!(dbg.interpreter().steps(), 0);
+
+        // Stopped on it, so it is passed over
+        assert_eq!(dbg.resume().unwrap(), StopReason::Finished);
+
+        dbg.restart();
+        assert_eq!(dbg.resume().unwrap(), StopReason::Breakpoint(id));
+    }
+
+    #[test]
+    fn test_break_on_instruction() {
+        let mut dbg = debugger("+++[-]");
+        dbg.add_breakpoint(Breakpoint::Instruction(4));
+
+        for remaining in (0..3).rev() {
+            assert_eq!(dbg.resume().unwrap(), StopReason::Breakpoint(1));
+            assert_eq!(dbg.interpreter().tape()[0], remaining + 1);
+        }
+    }
+
+    #[test]
+    fn test_step_over_loop() {
+        let mut dbg = debugger("+++[->+<]>.");
+
+        for _ in 0..3 {
+            assert_eq!(dbg.step_over().unwrap(), StopReason::Step);
+        }
+        assert_eq!(dbg.step_over().unwrap(), StopReason::Step);
+
+        assert_eq!(dbg.interpreter().pc(), 9);
+        assert_eq!(dbg.interpreter().tape()[..2], [0, 3]);
+    }
+
+    #[test]
+    fn test_watchpoints_and_breakpoint_character() {
+        let mut dbg = debugger(">>+#-");
+        assert!(dbg.watch(2));
+
+        assert_eq!(
+            dbg.resume().unwrap(),
+            StopReason::Watchpoint {
+                cell: 2,
+                old: 0,
+                new: 1
+            }
+        );
+        assert_eq!(dbg.resume().unwrap(), StopReason::BreakpointOp);
+        assert_eq!(
+            dbg.resume().unwrap(),
+            StopReason::Watchpoint {
+                cell: 2,
+                old: 1,
+                new: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_reverse_step() {
+        let mut dbg = debugger("+>++");
+        dbg.resume().unwrap();
+
+        assert_eq!(dbg.reverse_step().unwrap(), StopReason::Step);
+        assert_eq!(dbg.interpreter().tape()[..2], [1, 1]);
+        assert_eq!(dbg.interpreter().pc(), 3);
+
+        for _ in 0..3 {
+            assert_eq!(dbg.reverse_step().unwrap(), StopReason::Step);
+        }
+        assert_eq!(dbg.reverse_step().unwrap(), StopReason::Start);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut dbg = debugger("++++[->+<]\n>-");
+        dbg.resume().unwrap();
+
+        assert!(dbg.watch(1));
+        assert_eq!(
+            dbg.reverse_continue().unwrap(),
+            StopReason::Watchpoint {
+                cell: 1,
+                old: 4,
+                new: 3
+            }
+        );
+        assert_eq!(
+            dbg.reverse_continue().unwrap(),
+            StopReason::Watchpoint {
+                cell: 1,
+                old: 3,
+                new: 4
+            }
+        );
+
+        // Stopped in the last iteration, right before the `+`, so the `-`
+        // of the same iteration comes first
+        dbg.unwatch(1);
+        dbg.add_breakpoint(Breakpoint::Instruction(5));
+        assert_eq!(dbg.reverse_continue().unwrap(), StopReason::Breakpoint(1));
+        assert_eq!(dbg.interpreter().tape()[..2], [1, 3]);
+        assert_eq!(dbg.reverse_continue().unwrap(), StopReason::Breakpoint(1));
+        assert_eq!(dbg.interpreter().tape()[..2], [2, 2]);
+
+        dbg.delete_breakpoint(1);
+        assert_eq!(dbg.reverse_continue().unwrap(), StopReason::Start);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{error::Error, fmt, io};
+
+use crate::interpreter::InterpreterError;
+use crate::lexer::LexerError;
+use crate::parser::ParserError;
+use crate::verify::Mismatch;
+
+/// Every way the pipeline from source to executable can fail
+#[derive(Debug)]
+pub enum CompileError {
+    LexerError(LexerError),
+    ParserError(ParserError),
+    /// Code generation ran into a state it can't handle
+    CodegenError(String),
+    /// LLVM rejected the module or could not set something up
+    LlvmError(String),
+    /// An external tool (`opt`, `llc`, `clang`, ...) could not be started or failed
+    ToolError {
+        tool: String,
+        message: String,
+    },
+    IoError(io::Error),
+    /// Running the program in the interpreter failed
+    InterpreterError(InterpreterError),
+    /// An optimization pass changed what the program does, see
+    /// [`crate::verify`]
+    VerificationError(Box<Mismatch>),
+}
+
+impl CompileError {
+    /// Process exit code the CLI uses for this kind of error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CompileError::IoError(_) => 2,
+            CompileError::LexerError(_) => 3,
+            CompileError::ParserError(_) => 4,
+            CompileError::CodegenError(_) => 5,
+            CompileError::LlvmError(_) => 6,
+            CompileError::ToolError { .. } => 7,
+            CompileError::InterpreterError(_) => 8,
+            CompileError::VerificationError(_) => 9,
+        }
+    }
+}
+
+impl Error for CompileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CompileError::LexerError(err) => Some(err),
+            CompileError::ParserError(err) => Some(err),
+            CompileError::IoError(err) => Some(err),
+            CompileError::InterpreterError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::LexerError(err) => write!(f, "{}", err),
+            CompileError::ParserError(err) => write!(f, "{}", err),
+            CompileError::CodegenError(msg) => write!(f, "Code generation failed: {}", msg),
+            CompileError::LlvmError(msg) => write!(f, "LLVM error: {}", msg),
+            CompileError::ToolError { tool, message } => {
+                write!(f, "`{}` failed: {}", tool, message)
+            }
+            CompileError::IoError(err) => write!(f, "{}", err),
+            CompileError::InterpreterError(err) => write!(f, "{}", err),
+            CompileError::VerificationError(mismatch) => write!(f, "{}", mismatch),
+        }
+    }
+}
+
+impl From<LexerError> for CompileError {
+    fn from(err: LexerError) -> Self {
+        CompileError::LexerError(err)
+    }
+}
+
+impl From<ParserError> for CompileError {
+    fn from(err: ParserError) -> Self {
+        CompileError::ParserError(err)
+    }
+}
+
+impl From<io::Error> for CompileError {
+    fn from(err: io::Error) -> Self {
+        CompileError::IoError(err)
+    }
+}
+
+impl From<InterpreterError> for CompileError {
+    fn from(err: InterpreterError) -> Self {
+        CompileError::InterpreterError(err)
+    }
+}
+
+impl From<Box<Mismatch>> for CompileError {
+    fn from(mismatch: Box<Mismatch>) -> Self {
+        CompileError::VerificationError(mismatch)
+    }
+}
@@ -0,0 +1,507 @@
+use std::fmt;
+use std::io;
+
+use crate::codegen::{CellSize, OutputMode, DEFAULT_TAPE_SIZE};
+use crate::error::CompileError;
+use crate::interpreter::{Interpreter, Program};
+use crate::lexer::Lexer;
+use crate::optimizer::Pipeline;
+use crate::parser::Parser;
+
+/// Cells the generated ops work on
+pub const DATA_CELLS: usize = 8;
+/// How deep loops nest. Each level has a counter cell after the data cells
+pub const MAX_DEPTH: usize = 3;
+/// Cells every generated program prints at the end, so backends can be
+/// compared on the final tape as well
+pub const TAPE_CELLS: usize = DATA_CELLS + MAX_DEPTH;
+
+/// Loops run between 1 and this many times
+const MAX_COUNT: u8 = 5;
+/// Generated programs read at most this many bytes of input
+const MAX_INPUT: usize = 8;
+
+/// A small xorshift generator, enough to pick random programs
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero would stay zero forever
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// One step of a generated program. Every op names the cell it works on and
+/// every loop has a counter cell of its own that only it changes, so a loop
+/// runs exactly `count` times and the pointer never leaves the tape
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Op { cell: usize, op: char },
+    Loop { count: u8, body: Vec<Item> },
+}
+
+/// A random program that always terminates, with the input to feed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzProgram {
+    pub items: Vec<Item>,
+    pub input: Vec<u8>,
+}
+
+impl FuzzProgram {
+    /// A program decoded from `size` random bytes
+    pub fn generate(rng: &mut Rng, size: usize) -> Self {
+        let bytes: Vec<u8> = (0..size).map(|_| rng.next_u64() as u8).collect();
+        Self::from_bytes(&bytes)
+    }
+
+    /// Decodes any bytes into a program, which makes this usable as the
+    /// target of a coverage-guided fuzzer: similar bytes give similar
+    /// programs. The first byte says how much input follows, every other
+    /// byte is an op, the start or the end of a loop
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let (input_len, rest) = match bytes.split_first() {
+            Some((&first, rest)) => ((first as usize % (MAX_INPUT + 1)).min(rest.len()), rest),
+            None => (0, bytes),
+        };
+        let (input, code) = rest.split_at(input_len);
+
+        // Bodies of the loops that are still open, innermost last
+        let mut open: Vec<(u8, Vec<Item>)> = Vec::new();
+        let mut items = Vec::new();
+
+        for &byte in code {
+            let cell = (byte >> 3) as usize % DATA_CELLS;
+            let item = match byte % 8 {
+                0 | 1 => Item::Op { cell, op: '+' },
+                2 => Item::Op { cell, op: '-' },
+                3 | 4 => Item::Op { cell, op: '.' },
+                5 => Item::Op { cell, op: ',' },
+                6 if open.len() < MAX_DEPTH => {
+                    open.push(((byte >> 3) % MAX_COUNT + 1, Vec::new()));
+                    continue;
+                }
+                _ => match open.pop() {
+                    Some((count, body)) => Item::Loop { count, body },
+                    None => continue,
+                },
+            };
+
+            match open.last_mut() {
+                Some((_, body)) => body.push(item),
+                None => items.push(item),
+            }
+        }
+
+        while let Some((count, body)) = open.pop() {
+            let item = Item::Loop { count, body };
+
+            match open.last_mut() {
+                Some((_, outer)) => outer.push(item),
+                None => items.push(item),
+            }
+        }
+
+        FuzzProgram {
+            items,
+            input: input.to_vec(),
+        }
+    }
+
+    /// The program as Brainfuck, followed by code that prints the first
+    /// [`TAPE_CELLS`] cells
+    pub fn source(&self) -> String {
+        let mut source = String::new();
+        let mut pointer = 0;
+
+        emit(&self.items, 0, &mut pointer, &mut source);
+
+        for cell in 0..TAPE_CELLS {
+            move_to(cell, &mut pointer, &mut source);
+            source.push('.');
+        }
+
+        source
+    }
+
+    /// Smaller programs that might still fail the same way, the most
+    /// promising first: fewer items, inlined loops, fewer iterations and
+    /// less input
+    fn reductions(&self) -> Vec<FuzzProgram> {
+        let mut reductions: Vec<_> = reduce_items(&self.items)
+            .into_iter()
+            .map(|items| FuzzProgram {
+                items,
+                input: self.input.clone(),
+            })
+            .collect();
+
+        if !self.input.is_empty() {
+            reductions.push(FuzzProgram {
+                items: self.items.clone(),
+                input: self.input[..self.input.len() - 1].to_vec(),
+            });
+        }
+
+        reductions
+    }
+}
+
+fn move_to(cell: usize, pointer: &mut usize, source: &mut String) {
+    let (step, distance) = if cell > *pointer {
+        (">", cell - *pointer)
+    } else {
+        ("<", *pointer - cell)
+    };
+
+    source.push_str(&step.repeat(distance));
+    *pointer = cell;
+}
+
+fn emit(items: &[Item], depth: usize, pointer: &mut usize, source: &mut String) {
+    let counter = DATA_CELLS + depth;
+
+    for item in items {
+        match item {
+            Item::Op { cell, op } => {
+                move_to(*cell, pointer, source);
+                source.push(*op);
+            }
+            Item::Loop { count, body } => {
+                move_to(counter, pointer, source);
+                source.push_str(&"+".repeat(*count as usize));
+                source.push('[');
+                emit(body, depth + 1, pointer, source);
+                move_to(counter, pointer, source);
+                source.push_str("-]");
+            }
+        }
+    }
+}
+
+fn reduce_items(items: &[Item]) -> Vec<Vec<Item>> {
+    let mut reductions = Vec::new();
+
+    for (index, item) in items.iter().enumerate() {
+        let replace = |with: &[Item]| {
+            let mut reduced = items[..index].to_vec();
+            reduced.extend_from_slice(with);
+            reduced.extend_from_slice(&items[index + 1..]);
+            reduced
+        };
+
+        reductions.push(replace(&[]));
+
+        if let Item::Loop { count, body } = item {
+            reductions.push(replace(body));
+
+            if *count > 1 {
+                reductions.push(replace(&[Item::Loop {
+                    count: 1,
+                    body: body.clone(),
+                }]));
+            }
+
+            for reduced_body in reduce_items(body) {
+                reductions.push(replace(&[Item::Loop {
+                    count: *count,
+                    body: reduced_body,
+                }]));
+            }
+        }
+    }
+
+    reductions
+}
+
+/// What running a generated program did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    /// The cells printed after the program, empty if it didn't get there
+    pub tape: Vec<u8>,
+    pub exit_code: i32,
+}
+
+impl Outcome {
+    /// Splits what a generated program wrote into its output and the tape
+    pub fn new(mut stdout: Vec<u8>, exit_code: i32) -> Self {
+        let tape = if exit_code == 0 && stdout.len() >= TAPE_CELLS {
+            stdout.split_off(stdout.len() - TAPE_CELLS)
+        } else {
+            Vec::new()
+        };
+
+        Outcome {
+            output: stdout,
+            tape,
+            exit_code,
+        }
+    }
+}
+
+/// A way to run programs that has to agree with all the others
+pub trait Backend {
+    fn name(&self) -> &str;
+
+    fn run(&self, source: &str, input: &[u8]) -> io::Result<Outcome>;
+}
+
+/// Runs programs in the interpreter, the reference the other backends are
+/// compared with. The tape is set up like the other backends', e.g. with the
+/// same cell size
+#[derive(Debug, Clone, Copy)]
+pub struct InterpreterBackend {
+    pub tape_size: u64,
+    pub cell_size: CellSize,
+    pub output_mode: OutputMode,
+}
+
+impl Default for InterpreterBackend {
+    fn default() -> Self {
+        InterpreterBackend {
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_size: CellSize::default(),
+            output_mode: OutputMode::default(),
+        }
+    }
+}
+
+impl Backend for InterpreterBackend {
+    fn name(&self) -> &str {
+        "interpreter"
+    }
+
+    fn run(&self, source: &str, input: &[u8]) -> io::Result<Outcome> {
+        let program = Program::from_lexer(Lexer::from(source))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+        Ok(self.interpret(program, input))
+    }
+}
+
+impl InterpreterBackend {
+    fn interpret(&self, program: Program, input: &[u8]) -> Outcome {
+        let mut output = Vec::new();
+
+        let result = Interpreter::new(program, input, &mut output)
+            .tape_size(self.tape_size)
+            .cell_size(self.cell_size)
+            .output_mode(self.output_mode)
+            .run();
+        let exit_code = match result {
+            Ok(()) => 0,
+            Err(err) => CompileError::from(err).exit_code(),
+        };
+
+        Outcome::new(output, exit_code)
+    }
+}
+
+/// Runs programs in the interpreter after every Brainfuck-level pass, which
+/// checks the optimizer without leaving the process
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptimizedBackend {
+    pub interpreter: InterpreterBackend,
+}
+
+impl Backend for OptimizedBackend {
+    fn name(&self) -> &str {
+        "optimized interpreter"
+    }
+
+    fn run(&self, source: &str, input: &[u8]) -> io::Result<Outcome> {
+        let mut root = Parser::parse_bytes(source.as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        Pipeline::all().run(&mut root);
+
+        Ok(self.interpreter.interpret(Program::new(&root), input))
+    }
+}
+
+/// How a backend disagreed with the first one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub backend: String,
+    pub reference: String,
+    pub what: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "The {} of {} differs from {}",
+            self.what, self.backend, self.reference
+        )?;
+        writeln!(f, "  {}: {}", self.reference, self.expected)?;
+        write!(f, "  {}: {}", self.backend, self.actual)
+    }
+}
+
+/// Runs `program` on every backend and compares each with the first one
+pub fn find_divergence(
+    program: &FuzzProgram,
+    backends: &[&dyn Backend],
+) -> io::Result<Option<Divergence>> {
+    let source = program.source();
+    let (reference, others) = match backends.split_first() {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let expected = reference.run(&source, &program.input)?;
+
+    for backend in others {
+        let actual = backend.run(&source, &program.input)?;
+
+        let fields = [
+            ("exit code", expected.exit_code != actual.exit_code),
+            ("output", expected.output != actual.output),
+            ("final tape", expected.tape != actual.tape),
+        ];
+
+        if let Some(&(what, _)) = fields.iter().find(|&&(_, differs)| differs) {
+            let show = |outcome: &Outcome| match what {
+                "exit code" => outcome.exit_code.to_string(),
+                "output" => format!("{:?}", String::from_utf8_lossy(&outcome.output)),
+                _ => format!("{:?}", outcome.tape),
+            };
+
+            return Ok(Some(Divergence {
+                backend: backend.name().to_string(),
+                reference: reference.name().to_string(),
+                what,
+                expected: show(&expected),
+                actual: show(&actual),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Shrinks `program` for as long as `fails` holds for a smaller version,
+/// which leaves a program where removing anything makes the failure go away
+pub fn shrink<F>(program: FuzzProgram, mut fails: F) -> FuzzProgram
+where
+    F: FnMut(&FuzzProgram) -> bool,
+{
+    let mut program = program;
+
+    while let Some(smaller) = program
+        .reductions()
+        .into_iter()
+        .find(|candidate| fails(candidate))
+    {
+        program = smaller;
+    }
+
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interpreter() -> InterpreterBackend {
+        InterpreterBackend::default()
+    }
+
+    /// Loses the last byte of output, like a backend that forgets to flush
+    struct Truncating;
+
+    impl Backend for Truncating {
+        fn name(&self) -> &str {
+            "truncating"
+        }
+
+        fn run(&self, source: &str, input: &[u8]) -> io::Result<Outcome> {
+            let mut outcome = interpreter().run(source, input)?;
+            outcome.output.pop();
+            Ok(outcome)
+        }
+    }
+
+    #[test]
+    fn test_generated_programs_terminate() {
+        let mut rng = Rng::new(1);
+
+        for _ in 0..200 {
+            let program = FuzzProgram::generate(&mut rng, 64);
+            let outcome = interpreter()
+                .run(&program.source(), &program.input)
+                .unwrap();
+
+            assert_eq!(outcome.exit_code, 0);
+            assert_eq!(outcome.tape.len(), TAPE_CELLS);
+            // Counters are back at zero once their loops are done
+            assert!(outcome.tape[DATA_CELLS..].iter().all(|&cell| cell == 0));
+        }
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        // One byte of input, then `+` on cell 1, a loop running twice around
+        // `.` on cell 0 and a stray `]`
+        let program = FuzzProgram::from_bytes(&[1, b'x', 8, 14, 3, 7, 7]);
+
+        assert_eq!(program.input, b"x");
+        assert_eq!(
+            program.items,
+            [
+                Item::Op { cell: 1, op: '+' },
+                Item::Loop {
+                    count: 2,
+                    body: vec![Item::Op { cell: 0, op: '.' }]
+                },
+            ]
+        );
+        assert_eq!(
+            program.source(),
+            ">+>>>>>>>++[<<<<<<<<.>>>>>>>>-]<<<<<<<<.>.>.>.>.>.>.>.>.>.>."
+        );
+    }
+
+    #[test]
+    fn test_optimized_backend() {
+        let reference = interpreter();
+        let optimized = OptimizedBackend::default();
+        let backends: [&dyn Backend; 2] = [&reference, &optimized];
+        let mut rng = Rng::new(3);
+
+        for _ in 0..200 {
+            let program = FuzzProgram::generate(&mut rng, 64);
+            assert_eq!(find_divergence(&program, &backends).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let reference = interpreter();
+        let backends: [&dyn Backend; 2] = [&reference, &Truncating];
+        let mut rng = Rng::new(7);
+
+        let program = (0..)
+            .map(|_| FuzzProgram::generate(&mut rng, 64))
+            .find(|program| find_divergence(program, &backends).unwrap().is_some())
+            .unwrap();
+
+        let minimal = shrink(program, |candidate| {
+            find_divergence(candidate, &backends).unwrap().is_some()
+        });
+
+        assert_eq!(minimal.items.len(), 1);
+        assert!(matches!(minimal.items[0], Item::Op { op: '.', .. }));
+
+        let divergence = find_divergence(&minimal, &backends).unwrap().unwrap();
+        assert_eq!(divergence.what, "output");
+    }
+}
@@ -0,0 +1,736 @@
+use std::{error::Error, fmt, io, io::Read, io::Write};
+
+use crate::codegen::{CellSize, OutputMode, DEFAULT_TAPE_SIZE};
+use crate::error::CompileError;
+use crate::lexer::{Lexer, Span};
+use crate::parser::{Node, Parser};
+use crate::profile::Profile;
+use crate::trace::{TraceFormat, TraceRecord, TraceWriter};
+
+mod history;
+
+use history::History;
+pub use history::{CellWrite, DEFAULT_SNAPSHOT_INTERVAL};
+
+/// Most cells the interpreter's tape can have. The whole tape is allocated
+/// at once, four bytes a cell, so it stays far below what binaries can have
+pub const MAX_TAPE_SIZE: u64 = 1 << 24;
+
+#[derive(Debug)]
+pub enum InterpreterError {
+    /// The op at this span moved the pointer off the tape
+    PointerOutOfBounds(Span),
+    /// Travelling back needs a recording, see [`Interpreter::recording`]
+    NotRecording,
+    IoError(io::Error),
+}
+
+impl Error for InterpreterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InterpreterError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpreterError::PointerOutOfBounds(span) => {
+                write!(f, "Pointer moved off the tape at {}", span)
+            }
+            InterpreterError::NotRecording => write!(f, "Execution is not being recorded"),
+            InterpreterError::IoError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for InterpreterError {
+    fn from(err: io::Error) -> Self {
+        InterpreterError::IoError(err)
+    }
+}
+
+/// A single instruction of the flattened program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Right,
+    Left,
+    Output,
+    Input,
+    /// `[`, jumps past the `LoopEnd` at this index when the cell is zero
+    LoopStart(usize),
+    /// `]`, jumps back behind the `LoopStart` at this index when the cell is not zero
+    LoopEnd(usize),
+    /// `#`, stops the debugger
+    Breakpoint,
+    /// Adds the amount to the cell, see [`Node::AddNode`]
+    Change(i32),
+    /// Moves the pointer by the amount, see [`Node::MoveNode`]
+    Move(i64),
+    /// See [`Node::ClearNode`]
+    Clear,
+    /// Multiplies into the cells listed at this index of `Program::factors`,
+    /// see [`Node::MulNode`]
+    Mul(usize),
+    /// See [`Node::ScanNode`]
+    Scan(i64),
+}
+
+impl Op {
+    /// The Brainfuck character the op came from
+    pub fn symbol(self) -> char {
+        match self {
+            Op::Add => '+',
+            Op::Sub => '-',
+            Op::Right => '>',
+            Op::Left => '<',
+            Op::Output => '.',
+            Op::Input => ',',
+            Op::LoopStart(_) => '[',
+            Op::LoopEnd(_) => ']',
+            Op::Breakpoint => '#',
+            Op::Change(amount) if amount < 0 => '-',
+            Op::Change(_) => '+',
+            Op::Move(amount) if amount < 0 => '<',
+            Op::Move(_) => '>',
+            Op::Clear | Op::Mul(_) | Op::Scan(_) => '[',
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+/// The parsed program as a flat list of ops with resolved jump targets.
+/// Indices into `ops` are the instruction indices the debugger uses
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    /// Source location of each op, `spans[i]` belongs to `ops[i]`
+    pub spans: Vec<Span>,
+    /// Offsets and factors of the `Mul` ops
+    pub factors: Vec<Vec<(i64, i32)>>,
+}
+
+impl Program {
+    pub fn new(root: &Node) -> Program {
+        let mut program = Program::default();
+        // Indices of the `LoopStart`s still waiting for their `LoopEnd`
+        let mut open_loops: Vec<usize> = Vec::new();
+        // Node lists still being flattened, innermost loop body last
+        let mut work = vec![std::slice::from_ref(root).iter()];
+
+        while let Some(nodes) = work.last_mut() {
+            let node = match nodes.next() {
+                Some(node) => node,
+                None => {
+                    work.pop();
+                    continue;
+                }
+            };
+
+            let (op, span) = match node {
+                Node::Expr(body) => {
+                    work.push(body.iter());
+                    continue;
+                }
+                Node::LoopExpr(body, span) => {
+                    open_loops.push(program.ops.len());
+                    work.push(body.iter());
+                    // Patched once the matching `LoopCloseNode` shows up
+                    (Op::LoopStart(0), *span)
+                }
+                Node::LoopCloseNode(span) => {
+                    let start = open_loops
+                        .pop()
+                        .expect("the parser only emits closed loops");
+                    program.ops[start] = Op::LoopStart(program.ops.len());
+                    (Op::LoopEnd(start), *span)
+                }
+                Node::PlusNode(span) => (Op::Add, *span),
+                Node::MinusNode(span) => (Op::Sub, *span),
+                Node::IncrementPtrNode(span) => (Op::Right, *span),
+                Node::DecrementPtrNode(span) => (Op::Left, *span),
+                Node::PrintCurrPosNode(span) => (Op::Output, *span),
+                Node::ReadCurrPosNode(span) => (Op::Input, *span),
+                Node::BreakpointNode(span) => (Op::Breakpoint, *span),
+                Node::AddNode(amount, span) => (Op::Change(*amount), *span),
+                Node::MoveNode(amount, span) => (Op::Move(*amount), *span),
+                Node::ClearNode(span) => (Op::Clear, *span),
+                Node::MulNode(factors, span) => {
+                    program.factors.push(factors.clone());
+                    (Op::Mul(program.factors.len() - 1), *span)
+                }
+                Node::ScanNode(step, span) => (Op::Scan(*step), *span),
+            };
+
+            program.ops.push(op);
+            program.spans.push(span);
+        }
+
+        program
+    }
+
+    /// Parses and flattens everything `lexer` produced
+    pub fn from_lexer(lexer: Lexer) -> Result<Program, CompileError> {
+        let nodes = Parser::parse(&lexer)?;
+
+        Ok(Program::new(&nodes))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// What a call to [`Interpreter::step`] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// An op was executed
+    Running,
+    /// A `#` was executed
+    Breakpoint,
+    /// There was nothing left to execute
+    Finished,
+}
+
+/// Runs a [`Program`] one op at a time with the same semantics as the
+/// compiled binary: cells wrap around, EOF leaves the cell unchanged and
+/// output is encoded according to the output mode
+pub struct Interpreter<R, W> {
+    program: Program,
+    tape: Vec<u32>,
+    pointer: usize,
+    /// Index of the next op to execute
+    pc: usize,
+    steps: u64,
+    cell_mask: u32,
+    output_mode: OutputMode,
+    input: R,
+    output: W,
+    /// Whether the output so far stops in the middle of a line
+    line_open: bool,
+    history: Option<History>,
+    trace: Option<TraceWriter<Box<dyn Write>>>,
+    /// How often each op ran, indexed like `program.ops`
+    counts: Option<Vec<u64>>,
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    pub fn new(program: Program, input: R, output: W) -> Self {
+        Interpreter {
+            program,
+            tape: vec![0; DEFAULT_TAPE_SIZE as usize],
+            pointer: 0,
+            pc: 0,
+            steps: 0,
+            cell_mask: u8::MAX as u32,
+            output_mode: OutputMode::default(),
+            input,
+            output,
+            line_open: false,
+            history: None,
+            trace: None,
+            counts: None,
+        }
+    }
+
+    /// Number of cells on the tape.
+    ///
+    /// # Panics
+    ///
+    /// If `tape_size` is 0 or more than [`MAX_TAPE_SIZE`]
+    pub fn tape_size(mut self, tape_size: u64) -> Self {
+        assert!(tape_size > 0, "the tape needs at least one cell");
+        assert!(
+            tape_size <= MAX_TAPE_SIZE,
+            "the interpreter's tape has at most {} cells",
+            MAX_TAPE_SIZE
+        );
+        self.tape = vec![0; tape_size as usize];
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> Self {
+        self.cell_mask = u32::MAX >> (32 - cell_size.bits());
+        self
+    }
+
+    pub fn output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.output_mode = output_mode;
+        self
+    }
+
+    /// Logs every executed op to `out` in the same format as a binary
+    /// compiled with tracing, so the two can be compared
+    pub fn trace<T: Write + 'static>(mut self, format: TraceFormat, out: T) -> Self {
+        self.trace = Some(TraceWriter::new(format, Box::new(out)));
+        self
+    }
+
+    /// Counts how often each op runs, like a binary compiled with
+    /// `--profile-loops` does, see [`Interpreter::profile`]
+    pub fn count_ops(mut self) -> Self {
+        self.counts = Some(vec![0; self.program.len()]);
+        self
+    }
+
+    /// Executes the op at `pc`
+    pub fn step(&mut self) -> Result<Status, InterpreterError> {
+        let op = match self.program.ops.get(self.pc) {
+            Some(&op) => op,
+            None => return Ok(Status::Finished),
+        };
+
+        self.record_snapshot();
+
+        let (pointer, before) = (self.pointer, self.cell());
+        let mut next = self.pc + 1;
+
+        match op {
+            Op::Add => self.set_cell(self.cell().wrapping_add(1)),
+            Op::Sub => self.set_cell(self.cell().wrapping_sub(1)),
+            Op::Right => {
+                if self.pointer + 1 >= self.tape.len() {
+                    return Err(InterpreterError::PointerOutOfBounds(
+                        self.program.spans[self.pc],
+                    ));
+                }
+                self.pointer += 1;
+            }
+            Op::Left => {
+                if self.pointer == 0 {
+                    return Err(InterpreterError::PointerOutOfBounds(
+                        self.program.spans[self.pc],
+                    ));
+                }
+                self.pointer -= 1;
+            }
+            Op::Output => self.write_cell()?,
+            Op::Input => self.read_cell()?,
+            Op::LoopStart(end) => {
+                if self.cell() == 0 {
+                    next = end + 1;
+                }
+            }
+            Op::LoopEnd(start) => {
+                if self.cell() != 0 {
+                    next = start + 1;
+                }
+            }
+            Op::Breakpoint => {}
+            Op::Change(amount) => self.set_cell(self.cell().wrapping_add(amount as u32)),
+            Op::Move(amount) => self.pointer = self.offset(amount)?,
+            Op::Clear => self.set_cell(0),
+            Op::Mul(index) => self.multiply(index)?,
+            Op::Scan(step) => {
+                while self.cell() != 0 {
+                    self.pointer = self.offset(step)?;
+                }
+            }
+        }
+
+        self.trace_op(op, pointer, before)?;
+        self.count_op();
+
+        self.pc = next;
+        self.steps += 1;
+        self.record_step();
+
+        match op {
+            Op::Breakpoint => Ok(Status::Breakpoint),
+            _ => Ok(Status::Running),
+        }
+    }
+
+    /// Runs until the end of the program, ignoring `#`
+    pub fn run(&mut self) -> Result<(), InterpreterError> {
+        while self.step()? != Status::Finished {}
+
+        self.flush()
+    }
+
+    /// Rewinds to the first op with a zeroed tape and drops the recording.
+    /// Input that was already consumed stays consumed
+    pub fn reset(&mut self) {
+        self.tape.iter_mut().for_each(|cell| *cell = 0);
+        self.pointer = 0;
+        self.pc = 0;
+        self.steps = 0;
+        self.clear_history();
+    }
+
+    /// Replaces the program and starts at its first op, keeping the tape
+    /// and the pointer. The recording starts over
+    pub fn load(&mut self, program: Program) {
+        self.program = program;
+        self.pc = 0;
+        self.clear_history();
+
+        if let Some(counts) = self.counts.as_mut() {
+            *counts = vec![0; self.program.len()];
+        }
+    }
+
+    /// Whether the output stopped in the middle of a line. That line counts
+    /// as finished afterwards, so interactive tools can print their own
+    /// messages on a line of their own
+    pub fn end_output_line(&mut self) -> bool {
+        std::mem::replace(&mut self.line_open, false)
+    }
+
+    pub fn flush(&mut self) -> Result<(), InterpreterError> {
+        self.output.flush()?;
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// The op counts so far if [`Interpreter::count_ops`] is on
+    pub fn profile(&self) -> Option<Profile> {
+        self.counts.clone().map(|counts| Profile { counts })
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn tape(&self) -> &[u32] {
+        &self.tape
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Number of ops executed so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// The cells up to `radius` cells on either side of the pointer, the
+    /// current one in brackets
+    pub fn tape_window(&self, radius: usize) -> String {
+        let tape = &self.tape;
+        let pointer = self.pointer;
+
+        let start = pointer.saturating_sub(radius);
+        let end = (pointer + radius + 1).min(tape.len());
+
+        let mut cells = String::from("cell ");
+        let mut values = String::from("value");
+
+        for (index, value) in tape.iter().enumerate().take(end).skip(start) {
+            let value = if index == pointer {
+                format!("[{}]", value)
+            } else {
+                value.to_string()
+            };
+            let width = value.len().max(index.to_string().len()) + 1;
+
+            cells.push_str(&format!("{:>width$}", index, width = width));
+            values.push_str(&format!("{:>width$}", value, width = width));
+        }
+
+        format!("{}\n{}", cells, values)
+    }
+
+    /// The op that will be executed next
+    pub fn current_op(&self) -> Option<(Op, Span)> {
+        self.program
+            .ops
+            .get(self.pc)
+            .map(|&op| (op, self.program.spans[self.pc]))
+    }
+
+    fn cell(&self) -> u32 {
+        self.tape[self.pointer]
+    }
+
+    fn set_cell(&mut self, value: u32) {
+        self.set_cell_at(self.pointer, value);
+    }
+
+    fn set_cell_at(&mut self, cell: usize, value: u32) {
+        let old = self.tape[cell];
+        let new = value & self.cell_mask;
+
+        self.record_write(cell, old, new);
+        self.tape[cell] = new;
+    }
+
+    /// The index `amount` cells away from the pointer, if it is on the tape
+    fn offset(&self, amount: i64) -> Result<usize, InterpreterError> {
+        let cell = self.pointer as i64 + amount;
+
+        if cell < 0 || cell >= self.tape.len() as i64 {
+            return Err(InterpreterError::PointerOutOfBounds(
+                self.program.spans[self.pc],
+            ));
+        }
+        Ok(cell as usize)
+    }
+
+    /// Runs a `Mul`. Like the loop it replaces, it only touches the other
+    /// cells when the current one is not zero
+    fn multiply(&mut self, index: usize) -> Result<(), InterpreterError> {
+        let value = self.cell();
+        if value == 0 {
+            return Ok(());
+        }
+
+        for i in 0..self.program.factors[index].len() {
+            let (offset, factor) = self.program.factors[index][i];
+            let cell = self.offset(offset)?;
+
+            self.set_cell_at(
+                cell,
+                self.tape[cell].wrapping_add(value.wrapping_mul(factor as u32)),
+            );
+        }
+
+        self.set_cell(0);
+        Ok(())
+    }
+
+    /// Logs the op at `pc`, which just ran. `#` is left out since compiled
+    /// binaries don't have it, and so are replayed steps
+    fn trace_op(&mut self, op: Op, pointer: usize, before: u32) -> io::Result<()> {
+        if op == Op::Breakpoint || self.is_replaying() {
+            return Ok(());
+        }
+
+        let span = self.program.spans[self.pc];
+        let after = self.cell();
+
+        match self.trace.as_mut() {
+            Some(trace) => trace.write(&TraceRecord {
+                line: span.start.line,
+                column: span.start.column,
+                op: op.symbol() as u8,
+                pointer: pointer as u64,
+                before,
+                after,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn count_op(&mut self) {
+        if self.is_replaying() {
+            return;
+        }
+
+        if let Some(counts) = self.counts.as_mut() {
+            counts[self.pc] += 1;
+        }
+    }
+
+    fn write_cell(&mut self) -> Result<(), InterpreterError> {
+        // The output of replayed steps was written the first time round
+        if self.is_replaying() {
+            return Ok(());
+        }
+
+        let value = self.cell();
+
+        match self.output_mode {
+            OutputMode::Bytes => {
+                self.output.write_all(&[value as u8])?;
+                self.line_open = value as u8 != b'\n';
+            }
+            OutputMode::Decimal => writeln!(self.output, "{}", value)?,
+            OutputMode::Utf8 => {
+                let ch = std::char::from_u32(value).unwrap_or('\u{FFFD}');
+                let mut encoded = [0; 4];
+                self.output
+                    .write_all(ch.encode_utf8(&mut encoded).as_bytes())?;
+                self.line_open = ch != '\n';
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_cell(&mut self) -> Result<(), InterpreterError> {
+        let input = match self.replay_input() {
+            Some(input) => input,
+            None => {
+                let input = self.read_byte()?;
+                self.record_input(input);
+                input
+            }
+        };
+
+        if let Some(byte) = input {
+            self.set_cell(byte as u32);
+        }
+
+        Ok(())
+    }
+
+    /// The next input byte, `None` on EOF
+    fn read_byte(&mut self) -> Result<Option<u8>, InterpreterError> {
+        // Prompts have to be visible before blocking on input
+        self.output.flush()?;
+
+        let mut byte = [0];
+
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, input: &[u8], cell_size: CellSize, output_mode: OutputMode) -> Vec<u8> {
+        let program = Program::from_lexer(Lexer::from(source)).unwrap();
+        let mut output = Vec::new();
+
+        Interpreter::new(program, input, &mut output)
+            .cell_size(cell_size)
+            .output_mode(output_mode)
+            .run()
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    fn test_run_loops() {
+        let output = run(
+            "++++++++[>++++++++<-]>+.",
+            b"",
+            CellSize::Bits8,
+            OutputMode::Bytes,
+        );
+
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    fn test_cells_wrap_around() {
+        assert_eq!(run("-.", b"", CellSize::Bits8, OutputMode::Bytes), [255]);
+        assert_eq!(
+            run("-.", b"", CellSize::Bits16, OutputMode::Decimal),
+            b"65535\n"
+        );
+        assert_eq!(
+            run("-+.", b"", CellSize::Bits32, OutputMode::Decimal),
+            b"0\n"
+        );
+    }
+
+    #[test]
+    fn test_eof_leaves_cell_unchanged() {
+        let output = run("+++,.,.", b"a", CellSize::Bits8, OutputMode::Bytes);
+
+        assert_eq!(output, b"aa");
+    }
+
+    #[test]
+    fn test_utf8_output() {
+        let output = run(
+            "+++++++++++[>+++++++++++++++++++++<-]>.",
+            b"",
+            CellSize::Bits16,
+            OutputMode::Utf8,
+        );
+
+        assert_eq!(output, "\u{E7}".as_bytes());
+    }
+
+    #[test]
+    fn test_trace() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Lets the test read what the interpreter wrote into its box
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let trace = Shared::default();
+        let program = Program::from_lexer(Lexer::from_bytes_debug(b"+[>\n#-<-]")).unwrap();
+
+        Interpreter::new(program, io::empty(), io::sink())
+            .trace(TraceFormat::Text, trace.clone())
+            .run()
+            .unwrap();
+
+        let trace = String::from_utf8(trace.0.borrow().clone()).unwrap();
+        let expected = [
+            "1:1 + ptr 0 0 -> 1",
+            "1:2 [ ptr 0 1 -> 1",
+            "1:3 > ptr 0 1 -> 0",
+            "2:2 - ptr 1 0 -> 255",
+            "2:3 < ptr 1 255 -> 1",
+            "2:4 - ptr 0 1 -> 0",
+            "2:5 ] ptr 0 0 -> 0",
+        ];
+
+        assert_eq!(trace.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_pointer_out_of_bounds() {
+        let program = Program::from_lexer(Lexer::from("+\n <")).unwrap();
+        let mut interpreter = Interpreter::new(program, io::empty(), io::sink());
+
+        match interpreter.run() {
+            Err(InterpreterError::PointerOutOfBounds(span)) => assert_eq!(span, Span::at(2, 2)),
+            other => panic!("expected an out of bounds error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "the tape needs at least one cell")]
+    fn test_empty_tape() {
+        Interpreter::new(Program::default(), io::empty(), io::sink()).tape_size(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "the interpreter's tape has at most")]
+    fn test_huge_tape() {
+        Interpreter::new(Program::default(), io::empty(), io::sink()).tape_size(MAX_TAPE_SIZE + 1);
+    }
+}
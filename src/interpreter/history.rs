@@ -0,0 +1,271 @@
+use std::io::{Read, Write};
+
+use super::{Interpreter, InterpreterError};
+
+/// Steps between two snapshots, travelling back replays at most this many ops
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 100_000;
+
+/// A cell write recorded in the undo log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellWrite {
+    /// Number of ops executed before the one that wrote the cell
+    pub step: u64,
+    /// Index of the op that wrote the cell
+    pub pc: usize,
+    pub cell: usize,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// Full machine state at a multiple of the snapshot interval
+struct Snapshot {
+    tape: Vec<u32>,
+    pointer: usize,
+    pc: usize,
+    /// How many entries of the input log had been consumed
+    inputs_read: usize,
+}
+
+/// Everything needed to move to any earlier step and back again. The
+/// program is deterministic given its input, so going back restores the
+/// closest snapshot and replays from there with recorded input and
+/// without producing output again
+pub(super) struct History {
+    interval: u64,
+    /// `snapshots[i]` is the state after `i * interval` steps
+    snapshots: Vec<Snapshot>,
+    /// Every cell change, ordered by step
+    writes: Vec<CellWrite>,
+    /// Every byte `,` consumed, `None` for EOF
+    inputs: Vec<Option<u8>>,
+    inputs_read: usize,
+    /// Number of steps that were executed for real. Below that the
+    /// interpreter only replays
+    recorded: u64,
+}
+
+impl History {
+    pub(super) fn new(interval: u64) -> Self {
+        History {
+            interval: interval.max(1),
+            snapshots: Vec::new(),
+            writes: Vec::new(),
+            inputs: Vec::new(),
+            inputs_read: 0,
+            recorded: 0,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    /// Records execution from the first step on so it can be stepped
+    /// backwards, with a snapshot every `snapshot_interval` steps
+    pub fn recording(mut self, snapshot_interval: u64) -> Self {
+        self.history = Some(History::new(snapshot_interval));
+        self
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Number of steps that can be travelled to
+    pub fn recorded_steps(&self) -> u64 {
+        self.history.as_ref().map_or(0, |history| history.recorded)
+    }
+
+    pub fn snapshot_interval(&self) -> u64 {
+        self.history.as_ref().map_or(0, |history| history.interval)
+    }
+
+    /// Moves to the state after `target` steps, at most to the last
+    /// recorded one
+    pub fn seek(&mut self, target: u64) -> Result<(), InterpreterError> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or(InterpreterError::NotRecording)?;
+        let target = target.min(history.recorded);
+
+        if target < self.steps {
+            let index = ((target / history.interval) as usize).min(history.snapshots.len() - 1);
+            let snapshot = &history.snapshots[index];
+
+            self.tape.copy_from_slice(&snapshot.tape);
+            self.pointer = snapshot.pointer;
+            self.pc = snapshot.pc;
+            self.steps = index as u64 * history.interval;
+
+            let inputs_read = snapshot.inputs_read;
+            self.history.as_mut().unwrap().inputs_read = inputs_read;
+        }
+
+        while self.steps < target {
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    /// The last change of `cell` by an op that ran before step `before`
+    pub fn last_change(&self, cell: usize, before: u64) -> Option<CellWrite> {
+        let writes = &self.history.as_ref()?.writes;
+        let end = writes.partition_point(|write| write.step < before);
+
+        writes[..end]
+            .iter()
+            .rev()
+            .find(|write| write.cell == cell)
+            .copied()
+    }
+
+    /// Whether the current step was already executed once
+    pub(super) fn is_replaying(&self) -> bool {
+        self.history
+            .as_ref()
+            .map_or(false, |history| self.steps < history.recorded)
+    }
+
+    /// Takes a snapshot when a new multiple of the interval is reached
+    pub(super) fn record_snapshot(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            let due = history.snapshots.len() as u64 * history.interval;
+
+            if self.steps == due && self.steps == history.recorded {
+                history.snapshots.push(Snapshot {
+                    tape: self.tape.clone(),
+                    pointer: self.pointer,
+                    pc: self.pc,
+                    inputs_read: history.inputs_read,
+                });
+            }
+        }
+    }
+
+    pub(super) fn record_write(&mut self, cell: usize, old: u32, new: u32) {
+        let replaying = self.is_replaying();
+
+        if let Some(history) = self.history.as_mut() {
+            if !replaying && old != new {
+                history.writes.push(CellWrite {
+                    step: self.steps,
+                    pc: self.pc,
+                    cell,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+
+    /// The byte `,` read the first time this step ran, if it is replayed
+    pub(super) fn replay_input(&mut self) -> Option<Option<u8>> {
+        if !self.is_replaying() {
+            return None;
+        }
+
+        let history = self.history.as_mut()?;
+        let input = history.inputs[history.inputs_read];
+        history.inputs_read += 1;
+
+        Some(input)
+    }
+
+    pub(super) fn record_input(&mut self, input: Option<u8>) {
+        if let Some(history) = self.history.as_mut() {
+            history.inputs.push(input);
+            history.inputs_read += 1;
+        }
+    }
+
+    /// Called after every step, extends the recording when new ground was covered
+    pub(super) fn record_step(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.recorded = history.recorded.max(self.steps);
+        }
+    }
+
+    /// Drops the recording but keeps recording from now on
+    pub(super) fn clear_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            *history = History::new(history.interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Program;
+    use crate::lexer::Lexer;
+
+    fn interpreter<'a>(
+        source: &str,
+        input: &'a [u8],
+        output: &'a mut Vec<u8>,
+    ) -> Interpreter<&'a [u8], &'a mut Vec<u8>> {
+        let program = Program::from_lexer(Lexer::from(source)).unwrap();
+
+        // A tiny interval so seeking crosses several snapshots
+        Interpreter::new(program, input, output).recording(4)
+    }
+
+    #[test]
+    fn test_seek_restores_state() {
+        let mut output = Vec::new();
+        let mut interpreter = interpreter("+++[>++<-]>.", b"", &mut output);
+
+        let mut states = Vec::new();
+        loop {
+            states.push((
+                interpreter.pc(),
+                interpreter.pointer(),
+                interpreter.tape()[..2].to_vec(),
+            ));
+            if interpreter.is_finished() {
+                break;
+            }
+            interpreter.step().unwrap();
+        }
+
+        for target in (0..states.len() as u64).rev().chain(0..states.len() as u64) {
+            interpreter.seek(target).unwrap();
+
+            let (pc, pointer, cells) = &states[target as usize];
+            assert_eq!(interpreter.pc(), *pc);
+            assert_eq!(interpreter.pointer(), *pointer);
+            assert_eq!(interpreter.tape()[..2], cells[..]);
+        }
+
+        drop(interpreter);
+        // Replaying does not print again
+        assert_eq!(output, [6]);
+    }
+
+    #[test]
+    fn test_replay_uses_recorded_input() {
+        let mut output = Vec::new();
+        let mut interpreter = interpreter(",>,>,", b"ab", &mut output);
+
+        interpreter.run().unwrap();
+        interpreter.seek(0).unwrap();
+        interpreter.run().unwrap();
+
+        assert_eq!(interpreter.tape()[..3], [97, 98, 0]);
+    }
+
+    #[test]
+    fn test_last_change() {
+        let mut output = Vec::new();
+        let mut interpreter = interpreter("++>+<-", b"", &mut output);
+        interpreter.run().unwrap();
+
+        let write = interpreter.last_change(0, interpreter.steps()).unwrap();
+        assert_eq!((write.step, write.pc, write.old, write.new), (5, 5, 2, 1));
+
+        let write = interpreter.last_change(0, 5).unwrap();
+        assert_eq!((write.step, write.old, write.new), (1, 1, 2));
+
+        assert_eq!(interpreter.last_change(2, interpreter.steps()), None);
+    }
+}
@@ -1,5 +1,5 @@
-use std::error::Error;
-use std::{fmt, fs, fs::File, io::Write, path::Path};
+use std::convert::TryFrom;
+use std::{fmt, fs::File, io, io::Read, path::Path};
 
 #[derive(Debug)]
 pub enum LexerError {
@@ -16,6 +16,54 @@ impl fmt::Display for LexerError {
     }
 }
 
+/// Line and column of a character in the source, both starting at 1
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Source range covered by a token or node, both ends inclusive
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    /// Span of a single character
+    pub fn at(line: u32, column: u32) -> Self {
+        let position = Position { line, column };
+
+        Span {
+            start: position,
+            end: position,
+        }
+    }
+
+    /// Smallest span that covers both `self` and `other`
+    pub fn to(self, other: Span) -> Self {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}:{}", self.start.line, self.start.column)
+        } else {
+            write!(
+                f,
+                "{}:{}-{}:{}",
+                self.start.line, self.start.column, self.end.line, self.end.column
+            )
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Token {
     PlusToken,
@@ -25,36 +73,219 @@ pub enum Token {
     GreaterThanToken,
     SmallerThanToken,
     DotToken,
+    CommaToken,
+    /// `#`, only produced in debug mode
+    BreakpointToken,
 }
 
+/// Keeps the tokens of a whole program, e.g. for the linter. Compiling
+/// doesn't need them all at once and streams them into the parser with
+/// [`tokenize`] instead
 pub struct Lexer {
     pub tokens: Vec<Token>,
+    /// Byte offset of each token in the source, `offsets[i]` belongs to
+    /// `tokens[i]`. Lines and columns are worked out when they are needed
+    pub offsets: Vec<u32>,
+    scanner: Scanner,
+}
+
+/// How much of a reader is lexed at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Turns source into tokens piece by piece, keeping track of where in the
+/// source it is
+struct Scanner {
+    /// Byte offset of the next byte
+    offset: u32,
+    /// Byte offsets at which lines start, the first one at 0
+    line_starts: Vec<u32>,
+    /// Whether `#` is lexed as a breakpoint instead of being a comment
+    breakpoints: bool,
+}
+
+impl Scanner {
+    fn new(breakpoints: bool) -> Self {
+        Scanner {
+            offset: 0,
+            line_starts: vec![0],
+            breakpoints,
+        }
+    }
+
+    /// Hands every token in `bytes` to `emit` together with its offset and
+    /// position, which is cheap to know while scanning
+    fn scan<E: From<io::Error>>(
+        &mut self,
+        bytes: &[u8],
+        mut emit: impl FnMut(Token, u32, Position) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let end = u32::try_from(bytes.len())
+            .ok()
+            .and_then(|len| self.offset.checked_add(len))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "sources over 4 GiB are not supported",
+                )
+            })?;
+        let mut line = self.line_starts.len() as u32;
+        let mut line_start = *self.line_starts.last().unwrap();
+
+        for (&byte, offset) in bytes.iter().zip(self.offset..end) {
+            let token = match byte {
+                b'+' => Some(Token::PlusToken),
+                b'-' => Some(Token::MinusToken),
+                b'.' => Some(Token::DotToken),
+                b',' => Some(Token::CommaToken),
+                b'[' => Some(Token::LeftBracketToken),
+                b']' => Some(Token::RightBracketToken),
+                b'<' => Some(Token::SmallerThanToken),
+                b'>' => Some(Token::GreaterThanToken),
+                b'#' if self.breakpoints => Some(Token::BreakpointToken),
+                _ => None,
+            };
+
+            if let Some(token) = token {
+                let column = offset - line_start + 1;
+                emit(token, offset, Position { line, column })?;
+            }
+
+            if byte == b'\n' {
+                line += 1;
+                line_start = offset + 1;
+                self.line_starts.push(line_start);
+            }
+        }
+
+        self.offset = end;
+        Ok(())
+    }
+
+    /// Scans everything `reader` produces in chunks of [`CHUNK_SIZE`]
+    fn scan_reader<R: Read, E: From<io::Error>>(
+        &mut self,
+        mut reader: R,
+        mut emit: impl FnMut(Token, u32, Position) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(len) => self.scan(&chunk[..len], &mut emit)?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn position(&self, offset: u32) -> Position {
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+
+        Position {
+            line: line as u32,
+            column: offset - self.line_starts[line - 1] + 1,
+        }
+    }
+}
+
+/// Lexes everything `reader` produces and hands each token to `emit` as
+/// soon as it is found, without keeping any of them
+pub fn tokenize<R: Read, E: From<io::Error>>(
+    reader: R,
+    mut emit: impl FnMut(Token, Span) -> Result<(), E>,
+) -> Result<(), E> {
+    Scanner::new(false).scan_reader(reader, |token, _, position| {
+        emit(token, Span::at(position.line, position.column))
+    })
+}
+
+/// Like [`tokenize`], for source that is already in memory
+pub fn tokenize_bytes<E: From<io::Error>>(
+    source: &[u8],
+    mut emit: impl FnMut(Token, Span) -> Result<(), E>,
+) -> Result<(), E> {
+    Scanner::new(false).scan(source, |token, _, position| {
+        emit(token, Span::at(position.line, position.column))
+    })
 }
 
 impl Lexer {
     pub fn new<T: AsRef<Path>>(filename: T) -> Result<Lexer, std::io::Error> {
-        let chars_vec = fs::read(filename)?
-            .iter()
-            .filter(|x| **x != 10 as u8)
-            .map(|x| *x)
-            .collect::<Vec<_>>();
-
-        let mut tokens: Vec<Token> = Vec::new();
-
-        for ch in chars_vec {
-            match ch as char {
-                '+' => tokens.push(Token::PlusToken),
-                '-' => tokens.push(Token::MinusToken),
-                '.' => tokens.push(Token::DotToken),
-                '[' => tokens.push(Token::LeftBracketToken),
-                ']' => tokens.push(Token::RightBracketToken),
-                '<' => tokens.push(Token::SmallerThanToken),
-                '>' => tokens.push(Token::GreaterThanToken),
-                _ => {}
-            }
+        Self::from_reader(File::open(filename)?)
+    }
+
+    /// Lexes everything `reader` produces until EOF, e.g. stdin. The input
+    /// is consumed in fixed-size chunks and never held in memory as a whole.
+    /// Fails on sources over 4 GiB
+    pub fn from_reader<R: Read>(reader: R) -> Result<Lexer, std::io::Error> {
+        Self::empty(false).read_all(reader)
+    }
+
+    /// Like `from_reader`, but also lexes the `#` breakpoint character
+    /// that only the debugger understands
+    pub fn from_reader_debug<R: Read>(reader: R) -> Result<Lexer, std::io::Error> {
+        Self::empty(true).read_all(reader)
+    }
+
+    /// Lexes source that is already in memory.
+    ///
+    /// # Panics
+    ///
+    /// If the source is over 4 GiB
+    pub fn from_bytes(chars_vec: &[u8]) -> Lexer {
+        Self::empty(false).read_bytes(chars_vec)
+    }
+
+    /// Like `from_bytes`, but also lexes the `#` breakpoint character
+    pub fn from_bytes_debug(chars_vec: &[u8]) -> Lexer {
+        Self::empty(true).read_bytes(chars_vec)
+    }
+
+    fn read_all<R: Read>(mut self, reader: R) -> Result<Lexer, std::io::Error> {
+        let (tokens, offsets) = (&mut self.tokens, &mut self.offsets);
+
+        self.scanner
+            .scan_reader(reader, |token, offset, _| -> io::Result<()> {
+                tokens.push(token);
+                offsets.push(offset);
+                Ok(())
+            })?;
+
+        Ok(self)
+    }
+
+    fn read_bytes(mut self, chars_vec: &[u8]) -> Lexer {
+        let (tokens, offsets) = (&mut self.tokens, &mut self.offsets);
+
+        self.scanner
+            .scan(chars_vec, |token, offset, _| -> io::Result<()> {
+                tokens.push(token);
+                offsets.push(offset);
+                Ok(())
+            })
+            .expect("sources over 4 GiB are not supported");
+
+        self
+    }
+
+    fn empty(breakpoints: bool) -> Lexer {
+        Lexer {
+            tokens: Vec::new(),
+            offsets: Vec::new(),
+            scanner: Scanner::new(breakpoints),
         }
+    }
 
-        Ok(Lexer { tokens })
+    /// Where the token at `index` is in the source
+    pub fn span(&self, index: usize) -> Span {
+        let Position { line, column } = self.scanner.position(self.offsets[index]);
+        Span::at(line, column)
+    }
+
+    /// Where each token is, in order
+    pub fn spans(&self) -> impl Iterator<Item = Span> + '_ {
+        (0..self.tokens.len()).map(move |index| self.span(index))
     }
 
     pub fn check_loops(&self) -> Result<(), LexerError> {
@@ -80,21 +311,32 @@ impl Lexer {
     }
 
     // This is used for tests only, so not care about cloning
+    #[allow(dead_code)]
     fn get_tokens(&self) -> Vec<Token> {
         self.tokens.clone()
     }
 }
 
+impl From<&str> for Lexer {
+    fn from(source: &str) -> Self {
+        Lexer::from_bytes(source.as_bytes())
+    }
+}
+
+impl From<&[u8]> for Lexer {
+    fn from(source: &[u8]) -> Self {
+        Lexer::from_bytes(source)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::fs;
+    use std::io::{Cursor, Write};
 
     use super::*;
 
     static FOO_FILE: &str = "foo_test.bf";
-    static LEX_TOKENS_FILE: &str = "lex_test.bf";
-    static VALID_BRACKETS_FILE: &str = "brackets_valid_test.bf";
-    static INVALID_BRACKETS_FILE: &str = "brackets_invalid_test.bf";
 
     #[test]
     fn test_valid_filename() {
@@ -115,10 +357,7 @@ mod tests {
 
     #[test]
     fn test_lex_tokens() {
-        let mut file = File::create(LEX_TOKENS_FILE).unwrap();
-        file.write_all(b"+-[+]><.").unwrap();
-
-        let lx = Lexer::new(LEX_TOKENS_FILE).unwrap();
+        let lx = Lexer::from("+-[+]><.,");
         let test_tokens: Vec<Token> = vec![
             Token::PlusToken,
             Token::MinusToken,
@@ -128,34 +367,104 @@ mod tests {
             Token::GreaterThanToken,
             Token::SmallerThanToken,
             Token::DotToken,
+            Token::CommaToken,
         ];
 
         assert_eq!(lx.tokens, test_tokens);
+    }
+
+    #[test]
+    fn test_lex_sources_agree() {
+        let source = "++ [ > . < - ] comment ,";
 
-        fs::remove_file(LEX_TOKENS_FILE).unwrap();
+        let from_str = Lexer::from(source);
+        let from_bytes = Lexer::from(source.as_bytes());
+        let from_reader = Lexer::from_reader(Cursor::new(source)).unwrap();
+
+        assert_eq!(from_str.tokens, from_bytes.tokens);
+        assert_eq!(from_str.tokens, from_reader.tokens);
+        assert_eq!(from_str.offsets, from_reader.offsets);
     }
 
     #[test]
-    fn test_valid_brackets() {
-        let mut file = File::create(VALID_BRACKETS_FILE).unwrap();
-        file.write_all(b"[[]+[]][]").unwrap();
+    fn test_lex_chunk_boundaries() {
+        // A reader that hands out one byte at a time still has to produce
+        // the same positions as lexing everything at once
+        struct ByteReader<'a>(&'a [u8]);
+
+        impl Read for ByteReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.split_first() {
+                    Some((&first, rest)) => {
+                        buf[0] = first;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    None => Ok(0),
+                }
+            }
+        }
 
-        let lx = Lexer::new(VALID_BRACKETS_FILE).unwrap();
+        let source = "+\n+ [\n\n  -]";
+
+        let whole = Lexer::from(source);
+        let chunked = Lexer::from_reader(ByteReader(source.as_bytes())).unwrap();
+
+        assert_eq!(whole.tokens, chunked.tokens);
+        assert!(whole.spans().eq(chunked.spans()));
+
+        // Streaming hands out the same tokens at the same spans
+        let mut streamed = Vec::new();
+        tokenize(
+            ByteReader(source.as_bytes()),
+            |token, span| -> io::Result<()> {
+                streamed.push((token, span));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(streamed
+            .into_iter()
+            .eq(whole.tokens.iter().copied().zip(whole.spans())));
+    }
 
-        assert!(lx.check_loops().is_ok());
+    #[test]
+    fn test_lex_spans() {
+        let lx = Lexer::from("+ comment -\n  [.]");
+        let test_spans: Vec<Span> = vec![
+            Span::at(1, 1),
+            Span::at(1, 11),
+            Span::at(2, 3),
+            Span::at(2, 4),
+            Span::at(2, 5),
+        ];
 
-        fs::remove_file(VALID_BRACKETS_FILE).unwrap();
+        assert_eq!(lx.offsets, vec![0, 10, 14, 15, 16]);
+        assert_eq!(lx.spans().collect::<Vec<_>>(), test_spans);
     }
 
     #[test]
-    fn test_invalid_brackets() {
-        let mut file = File::create(INVALID_BRACKETS_FILE).unwrap();
-        file.write_all(b"[[]").unwrap();
+    fn test_lex_breakpoints() {
+        let source = "+# comment";
+
+        assert_eq!(Lexer::from(source).tokens, vec![Token::PlusToken]);
+        assert_eq!(
+            Lexer::from_bytes_debug(source.as_bytes()).tokens,
+            vec![Token::PlusToken, Token::BreakpointToken]
+        );
+    }
 
-        let lx = Lexer::new(INVALID_BRACKETS_FILE).unwrap();
+    #[test]
+    fn test_valid_brackets() {
+        let lx = Lexer::from("[[]+[]][]");
 
-        assert!(lx.check_loops().is_err());
+        assert!(lx.check_loops().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_brackets() {
+        let lx = Lexer::from("[[]");
 
-        fs::remove_file(INVALID_BRACKETS_FILE).unwrap();
+        assert!(lx.check_loops().is_err());
     }
 }
@@ -0,0 +1,26 @@
+//! Brainwash is a Brainfuck compiler built on LLVM. [`Compiler`] drives the
+//! whole pipeline; the individual stages are available as modules for
+//! tools that only need part of it.
+
+pub mod analysis;
+pub mod binary;
+pub mod codegen;
+pub mod compiler;
+pub mod coverage;
+pub mod debugger;
+pub mod error;
+pub mod fuzz;
+pub mod interpreter;
+pub mod lexer;
+pub mod lint;
+pub mod optimizer;
+pub mod parser;
+pub mod prefix;
+pub mod profile;
+pub mod repl;
+pub mod testing;
+pub mod trace;
+pub mod verify;
+
+pub use crate::compiler::{Artifact, Compiler};
+pub use crate::error::CompileError;
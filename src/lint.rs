@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::analysis::Analyzer;
+use crate::codegen::{CellSize, DEFAULT_TAPE_SIZE};
+use crate::lexer::{Lexer, Span, Token};
+use crate::parser::{Node, Parser, ParserError};
+
+/// Every lint, in the order of their codes
+pub const LINTS: [Lint; 5] = [
+    Lint::Cancel,
+    Lint::EmptyLoop,
+    Lint::AfterInfiniteLoop,
+    Lint::UnreachableLoop,
+    Lint::UnsetOutput,
+];
+
+/// A kind of likely mistake [`Linter`] looks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Lint {
+    /// `+-`, `-+`, `><` or `<>`, which undo themselves
+    Cancel,
+    /// `[]`, which never ends if it runs at all
+    EmptyLoop,
+    /// Code after a loop that never ends once it runs
+    AfterInfiniteLoop,
+    /// A loop that is only ever reached on a zero cell
+    UnreachableLoop,
+    /// `.` on a cell nothing has written to, which always prints zero
+    UnsetOutput,
+}
+
+impl Lint {
+    /// Short stable name like `W001`
+    pub fn code(self) -> &'static str {
+        match self {
+            Lint::Cancel => "W001",
+            Lint::EmptyLoop => "W002",
+            Lint::AfterInfiniteLoop => "W003",
+            Lint::UnreachableLoop => "W004",
+            Lint::UnsetOutput => "W005",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::Cancel => "cancel",
+            Lint::EmptyLoop => "empty-loop",
+            Lint::AfterInfiniteLoop => "after-infinite-loop",
+            Lint::UnreachableLoop => "unreachable-loop",
+            Lint::UnsetOutput => "unset-output",
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Takes either the code or the name of a lint
+impl FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LINTS
+            .iter()
+            .copied()
+            .find(|lint| lint.code().eq_ignore_ascii_case(s) || lint.name() == s)
+            .ok_or_else(|| format!("unknown lint: {}", s))
+    }
+}
+
+/// What to do about a lint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Don't report it
+    Allow,
+    Warn,
+    /// Report it as an error, `bw lint` fails if there is any
+    Deny,
+}
+
+/// One likely mistake in a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub level: Level,
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.level {
+            Level::Deny => "error",
+            _ => "warning",
+        };
+
+        write!(
+            f,
+            "{}[{} {}] at {}: {}",
+            level,
+            self.lint.code(),
+            self.lint.name(),
+            self.span,
+            self.message
+        )
+    }
+}
+
+/// Looks for likely mistakes in a program. Some lints only need the tokens,
+/// the others use what [`Analyzer`] proves about the program and are left
+/// out when it is too big to analyze
+#[derive(Debug, Clone)]
+pub struct Linter {
+    levels: HashMap<Lint, Level>,
+    tape_size: u64,
+    cell_size: CellSize,
+}
+
+impl Linter {
+    /// Warns about every lint
+    pub fn new() -> Self {
+        Linter {
+            levels: HashMap::new(),
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_size: CellSize::default(),
+        }
+    }
+
+    pub fn level(mut self, lint: Lint, level: Level) -> Self {
+        self.levels.insert(lint, level);
+        self
+    }
+
+    pub fn tape_size(mut self, tape_size: u64) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> Self {
+        self.cell_size = cell_size;
+        self
+    }
+
+    /// The warnings for the lexed program that aren't allowed, in source
+    /// order
+    pub fn check(&self, lexer: &Lexer) -> Result<Vec<Warning>, ParserError> {
+        let root = Parser::parse(lexer)?;
+
+        let mut found = cancelling(lexer);
+        found.extend(self.loops_and_outputs(&root));
+
+        let mut warnings: Vec<Warning> = found
+            .into_iter()
+            .filter_map(|(lint, span, message)| {
+                let level = self.levels.get(&lint).copied().unwrap_or(Level::Warn);
+                if level == Level::Allow {
+                    return None;
+                }
+                Some(Warning {
+                    lint,
+                    level,
+                    span,
+                    message,
+                })
+            })
+            .collect();
+
+        warnings.sort_by_key(|warning| (warning.span.start, warning.lint));
+        Ok(warnings)
+    }
+
+    fn loops_and_outputs(&self, root: &Node) -> Vec<(Lint, Span, String)> {
+        let analysis = Analyzer::new()
+            .tape_size(self.tape_size)
+            .cell_size(self.cell_size)
+            .run(root);
+        let (dead, infinite, outputs) = match &analysis {
+            Some(analysis) => (
+                &analysis.dead_loops[..],
+                &analysis.infinite_loops[..],
+                &analysis.unset_outputs[..],
+            ),
+            None => (&[][..], &[][..], &[][..]),
+        };
+
+        let mut found: Vec<(Lint, Span, String)> = outputs
+            .iter()
+            .map(|&span| {
+                let message = String::from("prints a cell nothing has written to");
+                (Lint::UnsetOutput, span, message)
+            })
+            .collect();
+
+        let mut bodies: Vec<&[Node]> = match root {
+            Node::Expr(body) => vec![body],
+            _ => Vec::new(),
+        };
+        // A loop at the very start is the usual way to write a comment,
+        // which is why it never runs
+        let comment = bodies.first().and_then(|top| top.first()?.span());
+
+        while let Some(body) = bodies.pop() {
+            for (index, node) in body.iter().enumerate() {
+                let (inner, open) = match node {
+                    Node::LoopExpr(inner, open) => (inner, *open),
+                    _ => continue,
+                };
+                // The parser puts the `]` right after its loop
+                let whole = body[index + 1].span().map_or(open, |close| open.to(close));
+
+                if dead.contains(&open) {
+                    if comment != Some(open) {
+                        let message =
+                            String::from("the cell is always zero here, the loop never runs");
+                        found.push((Lint::UnreachableLoop, whole, message));
+                    }
+                    continue;
+                }
+                if inner.is_empty() {
+                    let message = String::from("`[]` never ends once the cell isn't zero");
+                    found.push((Lint::EmptyLoop, whole, message));
+                }
+                if infinite.contains(&open) {
+                    let rest = body[index + 2..].iter().filter_map(Node::span);
+                    if let Some(after) = rest.reduce(Span::to) {
+                        let message = format!("the loop at {} never ends, this never runs", whole);
+                        found.push((Lint::AfterInfiniteLoop, after, message));
+                    }
+                }
+
+                bodies.push(inner);
+            }
+        }
+
+        found
+    }
+}
+
+impl Default for Linter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pairs of neighbouring tokens that undo each other. A pair is reported
+/// once, so `+-+-` is two of them
+fn cancelling(lexer: &Lexer) -> Vec<(Lint, Span, String)> {
+    let tokens = &lexer.tokens;
+    let symbol = |token: Token| match token {
+        Token::PlusToken => Some(('+', 0)),
+        Token::MinusToken => Some(('-', 0)),
+        Token::GreaterThanToken => Some(('>', 1)),
+        Token::SmallerThanToken => Some(('<', 1)),
+        _ => None,
+    };
+
+    let mut found = Vec::new();
+    let mut index = 0;
+
+    while index + 1 < tokens.len() {
+        match (symbol(tokens[index]), symbol(tokens[index + 1])) {
+            (Some((first, kind)), Some((second, other))) if kind == other && first != second => {
+                let message = format!("`{}{}` cancels itself out", first, second);
+                let span = lexer.span(index).to(lexer.span(index + 1));
+                found.push((Lint::Cancel, span, message));
+                index += 2;
+            }
+            _ => index += 1,
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lints(source: &str) -> Vec<(Lint, String)> {
+        Linter::new()
+            .check(&Lexer::from(source))
+            .unwrap()
+            .into_iter()
+            .map(|warning| (warning.lint, warning.span.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_cancel() {
+        assert_eq!(
+            lints("+-+-,<>"),
+            vec![
+                (Lint::Cancel, String::from("1:1-1:2")),
+                (Lint::Cancel, String::from("1:3-1:4")),
+                (Lint::Cancel, String::from("1:6-1:7")),
+            ]
+        );
+        // Comments in between don't matter, other ops do
+        assert_eq!(lints("+ add one\n-").len(), 1);
+        assert!(lints("+.-,>+<").is_empty());
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            lints(",[]+[]."),
+            vec![
+                (Lint::EmptyLoop, String::from("1:2-1:3")),
+                (Lint::EmptyLoop, String::from("1:5-1:6")),
+                (Lint::AfterInfiniteLoop, String::from("1:7")),
+            ]
+        );
+        assert_eq!(
+            lints("[comment]>,[>+[-]<]>[more]"),
+            vec![(Lint::UnreachableLoop, String::from("1:21-1:26"))]
+        );
+        // The rest of the outer loop never runs, what follows it does
+        assert_eq!(
+            lints(",[[-]+[]>.]>."),
+            vec![
+                (Lint::EmptyLoop, String::from("1:7-1:8")),
+                (Lint::AfterInfiniteLoop, String::from("1:9-1:10")),
+                (Lint::UnsetOutput, String::from("1:13")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unset_output() {
+        assert_eq!(
+            lints("+>.<.,>>."),
+            vec![
+                (Lint::UnsetOutput, String::from("1:3")),
+                (Lint::UnsetOutput, String::from("1:9")),
+            ]
+        );
+        assert!(lints("+[-].").is_empty());
+    }
+
+    #[test]
+    fn test_levels() {
+        let linter = Linter::new()
+            .level("cancel".parse().unwrap(), Level::Allow)
+            .level("w002".parse().unwrap(), Level::Deny);
+        let warnings = linter.check(&Lexer::from("+-,[]")).unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].to_string(),
+            "error[W002 empty-loop] at 1:4-1:5: `[]` never ends once the cell isn't zero"
+        );
+        assert!("no-such-lint".parse::<Lint>().is_err());
+    }
+}
@@ -1,34 +1,65 @@
-#![allow(unused_imports)]
-#![allow(dead_code)]
-
-mod binary;
-mod codegen;
-mod lexer;
-mod optimizer;
-mod parser;
-
-use crate::binary::*;
-use crate::codegen::*;
-use crate::lexer::*;
-use crate::optimizer::*;
-use crate::parser::*;
-
-use clap::{App, AppSettings, Arg, SubCommand};
-use inkwell::context::Context;
-use inkwell::passes::PassManager;
-use inkwell::values::FunctionValue;
-use std::error::Error;
-
-fn main() -> Result<(), Box<dyn Error>> {
+use brainwash::codegen::{self, CellSize, OutputMode};
+use brainwash::coverage;
+use brainwash::debugger::Debugger;
+use brainwash::fuzz::{self, Backend, FuzzProgram, InterpreterBackend, Outcome, Rng};
+use brainwash::interpreter::{self, Interpreter, Program, DEFAULT_SNAPSHOT_INTERVAL};
+use brainwash::lexer::Lexer;
+use brainwash::lint::{Level, Lint, Linter, LINTS};
+use brainwash::optimizer::{Pipeline, PASSES};
+use brainwash::prefix;
+use brainwash::profile::{Profile, Report};
+use brainwash::repl::Repl;
+use brainwash::testing::{self, TestCase};
+use brainwash::trace::{self, TraceFormat, TraceOptions, TraceRecord};
+use brainwash::verify;
+use brainwash::{CompileError, Compiler};
+
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where `--profile-loops` binaries write their profile unless told otherwise
+const DEFAULT_PROFILE: &str = "bw.profile";
+
+/// Where the program to compile comes from
+enum Input<'a> {
+    File(&'a str),
+    Stdin,
+    Inline(&'a str),
+}
+
+fn main() {
+    let pass_names: Vec<&str> = PASSES.iter().copied().chain(Some("none")).collect();
+    let lint_names: String = LINTS
+        .iter()
+        .map(|lint| format!("\n    {}  {}", lint.code(), lint.name()))
+        .collect();
+    let lint_help = format!("LINTS:{}", lint_names);
+
     let matches = App::new("Brainwash")
-        .setting(AppSettings::DisableVersion)
+        .global_setting(AppSettings::DisableVersion)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .author("Emil Jaszczuk <emj1054@gmail.com>")
         .arg(
             Arg::with_name("INPUT")
-                .help("Sets the input file to compile")
-                .required(true)
+                .help("Sets the input file to compile, `-` reads the program from stdin")
+                .required_unless("eval")
                 .index(1),
         )
+        .arg(
+            Arg::with_name("eval")
+                .short("e")
+                .long("eval")
+                .value_name("CODE")
+                .help("Compiles the given code instead of reading a file")
+                .conflicts_with("INPUT")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("profiler")
                 .short("p")
@@ -42,41 +73,1096 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .long("output")
                 .value_name("FILE")
                 .help("Sets the output file")
-                .default_value("bfo")
+                .default_value("main")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("opt-level")
+                .short("O")
+                .long("opt-level")
+                .value_name("LEVEL")
+                .help("Sets the optimization level, 0 skips `opt` entirely")
+                .possible_values(&["0", "1", "2", "3"])
+                .default_value("2")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short("g")
+                .long("debug")
+                .help("Emits DWARF debug info that maps the binary back to the source"),
+        )
+        .arg(
+            Arg::with_name("freestanding")
+                .long("freestanding")
+                .help("Emits a static binary that uses raw Linux syscalls instead of libc"),
+        )
+        .arg(
+            Arg::with_name("unbuffered")
+                .long("unbuffered")
+                .help("Writes every output byte immediately, for interactive programs"),
+        )
+        .arg(
+            Arg::with_name("bounds-check")
+                .long("bounds-check")
+                .help("Makes the binary exit with an error when the pointer leaves the tape"),
+        )
+        .arg(
+            Arg::with_name("profile-loops")
+                .long("profile-loops")
+                .help("Counts how often each op runs and writes the counts to a profile at exit"),
+        )
+        .arg(
+            Arg::with_name("profile-file")
+                .long("profile-file")
+                .value_name("FILE")
+                .help("Sets where the binary writes its profile [default: bw.profile]")
+                .requires("profile-loops")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("passes")
+                .long("passes")
+                .value_name("PASSES")
+                .help("Sets the Brainfuck-level optimization passes to run, `none` runs none [default: dead,fold,clear,mul,scan]")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&pass_names),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Prints what the optimization passes did"),
+        )
+        .arg(Arg::with_name("verify-opt").long("verify-opt").help(
+            "Runs the program before and after each optimization pass and fails if they differ",
+        ))
+        .arg(
+            Arg::with_name("verify-steps")
+                .long("verify-steps")
+                .value_name("STEPS")
+                .help("Sets how many steps each run may take when verifying [default: 1000000]")
+                .requires("verify-opt")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("prefix-steps")
+                .long("prefix-steps")
+                .value_name("STEPS")
+                .help("Sets how many steps may run at compile time, 0 runs none [default: 1000000]")
+                .takes_value(true),
+        )
+        .args(&tape_args(codegen::MAX_TAPE_SIZE))
+        .args(&trace_args())
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Interprets a program without compiling it")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the program to run")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("FILE")
+                        .help("Feeds the program from FILE instead of stdin")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("coverage")
+                        .long("coverage")
+                        .value_name("FILE")
+                        .help("Writes which lines and loops ran to FILE in the lcov format")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jit")
+                        .long("jit")
+                        .help("Compiles the program and runs it in LLVM's JIT instead")
+                        .conflicts_with_all(&["input", "coverage"]),
+                )
+                .args(&tape_args(codegen::MAX_TAPE_SIZE))
+                .args(&trace_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Runs a program in the interactive source-level debugger")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the program to debug, `#` in it acts as a breakpoint")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("FILE")
+                        .help("Feeds the program from FILE instead of stdin")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .long("record")
+                        .help("Records execution so the program can be stepped backwards"),
+                )
+                .args(&tape_args(interpreter::MAX_TAPE_SIZE)),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Runs Brainfuck line by line on a tape that persists between lines")
+                .args(&tape_args(interpreter::MAX_TAPE_SIZE)),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Shows the hottest loops and lines of a program from its profile")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the program the profile was recorded for")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PROFILE")
+                        .help("Sets the profile to read")
+                        .default_value(DEFAULT_PROFILE)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .value_name("N")
+                        .help("Sets how many loops and lines to show")
+                        .default_value("10")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("folded")
+                        .long("folded")
+                        .help("Prints folded stacks for flamegraph tools instead"),
+                )
+                .arg(
+                    Arg::with_name("lcov")
+                        .long("lcov")
+                        .help("Prints the coverage in the lcov format instead")
+                        .conflicts_with("folded"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Runs programs and compares their output with the expected one")
+                .arg(
+                    Arg::with_name("PATHS")
+                        .help("Sets the programs or directories of programs to test")
+                        .default_value(".")
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("compile")
+                        .long("compile")
+                        .help("Compiles every program instead of interpreting it"),
+                )
+                .arg(
+                    Arg::with_name("jit")
+                        .long("jit")
+                        .help("Runs every program in LLVM's JIT instead of interpreting it")
+                        .conflicts_with("compile"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("N")
+                        .help("Sets how many programs run at once [default: one per CPU]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-steps")
+                        .long("max-steps")
+                        .value_name("STEPS")
+                        .help("Fails interpreted programs that run longer than this")
+                        .default_value("100000000")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .help("Fails compiled and JIT programs that run longer than this")
+                        .default_value("10")
+                        .takes_value(true)
+                        .validator(|seconds| match seconds.parse::<f64>() {
+                            Ok(seconds) if Duration::try_from_secs_f64(seconds).is_ok() => Ok(()),
+                            _ => Err(String::from("the timeout is a number of seconds")),
+                        }),
+                )
+                .args(&tape_args(codegen::MAX_TAPE_SIZE)),
+        )
+        .subcommand(
+            SubCommand::with_name("fuzz")
+                .about("Runs random programs on every backend and shrinks any that disagree")
+                .arg(
+                    Arg::with_name("iterations")
+                        .short("n")
+                        .long("iterations")
+                        .value_name("N")
+                        .help("Sets how many programs to try")
+                        .default_value("1000")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .help("Sets the seed of the first program [default: the current time]")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .value_name("BYTES")
+                        .help("Sets how many random bytes each program is decoded from")
+                        .default_value("64")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compile")
+                        .long("compile")
+                        .help("Compiles every program too, which needs the LLVM tools"),
+                )
+                .args(&tape_args(interpreter::MAX_TAPE_SIZE)),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Warns about likely mistakes in a program")
+                .after_help(lint_help.as_str())
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the program to check")
+                        .required(true)
+                        .index(1),
+                )
+                .args(&lint_args())
+                .args(&tape_args(codegen::MAX_TAPE_SIZE)),
+        )
+        .subcommand(
+            SubCommand::with_name("trace-diff")
+                .about("Compares two traces, text or binary, and shows where they diverge")
+                .arg(Arg::with_name("LEFT").required(true).index(1))
+                .arg(Arg::with_name("RIGHT").required(true).index(2)),
+        )
         .get_matches();
 
-    let l = Lexer::new(matches.value_of("INPUT").unwrap())?;
-    l.check_loops().unwrap();
+    let result = match matches.subcommand() {
+        ("run", Some(run_matches)) => run(run_matches),
+        ("debug", Some(debug_matches)) => debug(debug_matches),
+        ("repl", Some(repl_matches)) => repl(repl_matches),
+        ("report", Some(report_matches)) => report(report_matches),
+        ("test", Some(test_matches)) => test(test_matches),
+        ("fuzz", Some(fuzz_matches)) => fuzz(fuzz_matches),
+        ("lint", Some(lint_matches)) => lint(lint_matches),
+        ("trace-diff", Some(diff_matches)) => trace_diff(diff_matches),
+        _ => compile(&matches),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+/// Options that describe the tape and the output, shared by every command
+/// that runs a program. Commands that only interpret it take at most
+/// [`interpreter::MAX_TAPE_SIZE`] cells
+fn tape_args(max_cells: u64) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("tape-size")
+            .long("tape-size")
+            .value_name("CELLS")
+            .help("Sets the number of cells on the tape")
+            .default_value("1024")
+            .takes_value(true)
+            .validator(move |cells| match cells.parse::<u64>() {
+                Ok(cells) if (1..=max_cells).contains(&cells) => Ok(()),
+                _ => Err(format!("the tape needs between 1 and {} cells", max_cells)),
+            }),
+        Arg::with_name("cell-size")
+            .long("cell-size")
+            .value_name("BITS")
+            .help("Sets the width of a tape cell")
+            .possible_values(&["8", "16", "32"])
+            .default_value("8")
+            .takes_value(true),
+        Arg::with_name("output-mode")
+            .long("output-mode")
+            .value_name("MODE")
+            .help("Sets how `.` writes a cell: raw bytes, decimal numbers or UTF-8 codepoints")
+            .possible_values(&["bytes", "decimal", "utf8"])
+            .default_value("bytes")
+            .takes_value(true),
+    ]
+}
+
+/// Options that make the compiled binary or the interpreter log every op
+fn trace_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("trace")
+            .long("trace")
+            .value_name("FORMAT")
+            .help("Logs every executed op with its position, the pointer and the cell")
+            .possible_values(&["text", "binary"])
+            .takes_value(true),
+        Arg::with_name("trace-file")
+            .long("trace-file")
+            .value_name("FILE")
+            .help("Writes the trace to FILE instead of stderr")
+            .requires("trace")
+            .takes_value(true),
+    ]
+}
+
+fn trace_options(matches: &ArgMatches) -> Option<TraceOptions> {
+    if !matches.is_present("trace") {
+        return None;
+    }
+
+    Some(TraceOptions {
+        format: value_t!(matches, "trace", TraceFormat).unwrap_or_else(|e| e.exit()),
+        file: matches.value_of("trace-file").map(PathBuf::from),
+    })
+}
+
+/// `--tape-size` for commands that can interpret the program or run it
+/// some other way, exits if the interpreter can't have that many cells
+fn interpreter_tape_size(matches: &ArgMatches) -> u64 {
+    let tape_size = value_t!(matches, "tape-size", u64).unwrap_or_else(|e| e.exit());
+
+    if tape_size > interpreter::MAX_TAPE_SIZE {
+        clap::Error::with_description(
+            &format!(
+                "the interpreter's tape has at most {} cells, bigger ones need --jit or --compile",
+                interpreter::MAX_TAPE_SIZE
+            ),
+            clap::ErrorKind::ValueValidation,
+        )
+        .exit();
+    }
+    tape_size
+}
+
+/// Applies the tape options to an interpreter
+fn configure<R: Read, W: Write>(
+    interpreter: Interpreter<R, W>,
+    matches: &ArgMatches,
+) -> Interpreter<R, W> {
+    let cell_size = value_t!(matches, "cell-size", CellSize).unwrap_or_else(|e| e.exit());
+    let output_mode = value_t!(matches, "output-mode", OutputMode).unwrap_or_else(|e| e.exit());
+    let tape_size = interpreter_tape_size(matches);
+
+    interpreter
+        .tape_size(tape_size)
+        .cell_size(cell_size)
+        .output_mode(output_mode)
+}
+
+/// Applies the tape options to a compiler, like `configure` does for the
+/// interpreter
+fn configure_compiler(compiler: Compiler, matches: &ArgMatches) -> Compiler {
+    let cell_size = value_t!(matches, "cell-size", CellSize).unwrap_or_else(|e| e.exit());
+    let output_mode = value_t!(matches, "output-mode", OutputMode).unwrap_or_else(|e| e.exit());
+    let tape_size = value_t!(matches, "tape-size", u64).unwrap_or_else(|e| e.exit());
+
+    compiler
+        .tape_size(tape_size)
+        .cell_size(cell_size)
+        .output_mode(output_mode)
+}
+
+fn run(matches: &ArgMatches) -> Result<(), CompileError> {
+    let path = matches.value_of("INPUT").unwrap();
+
+    if matches.is_present("jit") {
+        let artifact = configure_compiler(Compiler::new(), matches)
+            .trace(trace_options(matches))
+            .compile_file(path)?;
+
+        // Like a compiled binary, the exit code is the program's
+        match artifact.run_jit()? {
+            0 => return Ok(()),
+            code => process::exit(code),
+        }
+    }
+    let program = Program::from_lexer(Lexer::new(path)?)?;
+
+    let input: Box<dyn Read> = match matches.value_of("input") {
+        Some(file) => Box::new(File::open(file)?),
+        None => Box::new(io::stdin()),
+    };
+
+    let mut interpreter = configure(Interpreter::new(program, input, io::stdout()), matches);
+
+    if let Some(options) = trace_options(matches) {
+        let out: Box<dyn Write> = match options.file {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stderr())),
+        };
+        interpreter = interpreter.trace(options.format, out);
+    }
+
+    let coverage_file = matches.value_of("coverage");
+    if coverage_file.is_some() {
+        interpreter = interpreter.count_ops();
+    }
+
+    // Coverage is written even if the program fails, that's when it's
+    // most interesting
+    let result = interpreter.run();
+
+    if let (Some(file), Some(profile)) = (coverage_file, interpreter.profile()) {
+        let report = Report::new(interpreter.program(), &profile).unwrap();
+        std::fs::write(file, coverage::lcov(&report, path))?;
+    }
+
+    Ok(result?)
+}
+
+fn debug(matches: &ArgMatches) -> Result<(), CompileError> {
+    let path = matches.value_of("INPUT").unwrap();
+    let program = Program::from_lexer(Lexer::from_reader_debug(File::open(path)?)?)?;
+
+    // Commands are read from stdin too. Typed lines can be told apart, but
+    // piped ones would be mixed up with the program's input
+    let input: Box<dyn Read> = match matches.value_of("input") {
+        Some(file) => Box::new(File::open(file)?),
+        None if io::stdin().is_terminal() => Box::new(io::stdin()),
+        None => clap::Error::with_description(
+            "--input is required when commands are piped into the debugger",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+
+    let mut interpreter = configure(Interpreter::new(program, input, io::stdout()), matches);
+
+    if matches.is_present("record") {
+        interpreter = interpreter.recording(DEFAULT_SNAPSHOT_INTERVAL);
+    }
+
+    Debugger::new(interpreter).run(stdin_lines(), io::stdout())?;
+    Ok(())
+}
+
+fn repl(matches: &ArgMatches) -> Result<(), CompileError> {
+    let interpreter = configure(
+        Interpreter::new(Program::default(), io::stdin(), io::stdout()),
+        matches,
+    );
+
+    Repl::new(interpreter).run(stdin_lines(), io::stdout())?;
+    Ok(())
+}
+
+fn report(matches: &ArgMatches) -> Result<(), CompileError> {
+    let top = value_t!(matches, "top", usize).unwrap_or_else(|e| e.exit());
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let program = Program::from_lexer(Lexer::new(matches.value_of("INPUT").unwrap())?)?;
+    let profile: Profile = std::fs::read_to_string(matches.value_of("PROFILE").unwrap())?
+        .parse()
+        .map_err(invalid)?;
+    let report = Report::new(&program, &profile).map_err(invalid)?;
+
+    if matches.is_present("folded") {
+        print!("{}", report.folded_stacks());
+        return Ok(());
+    }
+
+    if matches.is_present("lcov") {
+        print!(
+            "{}",
+            coverage::lcov(&report, matches.value_of("INPUT").unwrap())
+        );
+        return Ok(());
+    }
+
+    let total = report.total().max(1);
+    let share = |ops: u64| ops as f64 * 100.0 / total as f64;
+
+    println!("{} ops executed", report.total());
+    println!();
+    println!("Hottest loops:");
+    println!(
+        "{:>4}  {:<16} {:>12} {:>14} {:>16} {:>7}",
+        "#", "loop", "entries", "iterations", "ops", "%"
+    );
+
+    for (rank, lp) in report.hottest_loops().iter().take(top).enumerate() {
+        println!(
+            "{:>4}  {:<16} {:>12} {:>14} {:>16} {:>7.2}",
+            rank + 1,
+            lp.start.to(lp.end).to_string(),
+            lp.entries,
+            lp.iterations,
+            lp.ops,
+            share(lp.ops)
+        );
+    }
+
+    let mut lines = report.lines();
+    lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    println!();
+    println!("Hottest lines:");
+    println!("{:>6} {:>16} {:>7}", "line", "ops", "%");
+
+    for (line, ops) in lines.into_iter().take(top) {
+        println!("{:>6} {:>16} {:>7.2}", line, ops, share(ops));
+    }
+
+    Ok(())
+}
+
+/// Exits with 1 if any test fails
+fn test(matches: &ArgMatches) -> Result<(), CompileError> {
+    let paths: Vec<PathBuf> = matches
+        .values_of("PATHS")
+        .unwrap()
+        .map(PathBuf::from)
+        .collect();
+    let jobs = match matches.value_of("jobs") {
+        Some(_) => value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit()),
+        None => thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+    };
+    let max_steps = value_t!(matches, "max-steps", u64).unwrap_or_else(|e| e.exit());
+    let timeout =
+        Duration::from_secs_f64(value_t!(matches, "timeout", f64).unwrap_or_else(|e| e.exit()));
+
+    let cases = testing::discover(&paths)?;
+    if cases.is_empty() {
+        println!("No tests found");
+        return Ok(());
+    }
+
+    let plural = if cases.len() == 1 { "" } else { "s" };
+    println!("running {} test{}", cases.len(), plural);
+
+    let results = if matches.is_present("compile") {
+        let compiler = configure_compiler(Compiler::new(), matches);
+        let next_binary = AtomicUsize::new(0);
+
+        testing::run_parallel(&cases, jobs, |case| {
+            let binary = std::env::temp_dir().join(format!(
+                "bw-test-{}-{}",
+                process::id(),
+                next_binary.fetch_add(1, Ordering::Relaxed)
+            ));
+            let result = compiler
+                .compile_bytes(&case.source)
+                .and_then(|artifact| artifact.link(binary.display().to_string()))
+                .map_err(|err| err.to_string())
+                .and_then(|_| run_program(&mut Command::new(&binary), case, timeout));
 
-    let mut p = Parser::new(l.tokens);
-    let nodes = p.parse_all();
+            let _ = std::fs::remove_file(&binary);
+            let _ = std::fs::remove_file(format!("{}2.ll", binary.display()));
 
-    let context = Context::create();
-    let module = context.create_module("bfc");
-    let builder = context.create_builder();
-    let execution_engine = module.create_execution_engine().unwrap();
+            result
+        })
+    } else if matches.is_present("jit") {
+        // Each program gets a process of its own, the JIT works on the real
+        // stdin and stdout and a crashing program would take the runner down
+        let bw = std::env::current_exe()?;
 
-    let types = Types::new(&context);
+        testing::run_parallel(&cases, jobs, |case| {
+            run_program(&mut jit_command(&bw, &case.path, matches), case, timeout)
+        })
+    } else {
+        // Once here rather than in every job
+        interpreter_tape_size(matches);
 
-    let opt: optimizer::OptWrapper<FunctionValue> = OptWrapper::new(&module);
-    opt.optimize();
+        testing::run_parallel(&cases, jobs, |case| {
+            let program =
+                Program::from_lexer(Lexer::from(&case.source[..])).map_err(|e| e.to_string())?;
+            let mut output = Vec::new();
+            let mut interpreter = configure(
+                Interpreter::new(program, &case.input[..], &mut output),
+                matches,
+            );
 
-    let cdg = Codegen {
-        input: nodes,
-        context: &context,
-        module,
-        builder,
-        execution_engine,
-        types,
-        passes: opt.pass_manager,
+            testing::interpret(&mut interpreter, max_steps)?;
+            drop(interpreter);
+
+            case.check(&output)
+        })
     };
 
-    cdg.generate_llvm("main2");
+    for (case, result) in cases.iter().zip(&results) {
+        let status = if result.is_ok() { "ok" } else { "FAILED" };
+        println!("test {} ... {}", case.path.display(), status);
+    }
+
+    let failures: Vec<_> = cases
+        .iter()
+        .zip(&results)
+        .filter_map(|(case, result)| Some((case, result.as_ref().err()?)))
+        .collect();
+
+    if !failures.is_empty() {
+        println!();
+        println!("failures:");
 
-    let bdr = BinaryGenerator::new("main");
-    bdr.compile()?;
+        for (case, message) in &failures {
+            println!();
+            println!("---- {} ----", case.path.display());
+            println!("{}", message);
+        }
+    }
+
+    println!();
+    println!(
+        "test result: {}. {} passed; {} failed",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        cases.len() - failures.len(),
+        failures.len()
+    );
+
+    if !failures.is_empty() {
+        process::exit(1);
+    }
 
     Ok(())
 }
+
+/// `bw run --jit` on `path` with the tape options in `matches`
+fn jit_command(bw: &Path, path: &Path, matches: &ArgMatches) -> Command {
+    let mut command = Command::new(bw);
+    command.args(["run", "--jit"]);
+
+    for option in &["tape-size", "cell-size", "output-mode"] {
+        command.arg(format!(
+            "--{}={}",
+            option,
+            matches.value_of(option).unwrap()
+        ));
+    }
+
+    command.arg(path);
+    command
+}
+
+/// How often a program with a timeout is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs `command` to the end with `input` on its stdin. With a `timeout`
+/// the program is killed once it runs longer, which is a `TimedOut` error
+fn run_with_input(
+    command: &mut Command,
+    input: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Writing from another thread keeps a program that prints before it
+    // reads everything from filling the pipe and blocking both sides.
+    // Output is read the same way while the program is waited on
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+
+    let status = match timeout {
+        Some(timeout) => wait_at_most(&mut child, timeout)?,
+        None => child.wait()?,
+    };
+    // A program that stops reading early closes the pipe, which is fine
+    let _ = writer.join();
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap()?,
+        stderr: stderr.join().unwrap()?,
+    })
+}
+
+fn read_in_background<R: Read + Send + 'static>(
+    mut pipe: R,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        pipe.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    })
+}
+
+/// Waits for `child` to exit, and kills it if it is still running after
+/// `timeout`
+fn wait_at_most(child: &mut Child, timeout: Duration) -> io::Result<ExitStatus> {
+    let start = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("the program ran longer than {:?} and was killed", timeout),
+            ));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Runs `command` with the input of `case` and compares its output
+fn run_program(command: &mut Command, case: &TestCase, timeout: Duration) -> Result<(), String> {
+    let output =
+        run_with_input(command, &case.input, Some(timeout)).map_err(|err| err.to_string())?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "the program failed with {}\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+
+    case.check(&output.stdout)
+}
+
+/// Runs fuzzed programs in a process of their own, either as a compiled
+/// binary or through `bw run --jit`
+struct ProcessBackend<'a> {
+    name: &'static str,
+    /// Compiles programs with the tape options of `bw fuzz`, `None` for the JIT
+    compiler: Option<Compiler>,
+    matches: &'a ArgMatches<'a>,
+    bw: PathBuf,
+    /// Where programs and binaries go, unique to this backend and process
+    scratch: PathBuf,
+}
+
+impl<'a> ProcessBackend<'a> {
+    fn new(name: &'static str, compile: bool, matches: &'a ArgMatches<'a>) -> io::Result<Self> {
+        Ok(ProcessBackend {
+            name,
+            compiler: if compile {
+                Some(configure_compiler(Compiler::new(), matches))
+            } else {
+                None
+            },
+            matches,
+            bw: std::env::current_exe()?,
+            scratch: std::env::temp_dir().join(format!("bw-fuzz-{}-{}", process::id(), name)),
+        })
+    }
+}
+
+impl Backend for ProcessBackend<'_> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&self, source: &str, input: &[u8]) -> io::Result<Outcome> {
+        let output = match &self.compiler {
+            Some(compiler) => {
+                compiler
+                    .compile_str(source)
+                    .and_then(|artifact| artifact.link(self.scratch.display().to_string()))
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+                run_with_input(&mut Command::new(&self.scratch), input, None)?
+            }
+            None => {
+                let path = self.scratch.with_extension("bf");
+                std::fs::write(&path, source)?;
+
+                run_with_input(&mut jit_command(&self.bw, &path, self.matches), input, None)?
+            }
+        };
+
+        // Killed by a signal, which no other backend can match
+        let exit_code = output.status.code().unwrap_or(-1);
+        Ok(Outcome::new(output.stdout, exit_code))
+    }
+}
+
+/// Exits with 1 and leaves a reproducer behind if the backends disagree
+fn fuzz(matches: &ArgMatches) -> Result<(), CompileError> {
+    let iterations = value_t!(matches, "iterations", u64).unwrap_or_else(|e| e.exit());
+    let size = value_t!(matches, "size", usize).unwrap_or_else(|e| e.exit());
+    let seed = match matches.value_of("seed") {
+        Some(_) => value_t!(matches, "seed", u64).unwrap_or_else(|e| e.exit()),
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs()),
+    };
+
+    let reference = InterpreterBackend {
+        tape_size: value_t!(matches, "tape-size", u64).unwrap_or_else(|e| e.exit()),
+        cell_size: value_t!(matches, "cell-size", CellSize).unwrap_or_else(|e| e.exit()),
+        output_mode: value_t!(matches, "output-mode", OutputMode).unwrap_or_else(|e| e.exit()),
+    };
+    let jit = ProcessBackend::new("jit", false, matches)?;
+    let compiled = ProcessBackend::new("binary", true, matches)?;
+
+    let mut backends: Vec<&dyn Backend> = vec![&reference, &jit];
+    if matches.is_present("compile") {
+        backends.push(&compiled);
+    }
+
+    println!("Fuzzing {} programs starting at seed {}", iterations, seed);
+
+    for program_seed in (0..iterations).map(|i| seed.wrapping_add(i)) {
+        let program = FuzzProgram::generate(&mut Rng::new(program_seed), size);
+
+        if fuzz::find_divergence(&program, &backends)?.is_none() {
+            continue;
+        }
+
+        println!(
+            "The program of seed {} diverges, shrinking it",
+            program_seed
+        );
+
+        let minimal = fuzz::shrink(program, |candidate| {
+            matches!(fuzz::find_divergence(candidate, &backends), Ok(Some(_)))
+        });
+        let divergence = fuzz::find_divergence(&minimal, &backends)?.unwrap();
+
+        // The reproducer is a `bw test` case that expects what the
+        // interpreter does
+        let source = minimal.source();
+        let expected = reference.run(&source, &minimal.input)?;
+        let name = format!("bw-fuzz-{}", program_seed);
+
+        std::fs::write(format!("{}.bf", name), &source)?;
+        std::fs::write(format!("{}.in", name), &minimal.input)?;
+        std::fs::write(
+            format!("{}.out", name),
+            [expected.output, expected.tape].concat(),
+        )?;
+
+        println!("{}", divergence);
+        println!();
+        println!("{}", source);
+        println!();
+        println!(
+            "Saved as {0}.bf, rerun it with `bw test --jit {0}.bf`",
+            name
+        );
+
+        process::exit(1);
+    }
+
+    println!("All backends agree");
+    Ok(())
+}
+
+/// Exits with 1 if the traces differ, like `diff`
+fn trace_diff(matches: &ArgMatches) -> Result<(), CompileError> {
+    let left = trace::read_trace(File::open(matches.value_of("LEFT").unwrap())?)?;
+    let right = trace::read_trace(File::open(matches.value_of("RIGHT").unwrap())?)?;
+
+    let (index, left, right) = match trace::first_divergence(&left, &right) {
+        Some(divergence) => divergence,
+        None => {
+            println!("The traces are identical, {} ops", left.len());
+            return Ok(());
+        }
+    };
+
+    let show = |record: Option<TraceRecord>| {
+        record.map_or_else(
+            || String::from("<end of trace>"),
+            |record| record.to_string(),
+        )
+    };
+
+    println!("The traces diverge after {} identical ops", index);
+    println!("< {}", show(left));
+    println!("> {}", show(right));
+
+    process::exit(1);
+}
+
+/// `--allow`, `--warn` and `--deny`, which take lint names or codes and
+/// can be repeated
+fn lint_args() -> Vec<Arg<'static, 'static>> {
+    [
+        ("allow", "Silences the lint"),
+        ("warn", "Reports the lint as a warning, the default"),
+        ("deny", "Reports the lint as an error and fails"),
+    ]
+    .iter()
+    .map(|&(name, help)| {
+        Arg::with_name(name)
+            .long(name)
+            .value_name("LINT")
+            .help(help)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|lint| lint.parse::<Lint>().map(|_| ()))
+    })
+    .collect()
+}
+
+/// Exits with 1 if any denied lint fires
+fn lint(matches: &ArgMatches) -> Result<(), CompileError> {
+    let path = matches.value_of("INPUT").unwrap();
+    let mut linter = Linter::new()
+        .tape_size(value_t!(matches, "tape-size", u64).unwrap_or_else(|e| e.exit()))
+        .cell_size(value_t!(matches, "cell-size", CellSize).unwrap_or_else(|e| e.exit()));
+
+    for (name, level) in &[
+        ("allow", Level::Allow),
+        ("warn", Level::Warn),
+        ("deny", Level::Deny),
+    ] {
+        for lint in matches.values_of(name).into_iter().flatten() {
+            linter = linter.level(lint.parse().expect("clap validates lints"), *level);
+        }
+    }
+
+    let warnings = linter.check(&Lexer::new(path)?)?;
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+
+    let errors = warnings
+        .iter()
+        .filter(|warning| warning.level == Level::Deny)
+        .count();
+    if !warnings.is_empty() {
+        println!();
+        println!("{} warnings, {} errors", warnings.len() - errors, errors);
+    }
+
+    if errors > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Lines typed on stdin. Each line is read on its own instead of through a
+/// long-lived lock, so `,` in the running program can read from stdin too
+fn stdin_lines() -> impl Iterator<Item = io::Result<String>> {
+    std::iter::from_fn(|| {
+        let mut line = String::new();
+
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(line.trim_end_matches(&['\n', '\r'][..]).to_string())),
+            Err(err) => Some(Err(err)),
+        }
+    })
+}
+
+fn compile(matches: &ArgMatches) -> Result<(), CompileError> {
+    let freestanding = matches.is_present("freestanding");
+    let opt_level = value_t!(matches, "opt-level", u32).unwrap_or_else(|e| e.exit());
+    let unbuffered = matches.is_present("unbuffered");
+    let tape_size = value_t!(matches, "tape-size", u64).unwrap_or_else(|e| e.exit());
+
+    let input = match (matches.value_of("eval"), matches.value_of("INPUT")) {
+        (Some(code), _) => Input::Inline(code),
+        (None, Some("-")) => Input::Stdin,
+        (None, Some(path)) => Input::File(path),
+        (None, None) => unreachable!("clap requires one of INPUT and --eval"),
+    };
+
+    let debug_source = if matches.is_present("debug") {
+        Some(match input {
+            Input::File(path) => std::fs::canonicalize(path)?,
+            Input::Stdin => PathBuf::from("<stdin>"),
+            Input::Inline(_) => PathBuf::from("<inline>"),
+        })
+    } else {
+        None
+    };
+
+    let passes = match matches.values_of("passes") {
+        Some(names) => Pipeline::named(names.filter(|&name| name != "none"))
+            .expect("clap only accepts known passes"),
+        None => Pipeline::all(),
+    };
+
+    let compiler = configure_compiler(Compiler::new(), matches)
+        .passes(passes)
+        .opt_level(opt_level)
+        .freestanding(freestanding)
+        .unbuffered(unbuffered)
+        .bounds_check(matches.is_present("bounds-check"))
+        .debug_source(debug_source)
+        .trace(trace_options(matches))
+        .profile_loops(if matches.is_present("profile-loops") {
+            Some(matches.value_of("profile-file").unwrap_or(DEFAULT_PROFILE))
+        } else {
+            None
+        })
+        .verify_opt(if matches.is_present("verify-opt") {
+            Some(match matches.value_of("verify-steps") {
+                Some(_) => value_t!(matches, "verify-steps", u64).unwrap_or_else(|e| e.exit()),
+                None => verify::DEFAULT_MAX_STEPS,
+            })
+        } else {
+            None
+        })
+        .prefix_steps(match matches.value_of("prefix-steps") {
+            Some(_) => value_t!(matches, "prefix-steps", u64).unwrap_or_else(|e| e.exit()),
+            None => prefix::DEFAULT_MAX_STEPS,
+        });
+
+    let artifact = match input {
+        Input::File(path) => compiler.compile_file(path)?,
+        Input::Stdin => compiler.compile_reader(io::stdin().lock())?,
+        Input::Inline(code) => compiler.compile_str(code)?,
+    };
+
+    let analysis = artifact.analysis();
+    for span in analysis.iter().flat_map(|analysis| &analysis.underflows) {
+        eprintln!(
+            "warning: the pointer always moves off the start of the tape at {}",
+            span
+        );
+    }
+
+    if matches.is_present("verbose") {
+        for (name, stats) in artifact.pass_stats() {
+            eprintln!(
+                "{:<6} removed {} nodes, rewrote {} loops",
+                name, stats.nodes_removed, stats.loops_rewritten
+            );
+
+            for span in &stats.removed {
+                eprintln!("  removed {}", span);
+            }
+        }
+
+        if artifact.prefix_steps() > 0 {
+            eprintln!("ran {} steps at compile time", artifact.prefix_steps());
+        }
+
+        match analysis.map(|analysis| analysis.max_cells) {
+            Some(Some(cells)) => eprintln!("uses at most {} of {} cells", cells, tape_size),
+            Some(None) => eprintln!("may need more than the {} cells on the tape", tape_size),
+            None => eprintln!("too big to analyze"),
+        }
+    }
+
+    artifact.link(matches.value_of("output").unwrap())
+}
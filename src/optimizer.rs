@@ -1,10 +1,16 @@
-use std::{borrow::Borrow, marker::PhantomData};
+use std::collections::BTreeMap;
+use std::ops::AddAssign;
+use std::sync::Arc;
+use std::{borrow::Borrow, fmt, marker::PhantomData, mem};
 
 use inkwell::{
     passes::{PassManager, PassManagerSubType},
     values::FunctionValue,
 };
 
+use crate::lexer::Span;
+use crate::parser::Node;
+
 pub struct OptWrapper<T> {
     pub pass_manager: PassManager<T>,
     sub_type: PhantomData<T>,
@@ -31,3 +37,724 @@ impl OptWrapper<FunctionValue<'_>> {
         self.pass_manager.initialize();
     }
 }
+
+/// What a [`Pass`] did to the program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PassStats {
+    /// How many nodes fewer the program has, a rewritten loop counts its
+    /// `LoopExpr`, its `LoopCloseNode` and its body
+    pub nodes_removed: usize,
+    /// Loops replaced by a single node
+    pub loops_rewritten: usize,
+    /// Source of the code the pass dropped without replacing it, like loops
+    /// that never run
+    pub removed: Vec<Span>,
+}
+
+impl PassStats {
+    pub fn changed(&self) -> bool {
+        *self != PassStats::default()
+    }
+}
+
+impl AddAssign for PassStats {
+    fn add_assign(&mut self, other: PassStats) {
+        self.nodes_removed += other.nodes_removed;
+        self.loops_rewritten += other.loops_rewritten;
+        self.removed.extend(other.removed);
+    }
+}
+
+/// A Brainfuck-level rewrite of the parsed program. Passes run before
+/// `Codegen` and have to keep the output and the final tape the same, which
+/// `--verify-opt` checks, see [`crate::verify`]
+pub trait Pass: Send + Sync {
+    /// The name `--passes` knows the pass by
+    fn name(&self) -> &'static str;
+
+    fn run(&self, root: &mut Node) -> PassStats;
+}
+
+/// Names of the passes [`Pipeline::named`] knows, in the order they run by
+/// default
+pub const PASSES: &[&str] = &["dead", "fold", "clear", "mul", "scan"];
+
+/// Rounds [`Pipeline::run`] gives up after, in case passes keep undoing
+/// each other's work
+const MAX_ROUNDS: usize = 16;
+
+/// Passes that run one after the other, over and over until none of them
+/// changes the program any more
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    passes: Vec<Arc<dyn Pass>>,
+}
+
+impl Pipeline {
+    /// Every pass in [`PASSES`]
+    pub fn all() -> Self {
+        Pipeline::named(PASSES.iter().copied()).expect("every pass in PASSES exists")
+    }
+
+    /// The passes called `names`, in that order
+    pub fn named<'a, I: IntoIterator<Item = &'a str>>(names: I) -> Result<Self, String> {
+        let mut pipeline = Pipeline::default();
+
+        for name in names {
+            let pass: Arc<dyn Pass> = match name {
+                "dead" => Arc::new(Dead),
+                "fold" => Arc::new(Fold::default()),
+                "clear" => Arc::new(Clear),
+                "mul" => Arc::new(Mul::default()),
+                "scan" => Arc::new(Scan),
+                _ => {
+                    return Err(format!(
+                        "unknown pass `{}`, expected one of {}",
+                        name,
+                        PASSES.join(", ")
+                    ))
+                }
+            };
+            pipeline.passes.push(pass);
+        }
+
+        Ok(pipeline)
+    }
+
+    pub fn push<P: Pass + 'static>(mut self, pass: P) -> Self {
+        self.passes.push(Arc::new(pass));
+        self
+    }
+
+    /// The same passes, for a binary that fails as soon as the pointer
+    /// leaves the tape. Only [`Fold`] and [`Mul`] move the pointer
+    /// differently than the program did, so they keep every step that can go
+    /// off the tape
+    pub fn bounds_checked(mut self) -> Self {
+        for pass in &mut self.passes {
+            match pass.name() {
+                "fold" => {
+                    *pass = Arc::new(Fold {
+                        one_way_moves: true,
+                    })
+                }
+                "mul" => {
+                    *pass = Arc::new(Mul {
+                        within_factors: true,
+                    })
+                }
+                _ => {}
+            }
+        }
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    /// Runs the passes until the program stops changing and returns what
+    /// each one did in total
+    pub fn run(&self, root: &mut Node) -> Vec<(&'static str, PassStats)> {
+        self.run_with(root, |_, _| {})
+    }
+
+    /// Like [`Pipeline::run`], calling `after_pass` with the name of the
+    /// pass and the program every time a pass changed it
+    pub fn run_with<F>(&self, root: &mut Node, mut after_pass: F) -> Vec<(&'static str, PassStats)>
+    where
+        F: FnMut(&'static str, &Node),
+    {
+        let mut stats = vec![PassStats::default(); self.passes.len()];
+
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+
+            for (pass, total) in self.passes.iter().zip(&mut stats) {
+                let round = pass.run(root);
+
+                if round.changed() {
+                    *total += round;
+                    changed = true;
+                    after_pass(pass.name(), root);
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        self.names().into_iter().zip(stats).collect()
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Calls `rewrite` on the body of every loop and then on the top level
+/// list, inner loops before the ones around them. The bodies are detached
+/// while they are visited instead of recursing into them, see [`crate::parser::Parser`]
+pub fn for_each_body<F: FnMut(&mut Vec<Node>)>(root: &mut Node, mut rewrite: F) {
+    let top = match root {
+        Node::Expr(body) => mem::take(&mut **body),
+        _ => return,
+    };
+    // Bodies still being visited, innermost last, each with the index of
+    // the next node to look at
+    let mut stack = vec![(top, 0)];
+
+    while let Some((body, next)) = stack.last_mut() {
+        let inner = body[*next..]
+            .iter()
+            .position(|node| matches!(node, Node::LoopExpr(..)));
+
+        if let Some(offset) = inner {
+            *next += offset + 1;
+            let inner = match &mut body[*next - 1] {
+                Node::LoopExpr(inner, _) => mem::take(&mut **inner),
+                _ => unreachable!("`position` found a loop"),
+            };
+            stack.push((inner, 0));
+            continue;
+        }
+
+        let (mut body, _) = stack.pop().unwrap();
+        rewrite(&mut body);
+
+        match stack.last_mut() {
+            Some((parent, next)) => {
+                if let Node::LoopExpr(inner, _) = &mut parent[*next - 1] {
+                    **inner = body;
+                }
+            }
+            None => {
+                if let Node::Expr(top) = root {
+                    **top = body;
+                }
+            }
+        }
+    }
+}
+
+/// How much `node` changes the cell, `None` for anything but `+`, `-` and
+/// folded runs of them
+fn cell_change(node: &Node) -> Option<i32> {
+    match node {
+        Node::PlusNode(_) => Some(1),
+        Node::MinusNode(_) => Some(-1),
+        Node::AddNode(amount, _) => Some(*amount),
+        _ => None,
+    }
+}
+
+/// How far `node` moves the pointer, `None` for anything but `>`, `<` and
+/// folded runs of them
+fn pointer_move(node: &Node) -> Option<i64> {
+    match node {
+        Node::IncrementPtrNode(_) => Some(1),
+        Node::DecrementPtrNode(_) => Some(-1),
+        Node::MoveNode(amount, _) => Some(*amount),
+        _ => None,
+    }
+}
+
+/// From the start of `first` to the end of `last`
+fn join(first: &Node, last: &Node) -> Span {
+    Span {
+        start: first.span().unwrap().start,
+        end: last.span().unwrap().end,
+    }
+}
+
+/// `node` and everything nested in it
+fn count_nodes(node: &Node) -> usize {
+    let mut count = 0;
+    let mut stack = vec![node];
+
+    while let Some(node) = stack.pop() {
+        count += 1;
+        if let Node::Expr(body) | Node::LoopExpr(body, _) = node {
+            stack.extend(body.iter());
+        }
+    }
+
+    count
+}
+
+/// What is known about the cells at some point of the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Zero {
+    Unknown,
+    /// The cell under the pointer is zero
+    Current,
+    /// Nothing has changed the tape yet
+    All,
+}
+
+/// Removes loops that are reached on a zero cell and so never run: loops
+/// before anything changed the tape, typically a comment at the top of the
+/// program, and loops right after another one, which always leaves on a
+/// zero cell
+pub struct Dead;
+
+impl Pass for Dead {
+    fn name(&self) -> &'static str {
+        "dead"
+    }
+
+    fn run(&self, root: &mut Node) -> PassStats {
+        // Loop bodies start on a cell that isn't zero, only the loops in
+        // them tell anything
+        let mut stats = PassStats::default();
+        for_each_body(root, |body| stats += remove_dead_loops(body, Zero::Unknown));
+
+        // That covered the top level too, which starts on an empty tape
+        if let Node::Expr(body) = root {
+            stats += remove_dead_loops(body, Zero::All);
+        }
+
+        stats
+            .removed
+            .sort_by_key(|span| (span.start.line, span.start.column));
+        stats
+    }
+}
+
+fn remove_dead_loops(body: &mut Vec<Node>, mut zero: Zero) -> PassStats {
+    let mut stats = PassStats::default();
+    let mut i = 0;
+
+    while i < body.len() {
+        let end = match &body[i] {
+            Node::LoopExpr(..) => i + 2,
+            Node::ClearNode(_) | Node::MulNode(..) | Node::ScanNode(..) => i + 1,
+            _ => i,
+        };
+
+        if end > i && zero != Zero::Unknown {
+            stats.removed.push(join(&body[i], &body[end - 1]));
+            stats.nodes_removed += body
+                .drain(i..end)
+                .map(|node| count_nodes(&node))
+                .sum::<usize>();
+            continue;
+        }
+
+        zero = match &body[i] {
+            // Every loop leaves on a zero cell
+            Node::LoopCloseNode(_)
+            | Node::ClearNode(_)
+            | Node::MulNode(..)
+            | Node::ScanNode(..) => Zero::Current,
+            Node::IncrementPtrNode(_) | Node::DecrementPtrNode(_) | Node::MoveNode(..) => {
+                if zero == Zero::All {
+                    Zero::All
+                } else {
+                    Zero::Unknown
+                }
+            }
+            Node::PrintCurrPosNode(_) | Node::BreakpointNode(_) => zero,
+            _ => Zero::Unknown,
+        };
+        i += 1;
+    }
+
+    stats
+}
+
+/// Replaces every loop in `body` whose body `replace` turns into a node,
+/// the `LoopCloseNode` after it goes too
+fn rewrite_loops<F>(body: &mut Vec<Node>, mut replace: F) -> PassStats
+where
+    F: FnMut(&[Node], Span) -> Option<Node>,
+{
+    let mut stats = PassStats::default();
+    let mut i = 0;
+
+    while i < body.len() {
+        let replacement = match &body[i] {
+            Node::LoopExpr(inner, _) => replace(inner, join(&body[i], &body[i + 1])),
+            _ => None,
+        };
+
+        if let Some(node) = replacement {
+            let removed = body.splice(i..i + 2, Some(node));
+            stats.nodes_removed += removed.map(|node| count_nodes(&node)).sum::<usize>() - 1;
+            stats.loops_rewritten += 1;
+        }
+        i += 1;
+    }
+
+    stats
+}
+
+/// Folds runs of `+` and `-` into one `AddNode` and runs of `>` and `<` into
+/// one `MoveNode`. Runs that cancel out disappear
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fold {
+    /// Only fold moves that go the same way, so the pointer still gets as
+    /// far as it did. `<>` on the first cell has to keep its `<` when the
+    /// binary checks the pointer, see [`Pipeline::bounds_checked`]
+    pub one_way_moves: bool,
+}
+
+impl Pass for Fold {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, root: &mut Node) -> PassStats {
+        let mut stats = PassStats::default();
+
+        for_each_body(root, |body| {
+            let before = body.len();
+            let mut nodes = mem::take(body).into_iter().peekable();
+
+            while let Some(node) = nodes.next() {
+                let mut last = None;
+
+                let folded = if let Some(mut amount) = cell_change(&node) {
+                    while let Some(more) = nodes.peek().and_then(cell_change) {
+                        amount = amount.wrapping_add(more);
+                        last = nodes.next();
+                    }
+                    last.as_ref()
+                        .map(|last| (amount != 0, Node::AddNode(amount, join(&node, last))))
+                } else if let Some(mut amount) = pointer_move(&node) {
+                    while let Some(more) = nodes.peek().and_then(pointer_move) {
+                        if self.one_way_moves && more.signum() != amount.signum() {
+                            break;
+                        }
+                        amount += more;
+                        last = nodes.next();
+                    }
+                    last.as_ref()
+                        .map(|last| (amount != 0, Node::MoveNode(amount, join(&node, last))))
+                } else {
+                    None
+                };
+
+                // Lone nodes stay as they are, so folding twice changes nothing
+                match folded {
+                    Some((true, folded)) => body.push(folded),
+                    Some((false, _)) => {}
+                    None => body.push(node),
+                }
+            }
+
+            stats.nodes_removed += before - body.len();
+        });
+
+        stats
+    }
+}
+
+/// Turns `[-]`, `[+]` and other loops that only change the cell by an odd
+/// amount into a `ClearNode`. Odd amounts reach zero from every value, even
+/// ones could loop forever
+pub struct Clear;
+
+impl Pass for Clear {
+    fn name(&self) -> &'static str {
+        "clear"
+    }
+
+    fn run(&self, root: &mut Node) -> PassStats {
+        let mut stats = PassStats::default();
+
+        for_each_body(root, |body| {
+            stats += rewrite_loops(body, |inner, span| match inner {
+                [node] if matches!(cell_change(node), Some(amount) if amount % 2 != 0) => {
+                    Some(Node::ClearNode(span))
+                }
+                _ => None,
+            });
+        });
+
+        stats
+    }
+}
+
+/// Turns loops like `[->+++>++<<]` into a `MulNode`: loops that only change
+/// cells and move the pointer, end up where they started and take one off
+/// the cell they run on
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Mul {
+    /// Only rewrite loops whose pointer stays between the lowest and the
+    /// highest offset the `MulNode` writes to, which are the cells it checks.
+    /// `[-<<>>>+<]` on the first cell has to stay a loop when the binary
+    /// checks the pointer, see [`Pipeline::bounds_checked`]
+    pub within_factors: bool,
+}
+
+impl Pass for Mul {
+    fn name(&self) -> &'static str {
+        "mul"
+    }
+
+    fn run(&self, root: &mut Node) -> PassStats {
+        let mut stats = PassStats::default();
+
+        for_each_body(root, |body| {
+            stats += rewrite_loops(body, |inner, span| {
+                let mut factors: BTreeMap<i64, i32> = BTreeMap::new();
+                let mut offset = 0;
+                let (mut lowest, mut highest) = (0, 0);
+
+                for node in inner {
+                    if let Some(amount) = cell_change(node) {
+                        let factor = factors.entry(offset).or_insert(0);
+                        *factor = factor.wrapping_add(amount);
+                    } else {
+                        offset += pointer_move(node)?;
+                        lowest = lowest.min(offset);
+                        highest = highest.max(offset);
+                    }
+                }
+
+                if offset != 0 || factors.remove(&0) != Some(-1) {
+                    return None;
+                }
+
+                let factors: Vec<_> = factors
+                    .into_iter()
+                    .filter(|&(_, factor)| factor != 0)
+                    .collect();
+                if factors.is_empty() {
+                    return None;
+                }
+                if self.within_factors {
+                    let first = factors[0].0.min(0);
+                    let last = factors[factors.len() - 1].0.max(0);
+                    if lowest < first || highest > last {
+                        return None;
+                    }
+                }
+                Some(Node::MulNode(factors, span))
+            });
+        });
+
+        stats
+    }
+}
+
+/// Turns `[>]`, `[<<]` and the like into a `ScanNode`
+pub struct Scan;
+
+impl Pass for Scan {
+    fn name(&self) -> &'static str {
+        "scan"
+    }
+
+    fn run(&self, root: &mut Node) -> PassStats {
+        let mut stats = PassStats::default();
+
+        for_each_body(root, |body| {
+            stats += rewrite_loops(body, |inner, span| match inner {
+                [node] => pointer_move(node).map(|step| Node::ScanNode(step, span)),
+                _ => None,
+            });
+        });
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzz::{FuzzProgram, Rng};
+    use crate::parser::parse;
+    use crate::verify::{self, Stage, Validator};
+
+    fn optimize(source: &str, passes: &[&str]) -> (Vec<Node>, Vec<(&'static str, PassStats)>) {
+        let mut root = parse(source);
+        let stats = Pipeline::named(passes.iter().copied())
+            .unwrap()
+            .run(&mut root);
+
+        match &mut root {
+            Node::Expr(body) => (mem::take(&mut **body), stats),
+            _ => unreachable!(),
+        }
+    }
+
+    /// From `start` to `end` on the first line
+    fn span(start: u32, end: u32) -> Span {
+        Span {
+            start: Span::at(1, start).start,
+            end: Span::at(1, end).end,
+        }
+    }
+
+    fn pass_stats(nodes_removed: usize, loops_rewritten: usize) -> PassStats {
+        PassStats {
+            nodes_removed,
+            loops_rewritten,
+            ..PassStats::default()
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let (nodes, stats) = optimize("+++--><<<.+-+>", &["fold"]);
+
+        assert_eq!(
+            nodes,
+            vec![
+                Node::AddNode(1, span(1, 5)),
+                Node::MoveNode(-2, span(6, 9)),
+                Node::PrintCurrPosNode(Span::at(1, 10)),
+                Node::AddNode(1, span(11, 13)),
+                Node::IncrementPtrNode(Span::at(1, 14)),
+            ]
+        );
+        assert_eq!(stats, vec![("fold", pass_stats(9, 0))]);
+    }
+
+    #[test]
+    fn test_fold_one_way_moves() {
+        let mut root = parse("<<>>>+<>");
+        Pipeline::named(["fold"].iter().copied())
+            .unwrap()
+            .bounds_checked()
+            .run(&mut root);
+
+        // The pointer still gets two cells to the left and back
+        let nodes = match &root {
+            Node::Expr(body) => &body[..],
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            nodes,
+            [
+                Node::MoveNode(-2, span(1, 2)),
+                Node::MoveNode(3, span(3, 5)),
+                Node::PlusNode(Span::at(1, 6)),
+                Node::DecrementPtrNode(Span::at(1, 7)),
+                Node::IncrementPtrNode(Span::at(1, 8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mul_within_factors() {
+        let validator = Validator::new(verify::sample_inputs(4, 0))
+            .tape_size(2)
+            .bounds_check(true);
+
+        // The first goes two cells left, the second writes nothing to the
+        // cell it moves to, neither is a multiplication on a checked tape
+        for source in &[",[-<<>>>+<]", ",[->+>+-<<]"] {
+            let mut root = parse(source);
+            let mut stages = vec![Stage::new("unoptimized", &root)];
+
+            let stats = Pipeline::all()
+                .bounds_checked()
+                .run_with(&mut root, |name, root| stages.push(Stage::new(name, root)));
+
+            assert!(stats.contains(&("mul", PassStats::default())), "{}", source);
+            if let Err(mismatch) = validator.check(&stages) {
+                panic!("{}\n{}", source, mismatch);
+            }
+        }
+
+        let (nodes, _) = optimize(",[-<<>>>+<]", &["fold", "mul"]);
+        assert_eq!(nodes[1], Node::MulNode(vec![(1, 1)], span(2, 11)));
+    }
+
+    #[test]
+    fn test_loops() {
+        let (nodes, stats) = optimize("[-]>[->++>+<<]>[>>]", &["fold", "clear", "mul", "scan"]);
+
+        assert_eq!(
+            nodes,
+            vec![
+                Node::ClearNode(span(1, 3)),
+                Node::IncrementPtrNode(Span::at(1, 4)),
+                Node::MulNode(vec![(1, 2), (2, 1)], span(5, 14)),
+                Node::IncrementPtrNode(Span::at(1, 15)),
+                Node::ScanNode(2, span(16, 19)),
+            ]
+        );
+        assert_eq!(
+            stats,
+            vec![
+                ("fold", pass_stats(3, 0)),
+                ("clear", pass_stats(2, 1)),
+                ("mul", pass_stats(7, 1)),
+                ("scan", pass_stats(2, 1)),
+            ]
+        );
+
+        // `[--]` never stops on odd cells and `[->+<<]` moves the pointer
+        let (nodes, _) = optimize("[--][->+<<]", &["clear", "mul"]);
+        assert_eq!(nodes.len(), 4);
+    }
+
+    #[test]
+    fn test_dead() {
+        let (nodes, stats) = optimize("[.]>[.]+[-][.]>[.]", &["dead"]);
+
+        assert_eq!(nodes.len(), 7);
+        assert_eq!(
+            stats[0].1,
+            PassStats {
+                nodes_removed: 9,
+                loops_rewritten: 0,
+                removed: vec![span(1, 3), span(5, 7), span(12, 14)],
+            }
+        );
+
+        // Inside a loop only another loop says anything about the cell
+        let (_, stats) = optimize("+[[.].>[-][[.]]]", &["dead"]);
+        assert_eq!(stats[0].1.removed, vec![span(11, 15)]);
+    }
+
+    #[test]
+    fn test_fixpoint() {
+        // Clearing only works once folding removed the `<>`
+        let (nodes, stats) = optimize("[<>-]", &["clear", "fold"]);
+
+        assert_eq!(nodes, vec![Node::ClearNode(span(1, 5))]);
+        assert_eq!(
+            stats,
+            vec![("clear", pass_stats(2, 1)), ("fold", pass_stats(2, 0))]
+        );
+
+        assert!(Pipeline::named(["fold", "nope"].iter().copied()).is_err());
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let depth = 100_000;
+        let source = format!("+{}-{}", "[".repeat(depth), "]".repeat(depth));
+        let (nodes, _) = optimize(&source, PASSES);
+
+        assert_eq!(nodes.len(), 3);
+    }
+
+    /// Every pass has to keep what random programs do, checked the same way
+    /// `--verify-opt` does
+    #[test]
+    fn test_preserves_semantics() {
+        let validator = Validator::new(verify::sample_inputs(4, 0));
+        let mut rng = Rng::new(7);
+
+        for _ in 0..200 {
+            let program = FuzzProgram::generate(&mut rng, 40);
+            let mut root = parse(&program.source());
+            let mut stages = vec![Stage::new("unoptimized", &root)];
+
+            Pipeline::all().run_with(&mut root, |name, root| stages.push(Stage::new(name, root)));
+
+            if let Err(mismatch) = validator.check(&stages) {
+                panic!("{}\n{}", program.source(), mismatch);
+            }
+        }
+    }
+}
@@ -1,135 +1,300 @@
-use crate::lexer::*;
+use crate::error::CompileError;
+use crate::lexer::{self, *};
 
-use std::{fmt, fs, fs::File, io::Write, path::Path};
+use std::io::Read;
+use std::{fmt, mem};
 
+#[derive(Debug, PartialEq)]
+pub enum ParserError {
+    /// The loop starting at this span has no `]`
+    UnclosedLoopError(Span),
+    /// This `]` does not close any loop
+    UnexpectedLoopCloseError(Span),
+}
+
+impl std::error::Error for ParserError {}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParserError::UnclosedLoopError(span) => write!(f, "Loop at {} is never closed", span),
+            ParserError::UnexpectedLoopCloseError(span) => {
+                write!(f, "`]` at {} does not close any loop", span)
+            }
+        }
+    }
+}
+
+/// Every node except the root `Expr` carries the span of the token it came
+/// from. A `LoopExpr` spans its `[`, the matching `]` follows it as a
+/// `LoopCloseNode`.
+///
+/// The parser only emits one node per token. The kinds after
+/// `BreakpointNode` come from the passes in [`crate::optimizer`] and span
+/// everything they replace
 #[derive(Debug, Clone, PartialEq)]
 pub enum Node {
-    PlusNode,
-    MinusNode,
-    IncrementPtrNode,
-    DecrementPtrNode,
-    PrintCurrPosNode,
-    LoopCloseNode,
+    PlusNode(Span),
+    MinusNode(Span),
+    IncrementPtrNode(Span),
+    DecrementPtrNode(Span),
+    PrintCurrPosNode(Span),
+    ReadCurrPosNode(Span),
+    LoopCloseNode(Span),
+    /// `#`, only present when the source was lexed in debug mode
+    BreakpointNode(Span),
+    /// Adds the amount to the cell, wrapping around
+    AddNode(i32, Span),
+    /// Moves the pointer by the amount
+    MoveNode(i64, Span),
+    /// Sets the cell to zero, like `[-]`
+    ClearNode(Span),
+    /// Adds the cell times the factor to the cell at each offset and sets it
+    /// to zero, like `[->+++<]`
+    MulNode(Vec<(i64, i32)>, Span),
+    /// Moves the pointer by the step until it is on a zero cell, like `[>]`
+    ScanNode(i64, Span),
 
     Expr(Box<Vec<Node>>),
-    LoopExpr(Box<Vec<Node>>),
+    LoopExpr(Box<Vec<Node>>, Span),
 }
 
+/// Builds the tree one token at a time, as the lexer finds them. Open loops
+/// are kept on an explicit stack instead of the call stack, so nesting depth
+/// is only bounded by memory. Everything else that walks the tree does the
+/// same
+#[derive(Debug, Default)]
 pub struct Parser {
-    pub input: Vec<Token>,
-    pub position: usize,
+    /// Enclosing node lists of the loops that are still open, together
+    /// with the span of their [, innermost last
+    open_loops: Vec<(Vec<Node>, Span)>,
+    /// The innermost node list, still being filled
+    result: Vec<Node>,
 }
 
 impl Parser {
-    pub fn new(input: Vec<Token>) -> Self {
-        Self { input, position: 0 }
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a program whose tokens were all kept
+    pub fn parse(lexer: &Lexer) -> Result<Node, ParserError> {
+        let mut parser = Parser::new();
+        for (&token, span) in lexer.tokens.iter().zip(lexer.spans()) {
+            parser.push(token, span)?;
+        }
+        parser.finish()
+    }
+
+    /// Lexes and parses everything `reader` produces in a single pass. The
+    /// tokens go straight into the tree and are never stored
+    pub fn parse_reader<R: Read>(reader: R) -> Result<Node, CompileError> {
+        let mut parser = Parser::new();
+        lexer::tokenize(reader, |token, span| -> Result<(), CompileError> {
+            Ok(parser.push(token, span)?)
+        })?;
+        Ok(parser.finish()?)
+    }
+
+    /// Like [`Parser::parse_reader`], for source that is already in memory
+    pub fn parse_bytes(source: &[u8]) -> Result<Node, CompileError> {
+        let mut parser = Parser::new();
+        lexer::tokenize_bytes(source, |token, span| -> Result<(), CompileError> {
+            Ok(parser.push(token, span)?)
+        })?;
+        Ok(parser.finish()?)
     }
 
-    fn parse_expr(&mut self, vect: &[Token], is_loop: bool) -> Node {
-        let mut result: Vec<Node> = Vec::new();
-
-        let mut vect_iter = vect.iter();
-        while let Some(tok) = vect_iter.next() {
-            match tok {
-                Token::PlusToken => {
-                    result.push(Node::PlusNode);
-                }
-                Token::MinusToken => {
-                    result.push(Node::MinusNode);
-                }
-                Token::SmallerThanToken => {
-                    result.push(Node::DecrementPtrNode);
-                }
-                Token::GreaterThanToken => {
-                    result.push(Node::IncrementPtrNode);
-                }
-                Token::DotToken => {
-                    result.push(Node::PrintCurrPosNode);
-                }
-                Token::LeftBracketToken => {
-                    let rest = self.input.clone();
-                    let pos = self.position.clone() + 1;
-
-                    // find next ] sign with an offset
-                    let (_, right) = rest.split_at(pos);
-                    let idx = right
-                        .clone()
-                        .iter()
-                        .position(|&r| r == Token::RightBracketToken)
-                        .unwrap();
-
-                    let rest = self.input.clone();
-                    let real_idx = idx + pos;
-
-                    let new_parsed = self.parse_expr(&rest[pos..real_idx], true);
-                    result.push(new_parsed);
-
-                    // skip loop-length times
-                    vect_iter.nth(real_idx - pos - 1);
-                }
-                Token::RightBracketToken => {
-                    result.push(Node::LoopCloseNode);
-                }
+    /// Adds the next token, found at `span`
+    pub fn push(&mut self, token: Token, span: Span) -> Result<(), ParserError> {
+        let node = match token {
+            Token::PlusToken => Node::PlusNode(span),
+            Token::MinusToken => Node::MinusNode(span),
+            Token::SmallerThanToken => Node::DecrementPtrNode(span),
+            Token::GreaterThanToken => Node::IncrementPtrNode(span),
+            Token::DotToken => Node::PrintCurrPosNode(span),
+            Token::CommaToken => Node::ReadCurrPosNode(span),
+            Token::BreakpointToken => Node::BreakpointNode(span),
+            Token::LeftBracketToken => {
+                self.open_loops.push((mem::take(&mut self.result), span));
+                return Ok(());
             }
-            self.position += 1;
+            Token::RightBracketToken => {
+                let (parent, loop_span) = self
+                    .open_loops
+                    .pop()
+                    .ok_or(ParserError::UnexpectedLoopCloseError(span))?;
+
+                let body = mem::replace(&mut self.result, parent);
+                self.result.push(Node::LoopExpr(Box::new(body), loop_span));
+                Node::LoopCloseNode(span)
+            }
+        };
+
+        self.result.push(node);
+        Ok(())
+    }
+
+    /// The whole program, once every token is in
+    pub fn finish(mut self) -> Result<Node, ParserError> {
+        match self.open_loops.pop() {
+            Some((_, span)) => Err(ParserError::UnclosedLoopError(span)),
+            None => Ok(Node::Expr(Box::new(mem::take(&mut self.result)))),
         }
+    }
+}
 
-        if is_loop == true {
-            Node::LoopExpr(Box::new(result))
-        } else {
-            Node::Expr(Box::new(result))
+impl Node {
+    /// The source the node came from, `None` for the root `Expr`
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Node::PlusNode(span)
+            | Node::MinusNode(span)
+            | Node::IncrementPtrNode(span)
+            | Node::DecrementPtrNode(span)
+            | Node::PrintCurrPosNode(span)
+            | Node::ReadCurrPosNode(span)
+            | Node::LoopCloseNode(span)
+            | Node::BreakpointNode(span)
+            | Node::AddNode(_, span)
+            | Node::MoveNode(_, span)
+            | Node::ClearNode(span)
+            | Node::MulNode(_, span)
+            | Node::ScanNode(_, span)
+            | Node::LoopExpr(_, span) => Some(*span),
+            Node::Expr(_) => None,
         }
     }
+}
+
+impl Drop for Node {
+    /// The derived drop glue would recurse once per nesting level, so
+    /// children are moved onto a heap-allocated stack and dropped from there
+    fn drop(&mut self) {
+        let mut stack: Vec<Node> = Vec::new();
+
+        if let Node::Expr(body) | Node::LoopExpr(body, _) = self {
+            stack.append(body);
+        }
 
-    pub fn parse_all(&mut self) -> Node {
-        self.parse_expr(&self.input.clone(), false)
+        while let Some(mut node) = stack.pop() {
+            if let Node::Expr(body) | Node::LoopExpr(body, _) = &mut node {
+                stack.append(body);
+            }
+        }
     }
 }
 
+/// Parses a program the test knows to be valid
 #[cfg(test)]
-mod tests {
-    use std::io::Write;
+pub(crate) fn parse(source: &str) -> Node {
+    Parser::parse_bytes(source.as_bytes()).unwrap()
+}
 
+#[cfg(test)]
+mod tests {
     use super::*;
 
-    static SIMPLE_NOLOOP_FILE: &str = "simple_noloop_test.bf";
-    static SIMPLE_LOOP_FILE: &str = "simple_loop_test.bf";
-
     #[test]
     fn test_valid_simple_noloops() {
-        let mut file = File::create(SIMPLE_NOLOOP_FILE).unwrap();
-        file.write_all(b"+.-").unwrap();
-
-        let lx = Lexer::new(SIMPLE_NOLOOP_FILE).unwrap();
-        let mut px = Parser::new(lx.tokens);
+        let lx = Lexer::from("+.-");
 
         let res = Node::Expr(Box::new(vec![
-            Node::PlusNode,
-            Node::PrintCurrPosNode,
-            Node::MinusNode,
+            Node::PlusNode(Span::at(1, 1)),
+            Node::PrintCurrPosNode(Span::at(1, 2)),
+            Node::MinusNode(Span::at(1, 3)),
         ]));
 
-        assert_eq!(px.parse_all(), res);
-        fs::remove_file(SIMPLE_NOLOOP_FILE).unwrap();
+        assert_eq!(Parser::parse(&lx).unwrap(), res);
     }
 
     #[test]
     fn test_valid_simple_loop() {
-        let mut file = File::create(SIMPLE_LOOP_FILE).unwrap();
-        file.write_all(b"+.[-]+").unwrap();
+        let lx = Lexer::from("+.[-]+");
+
+        let res = Node::Expr(Box::new(vec![
+            Node::PlusNode(Span::at(1, 1)),
+            Node::PrintCurrPosNode(Span::at(1, 2)),
+            Node::LoopExpr(
+                Box::new(vec![Node::MinusNode(Span::at(1, 4))]),
+                Span::at(1, 3),
+            ),
+            Node::LoopCloseNode(Span::at(1, 5)),
+            Node::PlusNode(Span::at(1, 6)),
+        ]));
+
+        assert_eq!(Parser::parse(&lx).unwrap(), res);
+    }
 
-        let lx = Lexer::new(SIMPLE_LOOP_FILE).unwrap();
-        let mut px = Parser::new(lx.tokens);
+    #[test]
+    fn test_valid_nested_loops() {
+        let lx = Lexer::from("[[-]>]");
 
         let res = Node::Expr(Box::new(vec![
-            Node::PlusNode,
-            Node::PrintCurrPosNode,
-            Node::LoopExpr(Box::new(vec![Node::MinusNode])),
-            Node::LoopCloseNode,
-            Node::PlusNode,
+            Node::LoopExpr(
+                Box::new(vec![
+                    Node::LoopExpr(
+                        Box::new(vec![Node::MinusNode(Span::at(1, 3))]),
+                        Span::at(1, 2),
+                    ),
+                    Node::LoopCloseNode(Span::at(1, 4)),
+                    Node::IncrementPtrNode(Span::at(1, 5)),
+                ]),
+                Span::at(1, 1),
+            ),
+            Node::LoopCloseNode(Span::at(1, 6)),
         ]));
 
-        assert_eq!(px.parse_all(), res);
-        fs::remove_file(SIMPLE_LOOP_FILE).unwrap();
+        assert_eq!(Parser::parse(&lx).unwrap(), res);
+        assert_eq!(Parser::parse_bytes(b"[[-]>]").unwrap(), res);
+        assert_eq!(Parser::parse_reader(&b"[[-]>]"[..]).unwrap(), res);
+    }
+
+    #[test]
+    fn test_unclosed_loop() {
+        let mut px = Parser::new();
+        px.push(Token::PlusToken, Span::at(1, 1)).unwrap();
+        px.push(Token::LeftBracketToken, Span::at(1, 2)).unwrap();
+        px.push(Token::MinusToken, Span::at(1, 3)).unwrap();
+
+        assert_eq!(
+            px.finish(),
+            Err(ParserError::UnclosedLoopError(Span::at(1, 2)))
+        );
+    }
+
+    #[test]
+    fn test_unexpected_loop_close() {
+        let lx = Lexer::from("+]");
+
+        assert_eq!(
+            Parser::parse(&lx),
+            Err(ParserError::UnexpectedLoopCloseError(Span::at(1, 2)))
+        );
+    }
+
+    #[test]
+    fn test_deeply_nested_loops() {
+        const DEPTH: usize = 100_000;
+
+        let source = format!("{}-{}", "[".repeat(DEPTH), "]".repeat(DEPTH));
+        let lx = Lexer::from(source.as_str());
+
+        let parsed = Parser::parse(&lx).unwrap();
+
+        let mut depth = 0;
+        let mut nodes = match &parsed {
+            Node::Expr(body) => body,
+            _ => panic!("root is not an Expr"),
+        };
+
+        while let Some(Node::LoopExpr(body, _)) = nodes.first() {
+            assert_eq!(nodes.len(), 2);
+            depth += 1;
+            nodes = body;
+        }
+
+        assert_eq!(depth, DEPTH);
+        assert_eq!(nodes.len(), 1);
     }
 }
@@ -0,0 +1,308 @@
+use std::io;
+use std::mem;
+
+use crate::codegen::{CellSize, OutputMode, DEFAULT_TAPE_SIZE};
+use crate::interpreter::{Interpreter, Op, Program};
+use crate::parser::Node;
+
+/// Steps [`Evaluator`] runs unless told otherwise
+pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+/// Cells the tape of [`Evaluator`] has at most, however big the binary's
+/// tape is. A prefix that moves past them isn't evaluated, which keeps
+/// compiling for a huge tape as cheap as it is without the evaluator
+pub const MAX_CELLS: u64 = 1 << 16;
+
+/// Where a program got to when its start ran at compile time. The binary
+/// writes `output` at once and carries on from there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    pub output: Vec<u8>,
+    /// The start of the tape the rest of the program starts on, up to the
+    /// last cell that isn't zero. Empty when there is no rest
+    pub tape: Vec<u32>,
+    pub pointer: usize,
+    /// Ops that ran at compile time
+    pub steps: u64,
+}
+
+/// Runs the part of a program that doesn't depend on input in the
+/// interpreter: everything up to the first `,`, or as much as the step
+/// budget allows
+#[derive(Debug, Clone)]
+pub struct Evaluator {
+    max_steps: u64,
+    tape_size: u64,
+    cell_size: CellSize,
+    output_mode: OutputMode,
+}
+
+impl Evaluator {
+    pub fn new() -> Self {
+        Evaluator {
+            max_steps: DEFAULT_MAX_STEPS,
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_size: CellSize::default(),
+            output_mode: OutputMode::default(),
+        }
+    }
+
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn tape_size(mut self, tape_size: u64) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> Self {
+        self.cell_size = cell_size;
+        self
+    }
+
+    pub fn output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.output_mode = output_mode;
+        self
+    }
+
+    /// Runs the start of `root` and replaces it with what is left to run.
+    /// Programs that move off the tape, or past [`MAX_CELLS`], are left
+    /// alone, so the binary fails the way it always did, and so are programs
+    /// that start with `,`
+    pub fn run(&self, root: &mut Node) -> Option<Prefix> {
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(Program::new(root), io::empty(), &mut output)
+            .tape_size(self.tape_size.min(MAX_CELLS))
+            .cell_size(self.cell_size)
+            .output_mode(self.output_mode);
+
+        while let Some((op, _)) = interpreter.current_op() {
+            if op == Op::Input || interpreter.steps() >= self.max_steps {
+                break;
+            }
+            interpreter.step().ok()?;
+        }
+
+        if interpreter.steps() == 0 {
+            return None;
+        }
+
+        let tape = if interpreter.is_finished() {
+            Vec::new()
+        } else {
+            let tape = interpreter.tape();
+            let used = tape.iter().rposition(|&value| value != 0);
+            tape[..used.map_or(0, |cell| cell + 1)].to_vec()
+        };
+        let (pc, pointer, steps) = (interpreter.pc(), interpreter.pointer(), interpreter.steps());
+        drop(interpreter);
+
+        if let Node::Expr(body) = root {
+            let top = mem::take(&mut **body);
+            **body = residual(top, pc);
+        }
+
+        Some(Prefix {
+            output,
+            tape,
+            pointer,
+            steps,
+        })
+    }
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What is left of `top` when the op at `pc` runs next.
+///
+/// If that op is inside loops, the rest of each loop's body runs first and
+/// then the loop itself, which checks the cell like its `]` would have
+fn residual(mut top: Vec<Node>, pc: usize) -> Vec<Node> {
+    let path = match path_to(&top, pc) {
+        Some(path) => path,
+        None => return Vec::new(),
+    };
+
+    // The rest of every body on the path but the top level one, which is
+    // the only one that isn't needed in one piece any more
+    let mut rests = Vec::new();
+    let mut body: &[Node] = &top;
+
+    for (level, &index) in path.iter().enumerate() {
+        if level > 0 {
+            rests.push(clone_nodes(&body[index..]));
+        }
+        if let Node::LoopExpr(inner, _) = &body[index] {
+            body = inner;
+        }
+    }
+
+    let mut residual: Vec<Node> = rests.into_iter().rev().flatten().collect();
+    residual.extend(top.drain(path[0]..));
+    residual
+}
+
+/// Indices of the nodes leading to the op at `pc` from the top level down,
+/// `None` once `pc` is past the end. A `]` is replaced by its loop
+fn path_to(top: &[Node], pc: usize) -> Option<Vec<usize>> {
+    // Nodes flatten to ops in this order, one op each, see `Program::new`
+    let mut op = 0;
+    let mut bodies = vec![top];
+    let mut path = vec![0];
+
+    while let Some(&body) = bodies.last() {
+        let index = *path.last().unwrap();
+
+        let node = match body.get(index) {
+            Some(node) => node,
+            None => {
+                bodies.pop();
+                path.pop();
+                if let Some(index) = path.last_mut() {
+                    *index += 1;
+                }
+                continue;
+            }
+        };
+
+        if op == pc {
+            if let Node::LoopCloseNode(_) = node {
+                *path.last_mut().unwrap() -= 1;
+            }
+            return Some(path);
+        }
+        op += 1;
+
+        match node {
+            Node::LoopExpr(inner, _) => {
+                bodies.push(inner);
+                path.push(0);
+            }
+            _ => *path.last_mut().unwrap() += 1,
+        }
+    }
+
+    None
+}
+
+/// A deep copy that doesn't recurse on loops like the derived `Clone` does
+fn clone_nodes(nodes: &[Node]) -> Vec<Node> {
+    // Copies still being filled with the rest of their originals, innermost
+    // last, together with the span of the loop they are the body of
+    let mut stack = vec![(Vec::new(), nodes.iter(), None)];
+
+    loop {
+        let (copy, originals, _) = stack.last_mut().unwrap();
+
+        match originals.next() {
+            Some(Node::LoopExpr(body, span)) => stack.push((Vec::new(), body.iter(), Some(*span))),
+            Some(node) => copy.push(node.clone()),
+            None => {
+                let (copy, _, span) = stack.pop().unwrap();
+
+                match (stack.last_mut(), span) {
+                    (Some((parent, _, _)), Some(span)) => {
+                        parent.push(Node::LoopExpr(Box::new(copy), span))
+                    }
+                    _ => return copy,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::parse;
+
+    /// Runs `source` on `input` in one go, and with its start evaluated
+    /// first and the rest started from the prefix's tape
+    fn both_ways(source: &str, input: &[u8], max_steps: u64) -> (Vec<u8>, Vec<u8>, Option<Prefix>) {
+        let mut expected = Vec::new();
+        Interpreter::new(Program::new(&parse(source)), input, &mut expected)
+            .run()
+            .unwrap();
+
+        let mut root = parse(source);
+        let prefix = Evaluator::new().max_steps(max_steps).run(&mut root);
+
+        let mut actual = Vec::new();
+        let mut interpreter = Interpreter::new(Program::new(&root), input, &mut actual);
+        if let Some(prefix) = &prefix {
+            let mut setup = String::new();
+            for &value in &prefix.tape {
+                setup.push_str(&"+".repeat(value as usize));
+                setup.push('>');
+            }
+            setup.push_str(&"<".repeat(prefix.tape.len()));
+            setup.push_str(&">".repeat(prefix.pointer));
+            interpreter.load(Program::from_lexer(Lexer::from(setup.as_str())).unwrap());
+            interpreter.run().unwrap();
+            interpreter.load(Program::new(&root));
+        }
+        interpreter.run().unwrap();
+        drop(interpreter);
+
+        let mut output = prefix
+            .as_ref()
+            .map_or(Vec::new(), |prefix| prefix.output.clone());
+        output.extend(actual);
+        (expected, output, prefix)
+    }
+
+    #[test]
+    fn test_input_free() {
+        let source = "++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.";
+        let (expected, actual, prefix) = both_ways(source, b"", DEFAULT_MAX_STEPS);
+        let prefix = prefix.unwrap();
+
+        assert_eq!(actual, expected);
+        assert_eq!(prefix.output, expected);
+        assert!(prefix.tape.is_empty());
+    }
+
+    #[test]
+    fn test_stops_at_input() {
+        let source = "++++[>++++++++++<-]>++.,[.,]";
+        let (expected, actual, prefix) = both_ways(source, b"hi\0", DEFAULT_MAX_STEPS);
+
+        assert_eq!(actual, expected);
+        assert_eq!(prefix.unwrap().output, b"*");
+        assert!(Evaluator::new().run(&mut parse(",+.")).is_none());
+        assert!(Evaluator::new().run(&mut parse("<+.")).is_none());
+    }
+
+    #[test]
+    fn test_huge_tape() {
+        let evaluator = Evaluator::new().tape_size(1 << 30);
+
+        // Only the cells up to the last one that was set are kept
+        let prefix = evaluator.run(&mut parse("+>>++>>,")).unwrap();
+        assert_eq!(prefix.tape, [1, 0, 2]);
+        assert_eq!(prefix.pointer, 4);
+
+        // The evaluator's tape ends long before the binary's does
+        let source = format!("{}+,", ">".repeat(MAX_CELLS as usize));
+        assert!(evaluator.run(&mut parse(&source)).is_none());
+    }
+
+    /// Budgets that run out inside loops, at their `[`, in their bodies and
+    /// at their `]`, have to leave a rest that carries on correctly
+    #[test]
+    fn test_stops_inside_loops() {
+        let source = "+++[>++[>+++<-]>.<<-]>>+.";
+
+        for max_steps in 1..120 {
+            let (expected, actual, _) = both_ways(source, b"", max_steps);
+            assert_eq!(actual, expected, "stopped after {} steps", max_steps);
+        }
+    }
+}
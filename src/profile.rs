@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::interpreter::{Op, Program};
+use crate::lexer::Span;
+
+/// First word of a profile
+pub const PROFILE_MAGIC: &str = "bwprofile";
+
+/// How often each op of a program ran, as written at exit by a binary
+/// compiled with `--profile-loops`. `counts[i]` belongs to `Program::ops[i]`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub counts: Vec<u64>,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let len = match (words.next(), words.next().map(str::parse::<usize>)) {
+            (Some(PROFILE_MAGIC), Some(Ok(len))) => len,
+            _ => return Err(String::from("not a profile")),
+        };
+
+        let counts = words
+            .map(str::parse)
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|err| format!("invalid counter in profile: {}", err))?;
+
+        if counts.len() != len {
+            return Err(format!(
+                "the profile should have {} counters but has {}",
+                len,
+                counts.len()
+            ));
+        }
+
+        Ok(Profile { counts })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", PROFILE_MAGIC, self.counts.len())?;
+
+        for count in &self.counts {
+            writeln!(f, "{}", count)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Execution counts of a single loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Span of the `[`
+    pub start: Span,
+    /// Span of the `]`
+    pub end: Span,
+    /// How often the loop was reached
+    pub entries: u64,
+    /// How often its body ran
+    pub iterations: u64,
+    /// Ops executed in the body, nested loops included
+    pub ops: u64,
+}
+
+/// A profile matched up with the program it was recorded for
+pub struct Report<'a> {
+    program: &'a Program,
+    counts: &'a [u64],
+}
+
+impl<'a> Report<'a> {
+    /// Fails if the profile has a different number of counters than the
+    /// program has ops, which means it belongs to some other program
+    pub fn new(program: &'a Program, profile: &'a Profile) -> Result<Self, String> {
+        if program.len() != profile.counts.len() {
+            return Err(format!(
+                "the profile has {} counters but the program has {} ops, \
+                 was it recorded for another program?",
+                profile.counts.len(),
+                program.len()
+            ));
+        }
+
+        Ok(Report {
+            program,
+            counts: &profile.counts,
+        })
+    }
+
+    pub fn program(&self) -> &Program {
+        self.program
+    }
+
+    /// How often each op ran, indexed like [`Program::ops`]
+    pub fn counts(&self) -> &[u64] {
+        self.counts
+    }
+
+    /// Number of ops executed in total
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Every loop that was reached, the one that executed most ops first
+    pub fn hottest_loops(&self) -> Vec<LoopProfile> {
+        // `sums[i]` is the number of ops executed before op `i`, so the
+        // ops of any range come out of a single subtraction
+        let mut sums = Vec::with_capacity(self.counts.len() + 1);
+        sums.push(0);
+        for count in self.counts {
+            sums.push(sums.last().unwrap() + count);
+        }
+
+        let mut loops: Vec<LoopProfile> = self
+            .program
+            .ops
+            .iter()
+            .enumerate()
+            .filter_map(|(start, op)| match *op {
+                Op::LoopStart(end) if self.counts[start] > 0 => Some(LoopProfile {
+                    start: self.program.spans[start],
+                    end: self.program.spans[end],
+                    entries: self.counts[start],
+                    iterations: self.counts[end],
+                    ops: sums[end + 1] - sums[start + 1],
+                }),
+                _ => None,
+            })
+            .collect();
+
+        loops.sort_by(|a, b| b.ops.cmp(&a.ops).then(a.start.start.cmp(&b.start.start)));
+        loops
+    }
+
+    /// Ops executed on each source line that has any, ordered by line
+    pub fn lines(&self) -> Vec<(u32, u64)> {
+        let mut lines = BTreeMap::new();
+
+        for (span, count) in self.program.spans.iter().zip(self.counts) {
+            *lines.entry(span.start.line).or_insert(0) += count;
+        }
+
+        lines.into_iter().filter(|&(_, count)| count > 0).collect()
+    }
+
+    /// The profile in the folded stack format flamegraph tools read. Every
+    /// loop is a frame named after the position of its `[` and every line
+    /// holds the ops executed directly in a loop, outside nested ones
+    pub fn folded_stacks(&self) -> String {
+        let ops = &self.program.ops;
+        // Per `LoopStart`: the enclosing loop and the ops executed directly in it
+        let mut parents: Vec<Option<usize>> = vec![None; ops.len()];
+        let mut own_ops = vec![0; ops.len()];
+        let mut top_level_ops = 0;
+        let mut open_loops: Vec<usize> = Vec::new();
+
+        for (index, op) in ops.iter().enumerate() {
+            match open_loops.last() {
+                Some(&innermost) => own_ops[innermost] += self.counts[index],
+                None => top_level_ops += self.counts[index],
+            }
+
+            match op {
+                Op::LoopStart(_) => {
+                    parents[index] = open_loops.last().copied();
+                    open_loops.push(index);
+                }
+                Op::LoopEnd(_) => {
+                    open_loops.pop();
+                }
+                _ => {}
+            }
+        }
+
+        let mut folded = String::new();
+
+        if top_level_ops > 0 {
+            folded.push_str(&format!("main {}\n", top_level_ops));
+        }
+
+        for (start, &count) in own_ops.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+
+            let mut frames = Vec::new();
+            let mut frame = Some(start);
+            while let Some(index) = frame {
+                let position = self.program.spans[index].start;
+                frames.push(format!("loop {}:{}", position.line, position.column));
+                frame = parents[index];
+            }
+            frames.push(String::from("main"));
+            frames.reverse();
+
+            folded.push_str(&format!("{} {}\n", frames.join(";"), count));
+        }
+
+        folded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::lexer::Lexer;
+    use std::io;
+
+    fn profile(source: &str) -> (Program, Profile) {
+        let program = Program::from_lexer(Lexer::from(source)).unwrap();
+        let mut interpreter =
+            Interpreter::new(program.clone(), io::empty(), io::sink()).count_ops();
+
+        interpreter.run().unwrap();
+
+        (program, interpreter.profile().unwrap())
+    }
+
+    #[test]
+    fn test_profile_round_trip() {
+        let (_, profile) = profile("++[>+<-]");
+
+        assert_eq!(profile.to_string().parse::<Profile>().unwrap(), profile);
+        assert!("bwprofile 3\n1\n2\n".parse::<Profile>().is_err());
+        assert!("1\n2\n".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn test_hottest_loops() {
+        let (program, profile) = profile("++[>+++[>+<-]<-]\n[-]");
+        let report = Report::new(&program, &profile).unwrap();
+        let loops = report.hottest_loops();
+
+        assert_eq!(loops.len(), 3);
+
+        assert_eq!(loops[0].start, Span::at(1, 3));
+        assert_eq!((loops[0].entries, loops[0].iterations), (1, 2));
+        // Per outer iteration: `>+++[<-]` with 3 inner iterations of 4 ops
+        // and the `[`, then `<-]`
+        assert_eq!(loops[0].ops, 2 * (4 + 1 + 3 * 5 + 3));
+
+        assert_eq!(loops[1].start, Span::at(1, 8));
+        assert_eq!((loops[1].entries, loops[1].iterations), (2, 6));
+        assert_eq!(loops[1].ops, 6 * 5);
+
+        // The loop on line 2 is reached but its body never runs
+        assert_eq!(loops[2].start, Span::at(2, 1));
+        assert_eq!(
+            (loops[2].entries, loops[2].iterations, loops[2].ops),
+            (1, 0, 0)
+        );
+
+        assert_eq!(report.lines(), [(1, report.total() - 1), (2, 1)]);
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let (program, profile) = profile("+[>+[-]<-]");
+        let report = Report::new(&program, &profile).unwrap();
+
+        assert_eq!(
+            report.folded_stacks(),
+            "main 2\nmain;loop 1:2 6\nmain;loop 1:2;loop 1:5 2\n"
+        );
+
+        let other = Profile { counts: vec![0; 3] };
+        assert!(Report::new(&program, &other).is_err());
+    }
+}
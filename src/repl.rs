@@ -0,0 +1,257 @@
+use std::fs;
+use std::io::{self, Read, Write};
+
+use crate::error::CompileError;
+use crate::interpreter::{Interpreter, Program};
+use crate::lexer::Lexer;
+use crate::parser::{Parser, ParserError};
+
+const HELP: &str = "\
+:tape [RADIUS]   Shows the cells around the pointer, 8 on each side by default
+:load FILE       Runs a program on the current tape
+:save FILE       Writes everything that ran since the last reset to FILE
+:reset           Clears the tape and the session
+:quit            Leaves the REPL
+Any other line is Brainfuck. A line with unclosed loops continues on the next one";
+
+/// Cells shown on either side of the pointer after every entry
+const WINDOW_RADIUS: usize = 4;
+
+/// What happened to an entry passed to [`Repl::eval`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Ran,
+    /// The entry has unclosed loops and waits for the lines that close them
+    Incomplete,
+}
+
+/// Runs one piece of Brainfuck after the other on a tape that persists
+/// between them
+pub struct Repl<R, W> {
+    interpreter: Interpreter<R, W>,
+    /// Source of every entry that ran since the last reset, for `:save`
+    session: String,
+    /// Lines of an entry whose loops are not closed yet
+    pending: String,
+}
+
+impl<R: Read, W: Write> Repl<R, W> {
+    pub fn new(interpreter: Interpreter<R, W>) -> Self {
+        Repl {
+            interpreter,
+            session: String::new(),
+            pending: String::new(),
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<R, W> {
+        &self.interpreter
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Lexes, parses and runs `source` on the current tape. Source with
+    /// unclosed loops is kept until a later call closes them
+    pub fn eval(&mut self, source: &str) -> Result<Entry, CompileError> {
+        self.pending.push_str(source);
+        self.pending.push('\n');
+
+        let lexer = Lexer::from(self.pending.as_str());
+
+        let nodes = match Parser::parse(&lexer) {
+            Ok(nodes) => nodes,
+            Err(ParserError::UnclosedLoopError(_)) => return Ok(Entry::Incomplete),
+            Err(err) => {
+                self.pending.clear();
+                return Err(err.into());
+            }
+        };
+
+        let entry = std::mem::take(&mut self.pending);
+
+        self.interpreter.load(Program::new(&nodes));
+        self.interpreter.run()?;
+        self.session.push_str(&entry);
+
+        Ok(Entry::Ran)
+    }
+
+    /// Runs a complete program, e.g. a file, on the current tape
+    pub fn run_program(&mut self, source: &str) -> Result<(), CompileError> {
+        let program = Program::from_lexer(Lexer::from(source))?;
+
+        self.interpreter.load(program);
+        self.interpreter.run()?;
+
+        self.session.push_str(source);
+        if !source.ends_with('\n') {
+            self.session.push('\n');
+        }
+
+        Ok(())
+    }
+
+    /// Clears the tape, the session and any unfinished entry
+    pub fn reset(&mut self) {
+        self.interpreter.reset();
+        self.session.clear();
+        self.pending.clear();
+    }
+
+    /// Reads entries from `commands` until `:quit` or EOF and writes the
+    /// replies to `console`
+    pub fn run<C, O>(&mut self, commands: C, mut console: O) -> io::Result<()>
+    where
+        C: IntoIterator<Item = io::Result<String>>,
+        O: Write,
+    {
+        writeln!(
+            console,
+            "Every line runs on the same tape, type `:help` for a list of commands"
+        )?;
+
+        let mut lines = commands.into_iter();
+
+        loop {
+            let prompt = if self.pending.is_empty() {
+                "bw> "
+            } else {
+                "... "
+            };
+            write!(console, "{}", prompt)?;
+            console.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+
+            if self.pending.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+
+            if self.pending.is_empty() && line.trim_start().starts_with(':') {
+                if !self.command(line.trim(), &mut console)? {
+                    break;
+                }
+                continue;
+            }
+
+            match self.eval(&line) {
+                Ok(Entry::Incomplete) => {}
+                result => self.report(result.map(|_| ()), &mut console)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a `:` command, returns false on `:quit`
+    fn command<O: Write>(&mut self, line: &str, console: &mut O) -> io::Result<bool> {
+        let mut words = line[1..].split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let argument = words.next();
+
+        match (name, argument) {
+            ("tape", radius) => match radius.map_or(Ok(8), str::parse) {
+                Ok(radius) => writeln!(console, "{}", self.interpreter.tape_window(radius))?,
+                Err(_) => writeln!(console, "`:tape` expects a number")?,
+            },
+            ("load", Some(path)) => match fs::read_to_string(path) {
+                Ok(source) => {
+                    let result = self.run_program(&source);
+                    self.report(result, console)?;
+                }
+                Err(err) => writeln!(console, "error: {}", err)?,
+            },
+            ("save", Some(path)) => match fs::write(path, &self.session) {
+                Ok(()) => writeln!(console, "Saved the session to {}", path)?,
+                Err(err) => writeln!(console, "error: {}", err)?,
+            },
+            ("load", None) | ("save", None) => {
+                writeln!(console, "`:{}` expects a file name", name)?
+            }
+            ("reset", _) => {
+                self.reset();
+                writeln!(console, "{}", self.interpreter.tape_window(WINDOW_RADIUS))?;
+            }
+            ("help", _) => writeln!(console, "{}", HELP)?,
+            ("quit", _) | ("q", _) => return Ok(false),
+            _ => writeln!(console, "unknown command `:{}`, try `:help`", name)?,
+        }
+
+        Ok(true)
+    }
+
+    /// Prints the outcome of an entry followed by the tape around the pointer
+    fn report<O: Write>(
+        &mut self,
+        result: Result<(), CompileError>,
+        console: &mut O,
+    ) -> io::Result<()> {
+        // Whatever the program printed comes first
+        let _ = self.interpreter.flush();
+
+        if self.interpreter.end_output_line() {
+            writeln!(console)?;
+        }
+
+        if let Err(err) = result {
+            writeln!(console, "error: {}", err)?;
+        }
+
+        writeln!(console, "{}", self.interpreter.tape_window(WINDOW_RADIUS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repl() -> Repl<io::Empty, Vec<u8>> {
+        Repl::new(Interpreter::new(
+            Program::default(),
+            io::empty(),
+            Vec::new(),
+        ))
+    }
+
+    #[test]
+    fn test_tape_persists_between_entries() {
+        let mut repl = repl();
+
+        assert_eq!(repl.eval("+++>").unwrap(), Entry::Ran);
+        assert_eq!(repl.eval("++<-").unwrap(), Entry::Ran);
+
+        assert_eq!(repl.interpreter().tape()[..2], [2, 2]);
+        assert_eq!(repl.interpreter().pointer(), 0);
+        assert_eq!(repl.session(), "+++>\n++<-\n");
+    }
+
+    #[test]
+    fn test_unclosed_loop_continues() {
+        let mut repl = repl();
+
+        assert_eq!(repl.eval("+++[>++").unwrap(), Entry::Incomplete);
+        assert_eq!(repl.interpreter().tape()[0], 0);
+
+        assert_eq!(repl.eval("<-]").unwrap(), Entry::Ran);
+        assert_eq!(repl.interpreter().tape()[..2], [0, 6]);
+    }
+
+    #[test]
+    fn test_failed_entries_are_not_saved() {
+        let mut repl = repl();
+
+        assert!(repl.eval("+]").is_err());
+        assert!(repl.eval("<").is_err());
+        assert_eq!(repl.eval("+").unwrap(), Entry::Ran);
+        assert_eq!(repl.session(), "+\n");
+
+        repl.reset();
+        assert_eq!(repl.session(), "");
+        assert_eq!(repl.interpreter().tape()[0], 0);
+    }
+}
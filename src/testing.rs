@@ -0,0 +1,376 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::interpreter::{Interpreter, Status};
+
+/// Extension of the programs `discover` picks up in directories
+pub const SOURCE_EXTENSION: &str = "bf";
+
+/// A program together with the input to feed it and the output it should
+/// write.
+///
+/// Both come from sidecar files next to the program, `name.in` and
+/// `name.out`, or from a comment loop at the very top of it. The loop never
+/// runs since the first cell starts at zero, so its lines can say anything:
+///
+/// ```text
+/// [
+///   input: 3\n
+///   output: 6\n
+/// ]
+/// ```
+///
+/// Values are trimmed and understand `\n`, `\r`, `\t`, `\0`, `\\` and
+/// `\xHH` escapes. Repeated keys append to each other and sidecar files
+/// win over the header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub path: PathBuf,
+    pub source: Vec<u8>,
+    pub input: Vec<u8>,
+    pub expected: Vec<u8>,
+}
+
+impl TestCase {
+    /// Reads the program at `path` and what it expects. Programs that don't
+    /// say what they should output aren't tests and come back as `None`
+    pub fn load(path: &Path) -> io::Result<Option<TestCase>> {
+        let source = fs::read(path)?;
+        let header = Header::parse(&source).map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        })?;
+
+        let expected = match read_sidecar(path, "out")?.or(header.output) {
+            Some(expected) => expected,
+            None => return Ok(None),
+        };
+        let input = read_sidecar(path, "in")?
+            .or(header.input)
+            .unwrap_or_default();
+
+        Ok(Some(TestCase {
+            path: path.to_path_buf(),
+            source,
+            input,
+            expected,
+        }))
+    }
+
+    /// Describes where `actual` starts to differ from the expected output
+    pub fn check(&self, actual: &[u8]) -> Result<(), String> {
+        if actual == &self.expected[..] {
+            return Ok(());
+        }
+
+        let offset = self
+            .expected
+            .iter()
+            .zip(actual)
+            .position(|(expected, actual)| expected != actual)
+            .unwrap_or_else(|| self.expected.len().min(actual.len()));
+        let line = self.expected[..offset]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count()
+            + 1;
+
+        Err(format!(
+            "the output differs on line {}, byte {}\n  expected: {}\n  actual:   {}",
+            line,
+            offset,
+            excerpt(&self.expected, offset),
+            excerpt(actual, offset)
+        ))
+    }
+}
+
+/// The line of `output` around `offset`, quoted and escaped
+fn excerpt(output: &[u8], offset: usize) -> String {
+    if offset >= output.len() {
+        return String::from("<end of output>");
+    }
+
+    let start = output[..offset]
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    let end = output[offset..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(output.len(), |newline| offset + newline + 1);
+
+    format!("{:?}", String::from_utf8_lossy(&output[start..end]))
+}
+
+fn read_sidecar(path: &Path, extension: &str) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path.with_extension(extension)) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Expectations written in the comment loop at the top of a program
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Header {
+    input: Option<Vec<u8>>,
+    output: Option<Vec<u8>>,
+}
+
+impl Header {
+    fn parse(source: &[u8]) -> Result<Header, String> {
+        let mut header = Header::default();
+
+        let start = match source.iter().position(|byte| !byte.is_ascii_whitespace()) {
+            Some(first) if source[first] == b'[' => first + 1,
+            _ => return Ok(header),
+        };
+
+        let mut depth = 1;
+        let end = source[start..].iter().position(|&byte| {
+            match byte {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        });
+
+        // Unbalanced brackets are for the lexer to report
+        let comment = match end {
+            Some(end) => &source[start..start + end],
+            None => return Ok(header),
+        };
+
+        for line in comment.split(|&byte| byte == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim();
+
+            let (field, value) = if let Some(value) = line.strip_prefix("input:") {
+                (&mut header.input, value)
+            } else if let Some(value) = line.strip_prefix("output:") {
+                (&mut header.output, value)
+            } else {
+                continue;
+            };
+
+            field
+                .get_or_insert_with(Vec::new)
+                .extend(unescape(value.trim())?);
+        }
+
+        Ok(header)
+    }
+}
+
+fn unescape(value: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| format!("invalid escape `\\x{}`", hex))?;
+                bytes.push(byte);
+            }
+            Some(other) => return Err(format!("unknown escape `\\{}`", other)),
+            None => return Err(String::from("`\\` at the end of a line")),
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Every test under `paths`, sorted by path. Directories are searched
+/// recursively for `.bf` files, skipping hidden ones; files are taken as
+/// they are
+pub fn discover(paths: &[PathBuf]) -> io::Result<Vec<TestCase>> {
+    let mut sources = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_sources(path, &mut sources)?;
+        } else {
+            sources.push(path.clone());
+        }
+    }
+
+    sources.sort();
+    sources.dedup();
+
+    let mut cases = Vec::new();
+    for source in sources {
+        if let Some(case) = TestCase::load(&source)? {
+            cases.push(case);
+        }
+    }
+
+    Ok(cases)
+}
+
+fn collect_sources(dir: &Path, sources: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let hidden = path
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().starts_with('.'));
+
+        if hidden {
+            continue;
+        } else if path.is_dir() {
+            collect_sources(&path, sources)?;
+        } else if path.extension() == Some(OsStr::new(SOURCE_EXTENSION)) {
+            sources.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `interpreter` to the end. A program that takes more than
+/// `max_steps` steps fails since it probably never stops
+pub fn interpret<R: Read, W: Write>(
+    interpreter: &mut Interpreter<R, W>,
+    max_steps: u64,
+) -> Result<(), String> {
+    while interpreter.step().map_err(|err| err.to_string())? != Status::Finished {
+        if interpreter.steps() > max_steps {
+            return Err(format!("still running after {} steps", max_steps));
+        }
+    }
+
+    interpreter.flush().map_err(|err| err.to_string())
+}
+
+/// Calls `run` on every case from `jobs` threads and returns the results
+/// in the order of `cases`
+pub fn run_parallel<T, F>(cases: &[TestCase], jobs: usize, run: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&TestCase) -> T + Sync,
+{
+    let next = AtomicUsize::new(0);
+
+    let mut results: Vec<(usize, T)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        match cases.get(index) {
+                            Some(case) => results.push((index, run(case))),
+                            None => return results,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    results.sort_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Program;
+    use crate::lexer::Lexer;
+
+    fn case(expected: &[u8]) -> TestCase {
+        TestCase {
+            path: PathBuf::from("test.bf"),
+            source: Vec::new(),
+            input: Vec::new(),
+            expected: expected.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let source = b"\n[ Echoes its input, [nested] brackets are fine\n  input: 3\\n\n  output: \\x33\n  output: \\n\n]\n,[.[-],]";
+        let header = Header::parse(source).unwrap();
+
+        assert_eq!(header.input.as_deref(), Some(&b"3\n"[..]));
+        assert_eq!(header.output.as_deref(), Some(&b"3\n"[..]));
+
+        assert_eq!(
+            Header::parse(b"+[\noutput: 1\n]").unwrap(),
+            Header::default()
+        );
+        assert!(Header::parse(b"[\noutput: \\q\n]").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let case = case(b"first\nsecond\n");
+
+        assert!(case.check(b"first\nsecond\n").is_ok());
+        assert_eq!(
+            case.check(b"first\nsecund\n").unwrap_err(),
+            "the output differs on line 2, byte 9\n  \
+             expected: \"second\\n\"\n  \
+             actual:   \"secund\\n\""
+        );
+        assert!(case
+            .check(b"first\n")
+            .unwrap_err()
+            .contains("<end of output>"));
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        let cases: Vec<_> = ["+.", "++.", "+[]", "+++."]
+            .iter()
+            .map(|source| TestCase {
+                source: source.as_bytes().to_vec(),
+                ..case(&[source.len() as u8 - 1])
+            })
+            .collect();
+
+        let results = run_parallel(&cases, 3, |case| {
+            let program = Program::from_lexer(Lexer::from(&case.source[..])).unwrap();
+            let mut output = Vec::new();
+            let mut interpreter = Interpreter::new(program, &case.input[..], &mut output);
+
+            interpret(&mut interpreter, 1000)?;
+            case.check(&output)
+        });
+
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert_eq!(
+            results[2],
+            Err(String::from("still running after 1000 steps"))
+        );
+        assert!(results[3].is_ok());
+    }
+}
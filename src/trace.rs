@@ -0,0 +1,273 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// First bytes of a binary trace
+pub const BINARY_MAGIC: &[u8; 8] = b"BWTRACE1";
+
+/// Size of a record in a binary trace: line, column, op, pointer, before, after
+pub const BINARY_RECORD_SIZE: usize = 4 + 4 + 1 + 8 + 4 + 4;
+
+/// How traced ops are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One line per op, e.g. `2:5 + ptr 3 7 -> 8`
+    Text,
+    /// `BINARY_MAGIC` followed by fixed-size little-endian records
+    Binary,
+}
+
+impl Default for TraceFormat {
+    fn default() -> Self {
+        TraceFormat::Text
+    }
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("unknown trace format: {}", s)),
+        }
+    }
+}
+
+/// Where and how a program logs the ops it executes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    /// Written to stderr if not set
+    pub file: Option<PathBuf>,
+}
+
+/// A single executed op. `before` is the current cell before the op and
+/// `after` the current cell after it, so for `>` and `<` they belong to
+/// different cells. `pointer` is the tape index before the op
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub line: u32,
+    pub column: u32,
+    /// The op as its Brainfuck character
+    pub op: u8,
+    pub pointer: u64,
+    pub before: u32,
+    pub after: u32,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} {} ptr {} {} -> {}",
+            self.line, self.column, self.op as char, self.pointer, self.before, self.after
+        )
+    }
+}
+
+impl FromStr for TraceRecord {
+    type Err = String;
+
+    /// Parses a line of a text trace
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid trace record: {}", s);
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        let (position, op, pointer, before, after) = match words[..] {
+            [position, op, "ptr", pointer, before, "->", after] if op.len() == 1 => {
+                (position, op.as_bytes()[0], pointer, before, after)
+            }
+            _ => return Err(invalid()),
+        };
+        let (line, column) = position.split_once(':').ok_or_else(invalid)?;
+
+        Ok(TraceRecord {
+            line: line.parse().map_err(|_| invalid())?,
+            column: column.parse().map_err(|_| invalid())?,
+            op,
+            pointer: pointer.parse().map_err(|_| invalid())?,
+            before: before.parse().map_err(|_| invalid())?,
+            after: after.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TraceRecord {
+    pub fn to_bytes(&self) -> [u8; BINARY_RECORD_SIZE] {
+        let mut bytes = [0; BINARY_RECORD_SIZE];
+
+        bytes[0..4].copy_from_slice(&self.line.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.column.to_le_bytes());
+        bytes[8] = self.op;
+        bytes[9..17].copy_from_slice(&self.pointer.to_le_bytes());
+        bytes[17..21].copy_from_slice(&self.before.to_le_bytes());
+        bytes[21..25].copy_from_slice(&self.after.to_le_bytes());
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; BINARY_RECORD_SIZE]) -> Self {
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let mut pointer = [0; 8];
+        pointer.copy_from_slice(&bytes[9..17]);
+
+        TraceRecord {
+            line: u32_at(0),
+            column: u32_at(4),
+            op: bytes[8],
+            pointer: u64::from_le_bytes(pointer),
+            before: u32_at(17),
+            after: u32_at(21),
+        }
+    }
+}
+
+/// Writes records in either format, starting binary traces with the magic
+pub struct TraceWriter<W> {
+    format: TraceFormat,
+    out: W,
+    started: bool,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(format: TraceFormat, out: W) -> Self {
+        TraceWriter {
+            format,
+            out,
+            started: false,
+        }
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", record),
+            TraceFormat::Binary => {
+                if !self.started {
+                    self.started = true;
+                    self.out.write_all(BINARY_MAGIC)?;
+                }
+                self.out.write_all(&record.to_bytes())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads a whole trace in either format, telling them apart by the magic
+pub fn read_trace<R: Read>(reader: R) -> io::Result<Vec<TraceRecord>> {
+    let mut reader = BufReader::new(reader);
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    if reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+        reader.consume(BINARY_MAGIC.len());
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        if bytes.len() % BINARY_RECORD_SIZE != 0 {
+            return Err(invalid(String::from(
+                "binary trace ends in the middle of a record",
+            )));
+        }
+
+        return Ok(bytes
+            .chunks(BINARY_RECORD_SIZE)
+            .map(|chunk| {
+                let mut record = [0; BINARY_RECORD_SIZE];
+                record.copy_from_slice(chunk);
+                TraceRecord::from_bytes(&record)
+            })
+            .collect());
+    }
+
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| line?.parse().map_err(invalid))
+        .collect()
+}
+
+/// Index and records of the first difference between two traces. A
+/// missing record means one trace ended earlier
+pub fn first_divergence(
+    left: &[TraceRecord],
+    right: &[TraceRecord],
+) -> Option<(usize, Option<TraceRecord>, Option<TraceRecord>)> {
+    (0..left.len().max(right.len()))
+        .map(|index| (index, left.get(index).copied(), right.get(index).copied()))
+        .find(|(_, left, right)| left != right)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<TraceRecord> {
+        vec![
+            TraceRecord {
+                line: 1,
+                column: 1,
+                op: b'+',
+                pointer: 0,
+                before: 0,
+                after: 1,
+            },
+            TraceRecord {
+                line: 12,
+                column: 345,
+                op: b'>',
+                pointer: u64::MAX,
+                before: u32::MAX,
+                after: 7,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        for &format in [TraceFormat::Text, TraceFormat::Binary].iter() {
+            let mut out = Vec::new();
+            let mut writer = TraceWriter::new(format, &mut out);
+
+            for record in &records() {
+                writer.write(record).unwrap();
+            }
+
+            assert_eq!(read_trace(&out[..]).unwrap(), records());
+        }
+    }
+
+    #[test]
+    fn test_text_format() {
+        assert_eq!(records()[0].to_string(), "1:1 + ptr 0 0 -> 1");
+        assert!("1:1 + ptr 0 0 1".parse::<TraceRecord>().is_err());
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let left = records();
+        let mut right = records();
+
+        assert_eq!(first_divergence(&left, &right), None);
+
+        right[1].after = 8;
+        assert_eq!(
+            first_divergence(&left, &right),
+            Some((1, Some(left[1]), Some(right[1])))
+        );
+
+        right.truncate(1);
+        assert_eq!(
+            first_divergence(&left, &right),
+            Some((1, Some(left[1]), None))
+        );
+    }
+}
@@ -0,0 +1,466 @@
+use std::fmt;
+use std::io;
+
+use crate::codegen::{CellSize, DEFAULT_TAPE_SIZE};
+use crate::fuzz::Rng;
+use crate::interpreter::{Interpreter, Op, Program};
+use crate::lexer::Span;
+use crate::parser::Node;
+
+/// Number of inputs [`sample_inputs`] comes up with for the compiler
+pub const SAMPLES: usize = 16;
+
+/// Steps each version of the program may take per input before the
+/// comparison gives up on it
+pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+/// Cells the tapes [`Validator`] runs the versions on have at most, however
+/// big the binary's tape is. A run that moves past them is inconclusive
+pub const MAX_CELLS: u64 = 1 << 16;
+
+/// One version of the program on its way through the optimizer, named after
+/// the pass that produced it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub name: String,
+    pub program: Program,
+}
+
+impl Stage {
+    pub fn new<S: Into<String>>(name: S, root: &Node) -> Self {
+        Stage {
+            name: name.into(),
+            program: Program::new(root),
+        }
+    }
+}
+
+/// A few inputs every program has to agree on: nothing, some text, the
+/// extreme bytes and random bytes from `seed`
+pub fn sample_inputs(count: usize, seed: u64) -> Vec<Vec<u8>> {
+    let mut inputs: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"0\n".to_vec(),
+        b"Hello, World!\n".to_vec(),
+        vec![0, 255],
+    ];
+
+    let mut rng = Rng::new(seed);
+    while inputs.len() < count {
+        let len = rng.next_u64() % 32 + 1;
+        inputs.push((0..len).map(|_| rng.next_u64() as u8).collect());
+    }
+
+    inputs.truncate(count);
+    inputs
+}
+
+/// Something a program does that every optimized version of it has to do
+/// as well, in the same order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    /// `.` on a cell holding this value
+    Output(u32),
+    /// `,`, the input is the same for both so the value is too
+    Input,
+    /// The pointer moved off the tape
+    Failed,
+    Finished,
+    OutOfSteps,
+    /// The pointer moved past [`MAX_CELLS`] on a tape that has more
+    OutOfCells,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Output(value) => write!(f, "writes {}", value),
+            Event::Input => write!(f, "reads input"),
+            Event::Failed => write!(f, "moves off the tape"),
+            Event::Finished => write!(f, "finishes"),
+            Event::OutOfSteps => write!(f, "runs out of steps"),
+            Event::OutOfCells => write!(f, "moves past the first {} cells", MAX_CELLS),
+        }
+    }
+}
+
+/// Where one version of the program was when the difference showed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// The op that did it, `None` at the end of the program
+    pub op: Option<(Op, Span)>,
+    pub steps: u64,
+    /// The cells around the pointer, see [`Interpreter::tape_window`]
+    pub tape: String,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.op {
+            Some((op, span)) => write!(f, "`{}` at {}", op, span)?,
+            None => write!(f, "the end of the program")?,
+        }
+        write!(f, " after {} steps\n{}", self.steps, self.tape)
+    }
+}
+
+/// The first thing a pass changed about what the program does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The pass that produced the diverging version
+    pub pass: String,
+    /// The version the pass started from
+    pub previous: String,
+    pub input: Vec<u8>,
+    pub what: String,
+    pub before: Position,
+    pub after: Position,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "`{}` changed the program on input {:?}: {}",
+            self.pass,
+            String::from_utf8_lossy(&self.input),
+            self.what
+        )?;
+        writeln!(f, "`{}` stopped at {}", self.previous, self.before)?;
+        write!(f, "`{}` stopped at {}", self.pass, self.after)
+    }
+}
+
+/// How much of the comparison actually happened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Pairs of versions run on one input each
+    pub runs: usize,
+    /// Runs that agreed until one side ran out of steps
+    pub out_of_steps: usize,
+    /// Runs that agreed until one side moved past [`MAX_CELLS`]
+    pub out_of_cells: usize,
+}
+
+/// Checks that optimizing a program didn't change what it does by running
+/// every version next to the one before it in the interpreter.
+///
+/// Both sides are stepped until they write, read, fail or finish, and have
+/// to do the same thing each time; at the end the tapes have to match too.
+/// Once the earlier version moves off the tape nothing is compared any
+/// more, since a compiled binary would already be reading random memory,
+/// unless the binary checks the pointer and has to fail the same way.
+/// Programs can loop forever, so a run that hits the step budget without
+/// disagreeing counts as inconclusive rather than as a failure, and so does
+/// one that needs more than [`MAX_CELLS`] of a bigger tape
+#[derive(Debug, Clone)]
+pub struct Validator {
+    inputs: Vec<Vec<u8>>,
+    max_steps: u64,
+    tape_size: u64,
+    cell_size: CellSize,
+    bounds_check: bool,
+}
+
+impl Validator {
+    pub fn new(inputs: Vec<Vec<u8>>) -> Self {
+        Validator {
+            inputs,
+            max_steps: DEFAULT_MAX_STEPS,
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_size: CellSize::default(),
+            bounds_check: false,
+        }
+    }
+
+    /// Steps each version may take per input
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn tape_size(mut self, tape_size: u64) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> Self {
+        self.cell_size = cell_size;
+        self
+    }
+
+    /// Whether the binary fails when the pointer leaves the tape, which
+    /// makes that something the optimized versions have to do as well
+    pub fn bounds_check(mut self, bounds_check: bool) -> Self {
+        self.bounds_check = bounds_check;
+        self
+    }
+
+    /// Compares each stage with the one before it on every input. The
+    /// first stage is the unoptimized program
+    pub fn check(&self, stages: &[Stage]) -> Result<Summary, Box<Mismatch>> {
+        let mut summary = Summary::default();
+
+        for pair in stages.windows(2) {
+            for input in &self.inputs {
+                summary.runs += 1;
+                match self.compare(&pair[0], &pair[1], input)? {
+                    Some(Event::OutOfSteps) => summary.out_of_steps += 1,
+                    Some(_) => summary.out_of_cells += 1,
+                    None => {}
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Why the comparison gave up before the end of both programs, if it did
+    fn compare(
+        &self,
+        before: &Stage,
+        after: &Stage,
+        input: &[u8],
+    ) -> Result<Option<Event>, Box<Mismatch>> {
+        let mut left = self.interpreter(&before.program, input);
+        let mut right = self.interpreter(&after.program, input);
+
+        loop {
+            let (expected, left_op) = self.next_event(&mut left);
+            let (actual, right_op) = self.next_event(&mut right);
+
+            for event in &[expected, actual] {
+                if let Event::OutOfSteps | Event::OutOfCells = event {
+                    return Ok(Some(*event));
+                }
+            }
+            // Compiled programs don't check the pointer unless told to, so
+            // passes may do anything once the program before them moved off
+            // the tape
+            if expected == Event::Failed && (!self.bounds_check || actual == Event::Failed) {
+                return Ok(None);
+            }
+
+            let what = if expected != actual {
+                format!(
+                    "it {} before `{}` but {} after it",
+                    expected, after.name, actual
+                )
+            } else if expected == Event::Finished {
+                match first_difference(left.tape(), right.tape()) {
+                    Some(cell) => format!(
+                        "it leaves {} in cell {} before `{}` but {} after it",
+                        left.tape()[cell],
+                        cell,
+                        after.name,
+                        right.tape()[cell]
+                    ),
+                    None => return Ok(None),
+                }
+            } else {
+                continue;
+            };
+
+            return Err(Box::new(Mismatch {
+                pass: after.name.clone(),
+                previous: before.name.clone(),
+                input: input.to_vec(),
+                what,
+                before: position(&left, left_op),
+                after: position(&right, right_op),
+            }));
+        }
+    }
+
+    fn interpreter<'a>(
+        &self,
+        program: &Program,
+        input: &'a [u8],
+    ) -> Interpreter<&'a [u8], io::Sink> {
+        Interpreter::new(program.clone(), input, io::sink())
+            .tape_size(self.tape_size.min(MAX_CELLS))
+            .cell_size(self.cell_size)
+    }
+
+    /// Steps `interpreter` up to and including the next op the other side
+    /// has to match, together with that op
+    fn next_event(
+        &self,
+        interpreter: &mut Interpreter<&[u8], io::Sink>,
+    ) -> (Event, Option<(Op, Span)>) {
+        loop {
+            if interpreter.steps() >= self.max_steps {
+                return (Event::OutOfSteps, None);
+            }
+
+            let current = interpreter.current_op();
+            let event = match current {
+                None => return (Event::Finished, None),
+                Some((Op::Output, _)) => {
+                    Some(Event::Output(interpreter.tape()[interpreter.pointer()]))
+                }
+                Some((Op::Input, _)) => Some(Event::Input),
+                Some(_) => None,
+            };
+
+            if interpreter.step().is_err() {
+                if self.past_the_cells(interpreter, current) {
+                    return (Event::OutOfCells, current);
+                }
+                return (Event::Failed, current);
+            }
+            if let Some(event) = event {
+                return (event, current);
+            }
+        }
+    }
+
+    /// Whether `op` failed because it went past the end of a tape cut down
+    /// to [`MAX_CELLS`], rather than past the end of the binary's tape
+    fn past_the_cells(
+        &self,
+        interpreter: &Interpreter<&[u8], io::Sink>,
+        op: Option<(Op, Span)>,
+    ) -> bool {
+        if self.tape_size <= MAX_CELLS {
+            return false;
+        }
+
+        let reach = match op {
+            Some((Op::Right, _)) => 1,
+            Some((Op::Move(amount), _)) | Some((Op::Scan(amount), _)) => amount,
+            Some((Op::Mul(index), _)) => interpreter.program().factors[index]
+                .iter()
+                .map(|&(offset, _)| offset)
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        };
+        interpreter.pointer() as i64 + reach >= interpreter.tape().len() as i64
+    }
+}
+
+fn first_difference(left: &[u32], right: &[u32]) -> Option<usize> {
+    left.iter()
+        .zip(right)
+        .position(|(left, right)| left != right)
+}
+
+fn position(interpreter: &Interpreter<&[u8], io::Sink>, op: Option<(Op, Span)>) -> Position {
+    Position {
+        op,
+        steps: interpreter.steps(),
+        tape: interpreter.tape_window(4),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn stage(name: &str, source: &str) -> Stage {
+        Stage {
+            name: name.to_string(),
+            program: Program::from_lexer(Lexer::from(source)).unwrap(),
+        }
+    }
+
+    fn validator() -> Validator {
+        Validator::new(sample_inputs(SAMPLES, 0)).max_steps(10_000)
+    }
+
+    #[test]
+    fn test_equivalent_stages() {
+        let stages = [
+            stage("unoptimized", ",[->+<]>+++-."),
+            stage("fold", ",[->+<]>++."),
+            stage("comments", "read , add [->+<] >++ ."),
+        ];
+
+        assert_eq!(
+            validator().check(&stages),
+            Ok(Summary {
+                runs: 2 * SAMPLES,
+                out_of_steps: 0,
+                out_of_cells: 0
+            })
+        );
+
+        // Leaving the tape is undefined in compiled programs
+        let stages = [stage("unoptimized", "<>+."), stage("fold", "+.")];
+        assert!(validator().check(&stages).is_ok());
+    }
+
+    #[test]
+    fn test_names_the_pass() {
+        let stages = [
+            stage("unoptimized", "+++.>+"),
+            stage("fold", "+++.>+"),
+            stage("broken", "++.>+"),
+        ];
+
+        let mismatch = validator().check(&stages).unwrap_err();
+        assert_eq!(mismatch.pass, "broken");
+        assert_eq!(mismatch.previous, "fold");
+        assert_eq!(mismatch.input, b"");
+        assert_eq!(
+            mismatch.what,
+            "it writes 3 before `broken` but writes 2 after it"
+        );
+        assert_eq!(mismatch.before.op.map(|(op, _)| op), Some(Op::Output));
+        assert_eq!(mismatch.after.steps, 3);
+        assert!(mismatch.after.tape.contains("[2]"));
+
+        let stages = [stage("unoptimized", "+>+[-]"), stage("broken", "+>+")];
+        let mismatch = validator().check(&stages).unwrap_err();
+        assert_eq!(
+            mismatch.what,
+            "it leaves 0 in cell 1 before `broken` but 1 after it"
+        );
+    }
+
+    #[test]
+    fn test_bounds_check() {
+        let stages = [stage("unoptimized", "+<>."), stage("fold", "+.")];
+        assert!(validator().check(&stages).is_ok());
+
+        // A binary that checks the pointer fails at the `<`
+        let mismatch = validator().bounds_check(true).check(&stages).unwrap_err();
+        assert_eq!(
+            mismatch.what,
+            "it moves off the tape before `fold` but writes 1 after it"
+        );
+
+        let stages = [stage("unoptimized", "+<>."), stage("fold", "+<.")];
+        assert!(validator().bounds_check(true).check(&stages).is_ok());
+    }
+
+    #[test]
+    fn test_huge_tape() {
+        let validator = Validator::new(sample_inputs(1, 0))
+            .tape_size(u64::from(u32::MAX))
+            .bounds_check(true);
+
+        // Only the first cells exist, the rest of the tape could hold anything
+        let stages = [stage("unoptimized", "+[>+]"), stage("broken", "+[>++]")];
+        let summary = validator.check(&stages).unwrap();
+        assert_eq!(summary.out_of_cells, summary.runs);
+
+        // The start of the tape is still where it was
+        let stages = [stage("unoptimized", "+<."), stage("broken", "+.")];
+        assert!(validator.check(&stages).is_err());
+    }
+
+    #[test]
+    fn test_out_of_steps() {
+        let stages = [stage("unoptimized", "+[]"), stage("same", "+[]")];
+        let summary = validator().check(&stages).unwrap();
+
+        assert_eq!(summary.out_of_steps, summary.runs);
+
+        // Output before the loop is still compared
+        let stages = [stage("unoptimized", "+.[]"), stage("broken", "++.[]")];
+        assert!(validator().check(&stages).is_err());
+    }
+}
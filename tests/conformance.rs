@@ -0,0 +1,120 @@
+//! Runs the programs in `tests/conformance` through every way brainwash can
+//! execute a program. `bw test` compares each run with the expected output,
+//! so passing everywhere means every backend wrote the same bytes
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// What `bw test --compile` runs besides `bw` itself
+const COMPILE_TOOLS: [&str; 4] = ["llvm-as", "opt", "llc", "clang"];
+
+fn on_path(tool: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(tool).is_file()))
+}
+
+fn conformance_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance")
+}
+
+fn bw_test(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_bw"))
+        .arg("test")
+        .args(args)
+        .arg(conformance_dir())
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+fn bw_run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_bw"))
+        .arg("run")
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_interpreter() {
+    bw_test(&[]);
+}
+
+#[test]
+fn test_jit() {
+    bw_test(&["--jit"]);
+}
+
+#[test]
+fn test_compiled() {
+    let missing: Vec<&str> = COMPILE_TOOLS
+        .iter()
+        .copied()
+        .filter(|tool| !on_path(tool))
+        .collect();
+
+    // Straight to stderr, the test harness would swallow `eprintln!`
+    if !missing.is_empty() {
+        writeln!(
+            io::stderr(),
+            "skipped, not on the PATH: {}",
+            missing.join(", ")
+        )
+        .unwrap();
+        return;
+    }
+
+    bw_test(&["--compile"]);
+}
+
+/// The corpus expects 8 bit cells, the probe has to see the wider ones too
+#[test]
+fn test_cell_sizes() {
+    let probe = conformance_dir().join("cell_size.bf");
+    let probe = probe.to_str().unwrap();
+
+    for &bits in ["16", "32"].iter() {
+        let expected = format!("{}\n", bits);
+
+        assert_eq!(bw_run(&["--cell-size", bits, probe]), expected);
+        assert_eq!(bw_run(&["--jit", "--cell-size", bits, probe]), expected);
+    }
+}
+
+/// A compiled or JIT program that never stops is killed and fails its test
+/// instead of hanging the runner
+#[test]
+fn test_timeout() {
+    let dir = std::env::temp_dir().join(format!("bw-timeout-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("forever.bf"), "[\n  output: done\n]\n+[]").unwrap();
+
+    let start = Instant::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_bw"))
+        .args(["test", "--jit", "--timeout", "0.5"])
+        .arg(&dir)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("was killed"), "{}", stdout);
+    assert!(start.elapsed() < Duration::from_secs(30));
+}
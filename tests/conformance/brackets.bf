@@ -0,0 +1,25 @@
+[
+  Bracket edge cases: every case leaves a small number in a cell that is
+  printed as a digit
+  output: 1 6 1 0 1 1\n
+]
+Loops on a zero cell are skipped however deep they are
+[[[]]][-][[-]]
++ print and clear   >++++++++[<++++++>-]<.[-] >++++[<++++++++>-]<.[-]
+
+A loop that moves a value
++++[>++<-]> print 6   >++++++++[<++++++>-]<.[-] >++++[<++++++++>-]<.[-]<
+
+A loop that scans left until it finds a zero cell
+>+>+>+[<] >>>     >++++++++[<++++++>-]<.[-] >++++[<++++++++>-]<.[-]
+<[-]<[-]<
+
+A loop whose body is just another loop
+++[[-]]  >++++++++[<++++++>-]<.[-] >++++[<++++++++>-]<.[-]
+
+A loop that ends on another cell than it started on
++[->]< +  >++++++++[<++++++>-]<.[-] >++++[<++++++++>-]<.[-]
+
+Deep nesting where every level runs once
++[[[[[[[[[[[[[[[[[[[[-]]]]]]]]]]]]]]]]]]]] +  >++++++++[<++++++>-]<.[-]
+++++++++++.[-]
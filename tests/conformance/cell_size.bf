@@ -0,0 +1,20 @@
+[
+  Prints the width of a cell in bits
+  output: 8\n
+
+  Cell 0 becomes 256 and is zero if cells have 8 bits, otherwise it runs
+  an outer loop 256 times that adds 256 to cell 3 which is then zero if
+  cells have 16 bits. Cells 1 2 and 4 flag 8 16 and 32 bits
+]
+>++++++++++++++++[<++++++++++++++++>-]<
+>+<
+[
+  >-<
+  >>+<<
+  [>>>>++++++++++++++++[<++++++++++++++++>-]<<<<-]
+  >>>[<->>+<[-]]<<<
+]
+>[>>>>>+++++++[<++++++++>-]<.[-]<<<<-]<
+>>[>>>>+++++++[<+++++++>-]<.+++++.[-]<<<-]<<
+>>>>[>>+++++++[<+++++++>-]<++.-.[-]<-]<<<<
+>>>>>++++++++++.[-]<<<<<
@@ -0,0 +1,9 @@
+[
+  Everything but the eight commands is ignored
+  output: !\n
+]
+This line has no commands at all: only words; numbers 1 2 3; and symbols * ! ? @ $ % ^ & ( ) { } = / | \\ " ' ~ `
++++ +++ ++ the cell is now 8
+[ >++++ this is four < - ] > the next cell holds 32
++ . prints an exclamation mark
+[-]++++++++++. and a newline
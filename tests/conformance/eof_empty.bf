@@ -0,0 +1,5 @@
+[
+  Reading from an empty input leaves the cell unchanged too
+  output: AA
+]
+++++++++[>++++++++<-]>+.,.
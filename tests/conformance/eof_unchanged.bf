@@ -0,0 +1,6 @@
+[
+  Reading at the end of the input leaves the cell unchanged
+  input: x
+  output: xx
+]
+,.,.
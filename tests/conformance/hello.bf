@@ -0,0 +1,5 @@
+[
+  The hello world program from Wikipedia
+  output: Hello World!\n
+]
+++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.
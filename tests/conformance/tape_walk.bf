@@ -0,0 +1,10 @@
+[
+  Walks 200 cells to the right carrying a counter and back again
+  output: ok\n
+]
+++++++++++[>++++++++++++++++++++<-]>
+[-[->+<]>]
+++++++++++[>++++++++++++++++++++<-]>
+[-[-<+>]<]
+<<
+++++++++++[>+++++++++++<-]>+.----.[-]++++++++++.
@@ -0,0 +1,6 @@
+[
+  Cells wrap around in both directions
+  output: \xff\x00\x00
+]
+-.+.
+>++++++++[<++++++++++++++++++++++++++++++++>-]<.